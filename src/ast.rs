@@ -0,0 +1,178 @@
+use std::fmt;
+use std::rc::Rc;
+
+/// Position of a token or syntax node in the source (1-based)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Error produced while tokenizing or parsing source code
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl SyntaxError {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Self { message: message.into(), span }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (line {}, column {})", self.message, self.span.line, self.span.column)
+    }
+}
+
+/// A sequence of statements, e.g. a whole program or the body of an if block
+pub type Block = Vec<Stmt>;
+
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum StmtKind {
+    /// require("name"); or require("name") as alias;
+    Require { name: String, alias: Option<String> },
+    /// var(name) = value;
+    VarDef { name: String, value: Expr },
+    /// name = value; (the variable must already exist)
+    Assign { name: String, value: Expr },
+    /// target[index] = value;
+    IndexAssign { target: Expr, index: Expr, value: Expr },
+    /// func(name(params)) = { body }; `exported` when written as export func(...) = { body };
+    FuncDef { name: String, params: Vec<String>, body: Rc<Block>, exported: bool },
+    /// if(condition) { ... } else { ... }; an `else if` is an else_body holding one nested If
+    If { condition: Expr, then_body: Block, else_body: Option<Block> },
+    /// while(condition) { ... }
+    While { condition: Expr, body: Block },
+    /// for(init; condition; step) { ... }
+    For { init: Box<Stmt>, condition: Expr, step: Box<Stmt>, body: Block },
+    /// for(var(name) in iterable) { ... }
+    ForEach { var: String, iterable: ForIterable, body: Block },
+    Break,
+    Continue,
+    /// return value; (value is optional)
+    Return(Option<Expr>),
+    /// A bare expression, normally a function call
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone)]
+pub enum ForIterable {
+    /// range(end), range(start, end) or range(start, end, step); end is exclusive
+    Range { start: Expr, end: Expr, step: Option<Expr> },
+    /// Any other expression, e.g. a string to iterate character by character
+    Value(Expr),
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    /// [item, item, ...]
+    List(Vec<Expr>),
+    /// {key: value, ...}
+    Map(Vec<(Expr, Expr)>),
+    /// var(name), self(name) or a bare name; a name with no variable can refer to a function
+    Var(String),
+    /// Typed prefix such as string:expr or int:expr
+    Cast { ty: CastType, expr: Box<Expr> },
+    Unary { op: UnaryOp, expr: Box<Expr> },
+    Binary { op: BinaryOp, left: Box<Expr>, right: Box<Expr> },
+    /// target[index]
+    Index { target: Box<Expr>, index: Box<Expr> },
+    /// name(args) or func(name(args)); name may contain dots (basic.print)
+    Call { name: String, args: Vec<Expr> },
+    /// Call of a function value, e.g. var(handlers)[int:0](x)
+    CallValue { callee: Box<Expr>, args: Vec<Expr> },
+    /// Anonymous function: func(a, b) { ... }
+    Lambda { params: Vec<String>, body: Rc<Block> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CastType {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+impl CastType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "string" => Some(CastType::String),
+            "int" => Some(CastType::Int),
+            "float" => Some(CastType::Float),
+            "bool" => Some(CastType::Bool),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    NotEq,
+    Lt,
+    Gt,
+    LtEq,
+    GtEq,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::LtEq | BinaryOp::GtEq
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Eq => "==",
+            BinaryOp::NotEq => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::LtEq => "<=",
+            BinaryOp::GtEq => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        };
+        f.write_str(symbol)
+    }
+}
@@ -1,41 +1,148 @@
-use std::fs::{File, OpenOptions};
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
 use std::io::{Read, Write};
 use std::path::Path;
 
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::bytecode::{compile_program, disassemble, Bundle, Module, Program};
+use crate::package::{self, PackageManager};
+use crate::parser;
+
 // 字节码文件魔数
 const MAGIC_NUMBER: [u8; 4] = *b"LEON";
-// 字节码版本
-const BYTECODE_VERSION: u8 = 1;
-// 加密密钥
+// 字节码版本：1 = 加密的源代码，2 = 编译后的指令，3 = 带校验和、可用密钥加密的编译指令
+const BYTECODE_VERSION: u8 = 3;
+// 版本1文件使用的加密密钥
 const ENCRYPTION_KEY: &[u8] = b"LEON_BASIC_ENCRYPT";
 
-/// 简单的XOR加密函数
-fn encrypt(data: &[u8]) -> Vec<u8> {
+// 版本3的标志位：程序内容已用 --key 加密；内容是带有模块表的程序包（--bundle）
+const FLAG_ENCRYPTED: u8 = 1;
+const FLAG_BUNDLE: u8 = 2;
+// 版本3文件头：魔数、版本号、标志、内容长度、CRC32校验和
+const HEADER_LEN: usize = 14;
+const CHECKSUM_RANGE: std::ops::Range<usize> = 10..14;
+// 加密文件在文件头之后保存密钥派生用的盐和随机数
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// 解密版本1文件中的源代码（XOR是对称的）
+fn decrypt(data: &[u8]) -> Vec<u8> {
     data.iter().enumerate().map(|(i, &byte)| {
         byte ^ ENCRYPTION_KEY[i % ENCRYPTION_KEY.len()]
     }).collect()
 }
 
-/// 解密函数（与加密函数相同，因为XOR是对称的）
-fn decrypt(data: &[u8]) -> Vec<u8> {
-    // XOR加密是对称的，所以解密使用相同的函数
-    encrypt(data)
+/// 字节码文件的内容
+pub enum Bytecode {
+    /// 版本1：只包含源代码，运行时重新解析
+    Source(String),
+    /// 版本2及以上：编译后的程序，由虚拟机执行；未打包的文件模块表为空
+    Compiled(Bundle),
+}
+
+/// 由 --key 提供的口令和盐派生加密密钥
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Failed to derive key: {}", e))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// 整个文件除校验和字段以外的CRC32
+fn file_checksum(file: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&file[..CHECKSUM_RANGE.start]);
+    hasher.update(&file[CHECKSUM_RANGE.end..]);
+    hasher.finalize()
+}
+
+/// 读取、解析并编译一个源文件
+fn compile_file(source_path: &str) -> Result<Program, String> {
+    let source_code = fs::read_to_string(source_path)
+        .map_err(|e| format!("Failed to read source file {}: {}", source_path, e))?;
+    let ast = parser::parse_program(&source_code)
+        .map_err(|e| format!("{}: {}", source_path, e))?;
+    compile_program(source_path, &ast).map_err(|e| format!("{}: {}", source_path, e))
+}
+
+/// 编译程序直接或间接 require 的所有模块，每个模块只编译一次。
+/// 模块文件的查找方式与运行时相同（不包括运行时才添加的 setrequirepath 路径）；解释器内置的库不需要打包。
+fn bundle_modules(main: &Program) -> Result<Vec<Module>, String> {
+    let mut packages = PackageManager::new();
+    packages.set_script_dir(&main.source_name);
+    let mut modules: Vec<Module> = Vec::new();
+    let mut pending: VecDeque<(String, String)> = main.required_modules().into_iter()
+        .map(|name| (name.to_string(), main.source_name.clone()))
+        .collect();
+    while let Some((name, required_by)) = pending.pop_front() {
+        if package::is_builtin(&name) || modules.iter().any(|module| module.name == name) {
+            continue;
+        }
+        let path = packages.find_package(&name)
+            .map_err(|e| format!("{}, required by {}", e, required_by))?
+            .display().to_string();
+        let program = compile_file(&path)?;
+        pending.extend(program.required_modules().into_iter().map(|required| (required.to_string(), path.clone())));
+        modules.push(Module { name, program });
+    }
+    Ok(modules)
 }
 
-/// 将LeonBasic源代码编译为字节码文件
-pub fn compile_to_bytecode(source_path: &str, output_path: &str) -> Result<(), String> {
+/// 将LeonBasic源代码编译为字节码文件。
+/// bundle 为真时把 require 的模块一并编译进文件；提供 key 时程序内容会被加密。
+pub fn compile_to_bytecode(source_path: &str, output_path: &str, key: Option<&str>, bundle: bool) -> Result<Vec<String>, String> {
     // 检查源文件是否存在
     if !Path::new(source_path).exists() {
         return Err(format!("Source file not found: {}", source_path));
     }
 
-    // 读取源代码文件
-    let mut source_file = File::open(source_path)
-        .map_err(|e| format!("Failed to open source file: {}", e))?;
-    
-    let mut source_code = String::new();
-    source_file.read_to_string(&mut source_code)
-        .map_err(|e| format!("Failed to read source file: {}", e))?;
+    // 编译源代码，打包时还有它需要的模块
+    let program = compile_file(source_path)?;
+    let (payload, bundled) = if bundle {
+        let modules = bundle_modules(&program)?;
+        let bundled = modules.iter().map(|module| module.program.source_name.clone()).collect();
+        (Bundle { main: program, modules }.encode(), bundled)
+    } else {
+        (program.encode(), Vec::new())
+    };
+
+    // 文件头：魔数、版本号、标志、内容长度（4字节，小端序），校验和最后填入
+    let mut flags = 0;
+    if key.is_some() {
+        flags |= FLAG_ENCRYPTED;
+    }
+    if bundle {
+        flags |= FLAG_BUNDLE;
+    }
+    let mut file = Vec::with_capacity(HEADER_LEN + payload.len());
+    file.extend_from_slice(&MAGIC_NUMBER);
+    file.push(BYTECODE_VERSION);
+    file.push(flags);
+    file.extend_from_slice(&[0; 8]);
+
+    let body = match key {
+        Some(key) => {
+            let mut salt = [0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+            file.extend_from_slice(&salt);
+            file.extend_from_slice(&nonce);
+            // 认证数据包含文件头的前几个字节，修改版本或标志也会导致解密失败
+            derive_key(key, &salt)?
+                .encrypt(&nonce, Payload { msg: &payload, aad: &file[..6] })
+                .map_err(|_| "Failed to encrypt bytecode".to_string())?
+        }
+        None => payload,
+    };
+    file[6..10].copy_from_slice(&(body.len() as u32).to_le_bytes());
+    file.extend_from_slice(&body);
+    let checksum = file_checksum(&file);
+    file[CHECKSUM_RANGE].copy_from_slice(&checksum.to_le_bytes());
 
     // 创建或截断输出文件
     let mut bytecode_file = OpenOptions::new()
@@ -44,78 +151,164 @@ pub fn compile_to_bytecode(source_path: &str, output_path: &str) -> Result<(), S
         .truncate(true)
         .open(output_path)
         .map_err(|e| format!("Failed to create output file: {}", e))?;
+    bytecode_file.write_all(&file)
+        .map_err(|e| format!("Failed to write bytecode: {}", e))?;
+    Ok(bundled)
+}
+
+/// 版本3文件中加密所需的参数
+struct Encryption {
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+}
+
+/// 字节码文件头
+struct Header {
+    version: u8,
+    flags: u8,
+    length: usize,
+    // 已验证的校验和（版本3）
+    checksum: Option<u32>,
+    encryption: Option<Encryption>,
+}
 
-    // 写入魔数
-    bytecode_file.write_all(&MAGIC_NUMBER)
-        .map_err(|e| format!("Failed to write magic number: {}", e))?;
-    
-    // 写入版本号
-    bytecode_file.write_all(&[BYTECODE_VERSION])
-        .map_err(|e| format!("Failed to write version: {}", e))?;
-    
-    // 写入源代码长度（4字节，小端序）
-    let source_len = source_code.len() as u32;
-    bytecode_file.write_all(&source_len.to_le_bytes())
-        .map_err(|e| format!("Failed to write source length: {}", e))?;
-    
-    // 对源代码进行加密
-    let encrypted_code = encrypt(source_code.as_bytes());
-    
-    // 写入加密后的源代码内容
-    bytecode_file.write_all(&encrypted_code)
-        .map_err(|e| format!("Failed to write encrypted source code: {}", e))?;
-
-    Ok(())
-}
-
-/// 从字节码文件中读取LeonBasic源代码
-pub fn read_from_bytecode(bytecode_path: &str) -> Result<String, String> {
+/// 读取字节码文件并验证文件头和校验和，返回文件头和内容
+fn read_bytecode_file(bytecode_path: &str) -> Result<(Header, Vec<u8>), String> {
     // 检查字节码文件是否存在
     if !Path::new(bytecode_path).exists() {
         return Err(format!("Bytecode file not found: {}", bytecode_path));
     }
 
-    // 读取字节码文件
-    let mut bytecode_file = File::open(bytecode_path)
+    let bytes = fs::read(bytecode_path)
         .map_err(|e| format!("Failed to open bytecode file: {}", e))?;
-    
-    // 读取并验证魔数
-    let mut magic_number = [0u8; 4];
-    bytecode_file.read_exact(&mut magic_number)
-        .map_err(|e| format!("Failed to read magic number: {}", e))?;
-    
-    if magic_number != MAGIC_NUMBER {
+
+    // 读取并验证魔数和版本号
+    if bytes.len() < 5 || bytes[..4] != MAGIC_NUMBER {
         return Err("Invalid bytecode file format: wrong magic number".to_string());
     }
-    
-    // 读取版本号
-    let mut version = [0u8; 1];
-    bytecode_file.read_exact(&mut version)
-        .map_err(|e| format!("Failed to read version: {}", e))?;
-    
-    // 检查版本兼容性
-    if version[0] > BYTECODE_VERSION {
-        return Err(format!("Unsupported bytecode version: {}", version[0]));
-    }
-    
-    // 读取源代码长度
-    let mut source_len_bytes = [0u8; 4];
-    bytecode_file.read_exact(&mut source_len_bytes)
-        .map_err(|e| format!("Failed to read source length: {}", e))?;
-    
-    let source_len = u32::from_le_bytes(source_len_bytes) as usize;
-    
-    // 读取源代码内容
-    let mut source_code = vec![0u8; source_len];
-    bytecode_file.read_exact(&mut source_code)
-        .map_err(|e| format!("Failed to read source code: {}", e))?;
-    
-    // 解密源代码
-    let decrypted_code = decrypt(&source_code);
-    
-    // 转换为字符串
-    String::from_utf8(decrypted_code)
-        .map_err(|e| format!("Failed to decode decrypted source code: {}", e))
+    let version = bytes[4];
+    if version == 0 || version > BYTECODE_VERSION {
+        return Err(format!("Unsupported bytecode version: {}", version));
+    }
+
+    // 版本1和2：内容长度紧跟在版本号之后，没有校验和
+    if version < 3 {
+        let length = bytes.get(5..9)
+            .map(|len| u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize)
+            .ok_or_else(|| "Bytecode file is truncated: incomplete header".to_string())?;
+        let body = bytes.get(9..9 + length)
+            .ok_or_else(|| format!("Bytecode file is truncated: expected {} bytes of content, found {}", length, bytes.len() - 9))?;
+        let header = Header { version, flags: 0, length, checksum: None, encryption: None };
+        return Ok((header, body.to_vec()));
+    }
+
+    // 先验证校验和，之后读取的长度、盐等字段都是可信的
+    if bytes.len() < HEADER_LEN {
+        return Err("Corrupted bytecode: the file is truncated".to_string());
+    }
+    let stored = u32::from_le_bytes([bytes[10], bytes[11], bytes[12], bytes[13]]);
+    let checksum = file_checksum(&bytes);
+    if checksum != stored {
+        return Err(format!(
+            "Corrupted bytecode: checksum mismatch (stored {:08x}, computed {:08x}); the file is damaged or incomplete",
+            stored, checksum));
+    }
+
+    let flags = bytes[5];
+    if flags & !(FLAG_ENCRYPTED | FLAG_BUNDLE) != 0 {
+        return Err(format!("Unsupported bytecode flags: {:#04x}", flags));
+    }
+    let length = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]) as usize;
+    let mut offset = HEADER_LEN;
+    let encryption = if flags & FLAG_ENCRYPTED != 0 {
+        let params = bytes.get(offset..offset + SALT_LEN + NONCE_LEN)
+            .ok_or_else(|| "Corrupted bytecode: missing encryption parameters".to_string())?;
+        offset += SALT_LEN + NONCE_LEN;
+        Some(Encryption {
+            salt: params[..SALT_LEN].try_into().expect("slice has SALT_LEN bytes"),
+            nonce: params[SALT_LEN..].try_into().expect("slice has NONCE_LEN bytes"),
+        })
+    } else {
+        None
+    };
+    if bytes.len() - offset != length {
+        return Err(format!("Corrupted bytecode: expected {} bytes of content, found {}", length, bytes.len() - offset));
+    }
+    let header = Header { version, flags, length, checksum: Some(checksum), encryption };
+    Ok((header, bytes[offset..].to_vec()))
+}
+
+/// 解密版本3文件的程序内容；未加密的文件原样返回
+fn decrypt_body(bytecode_path: &str, header: &Header, body: Vec<u8>, key: Option<&str>) -> Result<Vec<u8>, String> {
+    let Some(encryption) = &header.encryption else { return Ok(body) };
+    let key = key.ok_or_else(|| format!("{} is encrypted; pass the key with --key <key>", bytecode_path))?;
+
+    let mut aad = MAGIC_NUMBER.to_vec();
+    aad.extend_from_slice(&[header.version, header.flags]);
+    derive_key(key, &encryption.salt)?
+        .decrypt(Nonce::from_slice(&encryption.nonce), Payload { msg: &body, aad: &aad })
+        .map_err(|_| format!("Failed to decrypt {}: wrong key, or the file has been modified", bytecode_path))
+}
+
+/// 读取字节码文件，支持版本1到3；加密的文件需要提供 key
+pub fn read_from_bytecode(bytecode_path: &str, key: Option<&str>) -> Result<Bytecode, String> {
+    let (header, body) = read_bytecode_file(bytecode_path)?;
+    if header.version == 1 {
+        // 解密源代码并转换为字符串
+        return String::from_utf8(decrypt(&body))
+            .map(Bytecode::Source)
+            .map_err(|e| format!("Failed to decode decrypted source code: {}", e));
+    }
+    let payload = decrypt_body(bytecode_path, &header, body, key)?;
+    decode_payload(&header, &payload)
+        .map(Bytecode::Compiled)
+        .map_err(|e| format!("Invalid bytecode: {}", e))
+}
+
+/// 程序内容：打包的文件带有模块表，其他文件只有一个程序
+fn decode_payload(header: &Header, payload: &[u8]) -> Result<Bundle, String> {
+    if header.flags & FLAG_BUNDLE != 0 {
+        Bundle::decode(payload)
+    } else {
+        Program::decode(payload).map(|main| Bundle { main, modules: Vec::new() })
+    }
+}
+
+/// 反汇编字节码文件：文件头，以及编译后程序的常量池、函数表和指令列表
+pub fn disassemble_file(bytecode_path: &str, key: Option<&str>) -> Result<String, String> {
+    let (header, body) = read_bytecode_file(bytecode_path)?;
+    let mut listing = format!("File:      {}\nMagic:     {}\n", bytecode_path, String::from_utf8_lossy(&MAGIC_NUMBER));
+    if header.version == 1 {
+        listing.push_str(&format!("Version:   1 (encrypted source, no instructions)\nSource:    {} bytes\n", header.length));
+        return Ok(listing);
+    }
+
+    listing.push_str(&format!("Version:   {} (compiled program)\n", header.version));
+    if let Some(checksum) = header.checksum {
+        listing.push_str(&format!("Checksum:  {:08x} (ok)\n", checksum));
+    }
+    if header.encryption.is_some() {
+        listing.push_str("Encrypted: yes\n");
+        if key.is_none() {
+            listing.push_str(&format!("Program:   {} bytes (pass --key to show the instructions)\n", header.length));
+            return Ok(listing);
+        }
+    }
+    let payload = decrypt_body(bytecode_path, &header, body, key)?;
+    let bundle = decode_payload(&header, &payload).map_err(|e| format!("Invalid bytecode: {}", e))?;
+    listing.push_str(&format!("Program:   {} bytes\n", payload.len()));
+    if header.flags & FLAG_BUNDLE != 0 {
+        listing.push_str(&format!("Modules:   {}\n", bundle.modules.len()));
+        for module in &bundle.modules {
+            listing.push_str(&format!("  {:<16} {}\n", module.name, module.program.source_name));
+        }
+    }
+    listing.push_str(&disassemble(&bundle.main));
+    for module in &bundle.modules {
+        listing.push_str(&format!("\n######## module {} ########\n", module.name));
+        listing.push_str(&disassemble(&module.program));
+    }
+    Ok(listing)
 }
 
 /// 检查文件是否为有效的LeonBasic字节码文件
@@ -124,14 +317,44 @@ pub fn is_bytecode_file(file_path: &str) -> bool {
     if !file_path.ends_with(".lb") {
         return false;
     }
-    
+
     // 尝试打开文件并读取魔数
-    if let Ok(mut file) = File::open(file_path) {
+    if let Ok(mut file) = fs::File::open(file_path) {
         let mut magic_number = [0u8; 4];
-        if let Ok(_) = file.read_exact(&mut magic_number) {
+        if file.read_exact(&mut magic_number).is_ok() {
             return magic_number == MAGIC_NUMBER;
         }
     }
-    
+
     false
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_and_key() {
+        let dir = std::env::temp_dir().join(format!("leonbasic-build-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("demo.leon");
+        fs::write(&source, "var(x) = int:1 + int:2").unwrap();
+        let (source, plain, locked) = (source.to_str().unwrap(), dir.join("plain.lb"), dir.join("locked.lb"));
+        let (plain, locked) = (plain.to_str().unwrap(), locked.to_str().unwrap());
+
+        compile_to_bytecode(source, plain, None, false).unwrap();
+        assert!(matches!(read_from_bytecode(plain, None), Ok(Bytecode::Compiled(_))));
+        let mut bytes = fs::read(plain).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(plain, &bytes).unwrap();
+        assert!(read_from_bytecode(plain, None).err().unwrap().starts_with("Corrupted bytecode: checksum mismatch"));
+
+        compile_to_bytecode(source, locked, Some("secret"), false).unwrap();
+        assert!(matches!(read_from_bytecode(locked, Some("secret")), Ok(Bytecode::Compiled(_))));
+        assert!(read_from_bytecode(locked, None).err().unwrap().contains("is encrypted"));
+        assert!(read_from_bytecode(locked, Some("guess")).err().unwrap().contains("wrong key"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,16 +1,10 @@
 use super::{Env, Value};
+use super::process::{run_process, ProcessRequest};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::process::Command;
 
 fn format_value(value: &Value) -> String {
-    match value {
-        Value::String(s) => s.clone(),
-        Value::Int(i) => i.to_string(),
-        Value::Float(f) => f.to_string(),
-        Value::Null => "null".to_string(),
-        Value::File(_) => "[File object]".to_string(),
-    }
+    value.to_display_string()
 }
 
 pub fn register_basic_functions(env: &mut Env) {
@@ -21,32 +15,27 @@ pub fn register_basic_functions(env: &mut Env) {
             return Ok(Value::Null);
         }
         
-        let output = args.iter().map(|arg| format_value(arg)).collect::<Vec<String>>().join("");
+        let output = args.iter().map(format_value).collect::<Vec<String>>().join("");
         println!("{}", output);
         Ok(Value::Null)
     }));
     
-    // Execute system command
+    // Execute system command through the platform shell (cmd /c on Windows, sh -c elsewhere)
+    // and return its standard output. process.run gives access to stderr and the exit status.
     env.functions.insert("basic.runoscommand".to_string(), Box::new(|args| {
-        if let Some(Value::String(cmd)) = args.get(0) {
-            // On Windows, pass the entire command as a string to cmd /c
-            let output = Command::new("cmd")
-                .args(["/c", &cmd])
-                .output()
-                .map_err(|e| format!("Command execution failed: {}", e.to_string()))?;
-            
-            // Get standard output
-            let result = String::from_utf8_lossy(&output.stdout).to_string();
-            Ok(Value::String(result))
+        if let Some(Value::String(cmd)) = args.first() {
+            let output = run_process(&ProcessRequest::shell(cmd))?;
+            Ok(Value::String(output.stdout))
         } else {
             Err("runoscommand function requires a string parameter".to_string())
         }
     }));
     
-    // Set require path
+    // Add a directory to the module search path
+    let packages = env.packages.clone();
     env.functions.insert("basic.setrequirepath".to_string(), Box::new(move |args| {
-        if let Some(Value::String(path)) = args.get(0) {
-            // Simplified handling here, real environment needs more complex path management
+        if let Some(Value::String(path)) = args.first() {
+            packages.borrow_mut().add_require_path(path);
             Ok(Value::String(path.clone()))
         } else {
             Err("setrequirepath function requires a string parameter".to_string())
@@ -55,7 +44,7 @@ pub fn register_basic_functions(env: &mut Env) {
     
     // User input
     env.functions.insert("basic.input".to_string(), Box::new(|args| {
-        let prompt = if let Some(Value::String(p)) = args.get(0) {
+        let prompt = if let Some(Value::String(p)) = args.first() {
             p.clone()
         } else {
             "".to_string()
@@ -81,7 +70,7 @@ pub fn register_basic_functions(env: &mut Env) {
     // File operations - Open
     env.functions.insert("basic.open".to_string(), Box::new(|args| {
         if let (Some(Value::String(filename)), Some(Value::String(mode))) = 
-            (args.get(0), args.get(1)) {
+            (args.first(), args.get(1)) {
             
             let file = match mode.as_str() {
                 "read" => File::open(filename),
@@ -91,8 +80,8 @@ pub fn register_basic_functions(env: &mut Env) {
             };
             
             match file {
-                Ok(file) => Ok(Value::File(file)),
-                Err(e) => Err(e.to_string()),
+                Ok(file) => Ok(Value::new_file(filename, file)),
+                Err(e) => Err(format!("{}: {}", filename, e)),
             }
         } else {
             Err("open function requires two string parameters: filename and mode".to_string())
@@ -101,14 +90,11 @@ pub fn register_basic_functions(env: &mut Env) {
     
     // File operations - Write
     env.functions.insert("basic.write".to_string(), Box::new(|args| {
-        if let (Some(Value::File(file)), Some(content)) = 
-            (args.get(0), args.get(1)) {
+        if let (Some(Value::File(handle)), Some(content)) = 
+            (args.first(), args.get(1)) {
             
             let content_str = format_value(content);
-            let mut file_clone = unsafe { (&*file as *const File as *mut File).as_mut().unwrap() };
-            file_clone.write_all(content_str.as_bytes())
-                .map_err(|e| e.to_string())?;
-            
+            handle.with_file(|file| file.write_all(content_str.as_bytes()))?;
             Ok(Value::Null)
         } else {
             Err("write function requires a file parameter and a content parameter".to_string())
@@ -117,12 +103,9 @@ pub fn register_basic_functions(env: &mut Env) {
     
     // File operations - Read
     env.functions.insert("basic.read".to_string(), Box::new(|args| {
-        if let Some(Value::File(file)) = args.get(0) {
-            let mut file_clone = unsafe { (&*file as *const File as *mut File).as_mut().unwrap() };
+        if let Some(Value::File(handle)) = args.first() {
             let mut content = String::new();
-            file_clone.read_to_string(&mut content)
-                .map_err(|e| e.to_string())?;
-            
+            handle.with_file(|file| file.read_to_string(&mut content))?;
             Ok(Value::String(content))
         } else {
             Err("read function requires a file parameter".to_string())
@@ -131,11 +114,11 @@ pub fn register_basic_functions(env: &mut Env) {
     
     // File operations - Close
     env.functions.insert("basic.close".to_string(), Box::new(|args| {
-        if let Some(Value::File(file)) = args.get(0) {
-            // File will be automatically closed when out of scope, this is just for API consistency
+        if let Some(Value::File(handle)) = args.first() {
+            handle.close()?;
             Ok(Value::Null)
         } else {
             Err("close function requires a file parameter".to_string())
         }
     }));
-}
\ No newline at end of file
+}
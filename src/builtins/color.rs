@@ -22,13 +22,7 @@ const COLOR_BRIGHT_CYAN: &str = "\x1b[96m";
 const COLOR_BRIGHT_WHITE: &str = "\x1b[97m";
 
 fn format_value(value: &Value) -> String {
-    match value {
-        Value::String(s) => s.clone(),
-        Value::Int(i) => i.to_string(),
-        Value::Float(f) => f.to_string(),
-        Value::Null => "null".to_string(),
-        Value::File(_) => "[File object]".to_string(),
-    }
+    value.to_display_string()
 }
 
 // 根据颜色名称获取ANSI颜色代码
@@ -69,7 +63,7 @@ pub fn register_color_functions(env: &mut Env) {
         };
         
         // 剩余参数是要打印的内容
-        let output = args[1..].iter().map(|arg| format_value(arg)).collect::<Vec<String>>().join("");
+        let output = args[1..].iter().map(format_value).collect::<Vec<String>>().join("");
         
         // 打印带颜色的内容并重置颜色
         println!("{}{}{}", color_code, output, COLOR_RESET);
@@ -91,7 +85,7 @@ pub fn register_color_functions(env: &mut Env) {
         };
         
         // 剩余参数是要格式化的内容
-        let content = args[1..].iter().map(|arg| format_value(arg)).collect::<Vec<String>>().join("");
+        let content = args[1..].iter().map(format_value).collect::<Vec<String>>().join("");
         
         // 返回带颜色代码的字符串
         Ok(Value::String(format!("{}{}{}", color_code, content, COLOR_RESET)))
@@ -120,7 +114,7 @@ pub fn register_color_functions(env: &mut Env) {
         };
         
         // 剩余参数是要打印的内容
-        let output = args[2..].iter().map(|arg| format_value(arg)).collect::<Vec<String>>().join("");
+        let output = args[2..].iter().map(format_value).collect::<Vec<String>>().join("");
         
         // 打印带颜色和背景色的内容并重置
         println!("{}{}{}{}", bg_color_code, text_color, output, COLOR_RESET);
@@ -0,0 +1,151 @@
+use super::{Env, Value};
+use crate::resolve_index;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+type ListRef = Rc<RefCell<Vec<Value>>>;
+
+// Fetch the list argument at `position`
+fn list_arg(args: &[Value], position: usize, func_name: &str) -> Result<ListRef, String> {
+    match args.get(position) {
+        Some(Value::List(items)) => Ok(items.clone()),
+        Some(other) => Err(format!("{} requires a list parameter, got {}", func_name, other.type_name())),
+        None => Err(format!("{} requires a list parameter", func_name)),
+    }
+}
+
+// Fetch the integer argument at `position`
+fn int_arg(args: &[Value], position: usize, func_name: &str) -> Result<i64, String> {
+    match args.get(position) {
+        Some(Value::Int(i)) => Ok(*i),
+        Some(other) => Err(format!("{} requires an integer index, got {}", func_name, other.type_name())),
+        None => Err(format!("{} requires an integer index", func_name)),
+    }
+}
+
+// Clamp a slice bound into 0..=len; negative bounds count from the end
+fn slice_bound(index: i64, len: usize) -> usize {
+    if index < 0 {
+        (len as i64 + index).max(0) as usize
+    } else {
+        (index as usize).min(len)
+    }
+}
+
+// Sort order for list.sort: numbers by value, strings alphabetically
+fn compare_items(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Int(l), Value::Int(r)) => Some(l.cmp(r)),
+        (Value::Int(l), Value::Float(r)) => Some((*l as f64).total_cmp(r)),
+        (Value::Float(l), Value::Int(r)) => Some(l.total_cmp(&(*r as f64))),
+        (Value::Float(l), Value::Float(r)) => Some(l.total_cmp(r)),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        _ => None,
+    }
+}
+
+pub fn register_list_functions(env: &mut Env) {
+    // Append values to the end of a list
+    env.functions.insert("list.push".to_string(), Box::new(|args| {
+        let items = list_arg(&args, 0, "list.push")?;
+        if args.len() < 2 {
+            return Err("list.push requires a value to add".to_string());
+        }
+        items.borrow_mut().extend(args.into_iter().skip(1));
+        Ok(Value::Null)
+    }));
+
+    // Remove and return the last item
+    env.functions.insert("list.pop".to_string(), Box::new(|args| {
+        let items = list_arg(&args, 0, "list.pop")?;
+        let popped = items.borrow_mut().pop();
+        popped.ok_or_else(|| "list.pop called on an empty list".to_string())
+    }));
+
+    // Number of items
+    env.functions.insert("list.len".to_string(), Box::new(|args| {
+        let items = list_arg(&args, 0, "list.len")?;
+        let len = items.borrow().len();
+        Ok(Value::Int(len as i64))
+    }));
+
+    // Insert a value before the given index; an index equal to the length appends
+    env.functions.insert("list.insert".to_string(), Box::new(|args| {
+        let items = list_arg(&args, 0, "list.insert")?;
+        let index = int_arg(&args, 1, "list.insert")?;
+        let value = match args.get(2) {
+            Some(value) => value.clone(),
+            None => return Err("list.insert requires a value to insert".to_string()),
+        };
+
+        let mut items = items.borrow_mut();
+        let len = items.len();
+        let position = if index == len as i64 {
+            len
+        } else {
+            resolve_index(index, len)
+                .ok_or_else(|| format!("list.insert index {} out of range for list of length {}", index, len))?
+        };
+        items.insert(position, value);
+        Ok(Value::Null)
+    }));
+
+    // Remove and return the item at the given index
+    env.functions.insert("list.remove".to_string(), Box::new(|args| {
+        let items = list_arg(&args, 0, "list.remove")?;
+        let index = int_arg(&args, 1, "list.remove")?;
+
+        let mut items = items.borrow_mut();
+        let len = items.len();
+        let position = resolve_index(index, len)
+            .ok_or_else(|| format!("list.remove index {} out of range for list of length {}", index, len))?;
+        Ok(items.remove(position))
+    }));
+
+    // New list with the items from start up to (not including) end
+    env.functions.insert("list.slice".to_string(), Box::new(|args| {
+        let items = list_arg(&args, 0, "list.slice")?;
+        let items = items.borrow();
+        let start = slice_bound(int_arg(&args, 1, "list.slice")?, items.len());
+        let end = match args.get(2) {
+            Some(_) => slice_bound(int_arg(&args, 2, "list.slice")?, items.len()),
+            None => items.len(),
+        };
+        let slice = if start < end { items[start..end].to_vec() } else { Vec::new() };
+        Ok(Value::new_list(slice))
+    }));
+
+    // Sort in place; the list must hold only numbers or only strings
+    env.functions.insert("list.sort".to_string(), Box::new(|args| {
+        let items = list_arg(&args, 0, "list.sort")?;
+        let mut items = items.borrow_mut();
+        for pair in items.windows(2) {
+            if compare_items(&pair[0], &pair[1]).is_none() {
+                return Err(format!("list.sort cannot compare {} with {}",
+                                   pair[0].type_name(), pair[1].type_name()));
+            }
+        }
+        items.sort_by(|a, b| compare_items(a, b).unwrap_or(Ordering::Equal));
+        Ok(Value::Null)
+    }));
+
+    // Reverse in place
+    env.functions.insert("list.reverse".to_string(), Box::new(|args| {
+        let items = list_arg(&args, 0, "list.reverse")?;
+        items.borrow_mut().reverse();
+        Ok(Value::Null)
+    }));
+
+    // Join the items into a string with an optional separator
+    env.functions.insert("list.join".to_string(), Box::new(|args| {
+        let items = list_arg(&args, 0, "list.join")?;
+        let separator = match args.get(1) {
+            Some(Value::String(s)) => s.clone(),
+            Some(other) => return Err(format!("list.join separator must be a string, got {}", other.type_name())),
+            None => String::new(),
+        };
+        let parts: Vec<String> = items.borrow().iter().map(Value::to_display_string).collect();
+        Ok(Value::String(parts.join(&separator)))
+    }));
+}
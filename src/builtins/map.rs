@@ -0,0 +1,74 @@
+use super::{Env, Value};
+use indexmap::IndexMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+type MapRef = Rc<RefCell<IndexMap<String, Value>>>;
+
+// Fetch the map argument at `position`
+fn map_arg(args: &[Value], position: usize, func_name: &str) -> Result<MapRef, String> {
+    match args.get(position) {
+        Some(Value::Map(entries)) => Ok(entries.clone()),
+        Some(other) => Err(format!("{} requires a map parameter, got {}", func_name, other.type_name())),
+        None => Err(format!("{} requires a map parameter", func_name)),
+    }
+}
+
+// Fetch the string key argument at `position`
+fn key_arg(args: &[Value], position: usize, func_name: &str) -> Result<String, String> {
+    match args.get(position) {
+        Some(Value::String(key)) => Ok(key.clone()),
+        Some(other) => Err(format!("{} requires a string key, got {}", func_name, other.type_name())),
+        None => Err(format!("{} requires a string key", func_name)),
+    }
+}
+
+pub fn register_map_functions(env: &mut Env) {
+    // List of the keys in insertion order
+    env.functions.insert("map.keys".to_string(), Box::new(|args| {
+        let entries = map_arg(&args, 0, "map.keys")?;
+        let keys = entries.borrow().keys().map(|key| Value::String(key.clone())).collect();
+        Ok(Value::new_list(keys))
+    }));
+
+    // List of the values in insertion order
+    env.functions.insert("map.values".to_string(), Box::new(|args| {
+        let entries = map_arg(&args, 0, "map.values")?;
+        let values = entries.borrow().values().cloned().collect();
+        Ok(Value::new_list(values))
+    }));
+
+    // Whether the key is present
+    env.functions.insert("map.has".to_string(), Box::new(|args| {
+        let entries = map_arg(&args, 0, "map.has")?;
+        let key = key_arg(&args, 1, "map.has")?;
+        let found = entries.borrow().contains_key(&key);
+        Ok(Value::Bool(found))
+    }));
+
+    // Remove a key and return its value; the remaining keys keep their order
+    env.functions.insert("map.remove".to_string(), Box::new(|args| {
+        let entries = map_arg(&args, 0, "map.remove")?;
+        let key = key_arg(&args, 1, "map.remove")?;
+        let removed = entries.borrow_mut().shift_remove(&key);
+        removed.ok_or_else(|| format!("Key {:?} not found in map", key))
+    }));
+
+    // Number of entries
+    env.functions.insert("map.len".to_string(), Box::new(|args| {
+        let entries = map_arg(&args, 0, "map.len")?;
+        let len = entries.borrow().len();
+        Ok(Value::Int(len as i64))
+    }));
+
+    // New map with the entries of the first map, overridden and extended by the second
+    env.functions.insert("map.merge".to_string(), Box::new(|args| {
+        let first = map_arg(&args, 0, "map.merge")?;
+        let second = map_arg(&args, 1, "map.merge")?;
+        let mut merged = first.borrow().clone();
+        for (key, value) in second.borrow().iter() {
+            merged.insert(key.clone(), value.clone());
+        }
+        Ok(Value::new_map(merged))
+    }));
+}
@@ -5,12 +5,15 @@ mod basic;
 mod request;
 mod time;
 mod color;
+mod list;
+mod map;
+mod process;
 
 // Re-export registration functions from various modules
 pub use basic::register_basic_functions;
 pub use request::register_request_functions;
 pub use time::register_time_functions;
 pub use color::register_color_functions;
-
-// Export type aliases for internal use
-type Function = Box<dyn Fn(Vec<Value>) -> Result<Value, String>>;
\ No newline at end of file
+pub use list::register_list_functions;
+pub use map::register_map_functions;
+pub use process::register_process_functions;
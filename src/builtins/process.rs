@@ -0,0 +1,185 @@
+use super::{Env, Value};
+use indexmap::IndexMap;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often a command with a timeout is checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How long to wait for the output pipes of a command that was killed after a timeout
+const KILLED_OUTPUT_WAIT: Duration = Duration::from_millis(200);
+
+/// What to run and how
+pub(super) struct ProcessRequest {
+    program: String,
+    args: Vec<String>,
+    cwd: Option<String>,
+    env: Vec<(String, String)>,
+    timeout: Option<Duration>,
+}
+
+impl ProcessRequest {
+    /// Run a command line through the platform shell: `sh -c` or `cmd /c`
+    pub(super) fn shell(command: &str) -> Self {
+        let (shell, flag) = if cfg!(windows) { ("cmd", "/c") } else { ("sh", "-c") };
+        Self::argv(shell.to_string(), vec![flag.to_string(), command.to_string()])
+    }
+
+    /// Run a program directly with the given arguments, without a shell
+    fn argv(program: String, args: Vec<String>) -> Self {
+        Self { program, args, cwd: None, env: Vec::new(), timeout: None }
+    }
+}
+
+pub(super) struct ProcessOutput {
+    pub(super) stdout: String,
+    pub(super) stderr: String,
+    // None when the process was killed by a signal or by the timeout
+    status: Option<i32>,
+    timed_out: bool,
+}
+
+// Read a pipe to the end on a separate thread, so a full stderr cannot block stdout
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = pipe.read_to_end(&mut buffer);
+        let _ = sender.send(buffer);
+    });
+    receiver
+}
+
+pub(super) fn run_process(request: &ProcessRequest) -> Result<ProcessOutput, String> {
+    let mut command = Command::new(&request.program);
+    command.args(&request.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(cwd) = &request.cwd {
+        command.current_dir(cwd);
+    }
+    for (key, value) in &request.env {
+        command.env(key, value);
+    }
+
+    let mut child = command.spawn()
+        .map_err(|e| format!("Command execution failed: {}: {}", request.program, e))?;
+    let stdout = read_in_background(child.stdout.take().expect("stdout is piped"));
+    let stderr = read_in_background(child.stderr.take().expect("stderr is piped"));
+
+    let mut timed_out = false;
+    let status = match request.timeout {
+        None => child.wait(),
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+                    break Ok(status);
+                }
+                if Instant::now() >= deadline {
+                    timed_out = true;
+                    let _ = child.kill();
+                    break child.wait();
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }.map_err(|e| format!("Failed to wait for {}: {}", request.program, e))?;
+
+    // A killed command may have left children behind that still hold the pipes open
+    let collect = |receiver: Receiver<Vec<u8>>| {
+        let bytes = if timed_out {
+            receiver.recv_timeout(KILLED_OUTPUT_WAIT).unwrap_or_default()
+        } else {
+            receiver.recv().unwrap_or_default()
+        };
+        String::from_utf8_lossy(&bytes).to_string()
+    };
+
+    Ok(ProcessOutput {
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+        status: if timed_out { None } else { status.code() },
+        timed_out,
+    })
+}
+
+// Apply the options map of process.run: cwd, env and timeout (milliseconds)
+fn apply_options(request: &mut ProcessRequest, options: &Value) -> Result<(), String> {
+    let options = match options {
+        Value::Map(options) => options.borrow(),
+        other => return Err(format!("process.run options must be a map, got {}", other.type_name())),
+    };
+    for (key, value) in options.iter() {
+        match (key.as_str(), value) {
+            ("cwd", Value::String(cwd)) => request.cwd = Some(cwd.clone()),
+            ("env", Value::Map(vars)) => {
+                for (name, value) in vars.borrow().iter() {
+                    request.env.push((name.clone(), value.to_display_string()));
+                }
+            }
+            ("timeout", Value::Int(ms)) if *ms > 0 => request.timeout = Some(Duration::from_millis(*ms as u64)),
+            ("timeout", Value::Int(_)) => return Err("process.run timeout must be positive".to_string()),
+            ("cwd" | "env" | "timeout", other) => {
+                return Err(format!("process.run option {} has the wrong type: {}", key, other.type_name()));
+            }
+            _ => return Err(format!("Unknown process.run option: {}", key)),
+        }
+    }
+    Ok(())
+}
+
+pub fn register_process_functions(env: &mut Env) {
+    // Run a command and collect its output.
+    // A string runs through the platform shell, a list runs the program directly.
+    env.functions.insert("process.run".to_string(), Box::new(|args| {
+        let mut request = match args.first() {
+            Some(Value::String(command)) => ProcessRequest::shell(command),
+            Some(Value::List(argv)) => {
+                let mut argv = argv.borrow().iter().map(Value::to_display_string).collect::<Vec<_>>().into_iter();
+                let program = argv.next()
+                    .ok_or_else(|| "process.run requires a non-empty argument list".to_string())?;
+                ProcessRequest::argv(program, argv.collect())
+            }
+            _ => return Err("process.run requires a command string or an argument list".to_string()),
+        };
+        if let Some(options) = args.get(1) {
+            apply_options(&mut request, options)?;
+        }
+
+        let output = run_process(&request)?;
+        let mut result = IndexMap::new();
+        result.insert("stdout".to_string(), Value::String(output.stdout));
+        result.insert("stderr".to_string(), Value::String(output.stderr));
+        result.insert("status".to_string(), output.status.map_or(Value::Null, |code| Value::Int(code as i64)));
+        result.insert("timed_out".to_string(), Value::Bool(output.timed_out));
+        Ok(Value::new_map(result))
+    }));
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_collects_output_and_times_out() {
+        let mut request = ProcessRequest::argv("sh".to_string(), vec!["-c".to_string(), "echo out; echo $GREETING >&2; exit 3".to_string()]);
+        request.env.push(("GREETING".to_string(), "hi".to_string()));
+        let output = run_process(&request).unwrap();
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "hi\n");
+        assert_eq!(output.status, Some(3));
+        assert!(!output.timed_out);
+
+        let mut request = ProcessRequest::shell("sleep 5");
+        request.timeout = Some(Duration::from_millis(50));
+        let started = Instant::now();
+        let output = run_process(&request).unwrap();
+        assert!(output.timed_out);
+        assert_eq!(output.status, None);
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+}
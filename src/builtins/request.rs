@@ -5,7 +5,7 @@ use std::io::Write;
 pub fn register_request_functions(env: &mut Env) {
     // HTTP GET request
     env.functions.insert("request.get".to_string(), Box::new(|args| {
-        if let Some(Value::String(url)) = args.get(0) {
+        if let Some(Value::String(url)) = args.first() {
             let client = reqwest::blocking::Client::new();
             let response = client.get(url).send()
                 .map_err(|e| e.to_string())?;
@@ -20,7 +20,7 @@ pub fn register_request_functions(env: &mut Env) {
     // Download file
     env.functions.insert("request.download".to_string(), Box::new(|args| {
         if let (Some(Value::String(url)), Some(Value::String(filename))) = 
-            (args.get(0), args.get(1)) {
+            (args.first(), args.get(1)) {
             
             let client = reqwest::blocking::Client::new();
             let response = client.get(url).send()
@@ -43,7 +43,7 @@ pub fn register_request_functions(env: &mut Env) {
     
     // Check status code
     env.functions.insert("request.check".to_string(), Box::new(|args| {
-        if let Some(Value::String(url)) = args.get(0) {
+        if let Some(Value::String(url)) = args.first() {
             let client = reqwest::blocking::Client::new();
             let response = client.get(url).send()
                 .map_err(|e| e.to_string())?;
@@ -56,7 +56,7 @@ pub fn register_request_functions(env: &mut Env) {
     
     // View header information
     env.functions.insert("request.header".to_string(), Box::new(|args| {
-        if let Some(Value::String(url)) = args.get(0) {
+        if let Some(Value::String(url)) = args.first() {
             let client = reqwest::blocking::Client::new();
             let response = client.get(url).send()
                 .map_err(|e| e.to_string())?;
@@ -75,7 +75,7 @@ pub fn register_request_functions(env: &mut Env) {
     
     // View footer information
     env.functions.insert("request.footer".to_string(), Box::new(|args| {
-        if let Some(Value::String(url)) = args.get(0) {
+        if let Some(Value::String(url)) = args.first() {
             let client = reqwest::blocking::Client::new();
             let response = client.get(url).send()
                 .map_err(|e| e.to_string())?;
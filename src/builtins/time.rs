@@ -13,7 +13,7 @@ pub fn register_time_functions(env: &mut Env) {
     
     // Get current formatted time
     env.functions.insert("time.getDateTime".to_string(), Box::new(|args| {
-        let format = if let Some(Value::String(f)) = args.get(0) {
+        let format = if let Some(Value::String(f)) = args.first() {
             f.clone()
         } else {
             "%Y-%m-%d %H:%M:%S".to_string() // Default format
@@ -31,7 +31,7 @@ pub fn register_time_functions(env: &mut Env) {
     // Format specified timestamp
     env.functions.insert("time.formatTime".to_string(), Box::new(|args| {
         if let (Some(Value::Int(timestamp)), Some(Value::String(format))) = 
-            (args.get(0), args.get(1)) {
+            (args.first(), args.get(1)) {
             
             // Process millisecond timestamp, convert to seconds
             let timestamp_sec = (*timestamp as u64) / 1000;
@@ -44,7 +44,7 @@ pub fn register_time_functions(env: &mut Env) {
     
     // Pause execution for specified milliseconds
     env.functions.insert("time.sleep".to_string(), Box::new(|args| {
-        if let Some(Value::Int(ms)) = args.get(0) {
+        if let Some(Value::Int(ms)) = args.first() {
             if *ms < 0 {
                 return Err("Sleep time cannot be negative".to_string());
             }
@@ -58,7 +58,7 @@ pub fn register_time_functions(env: &mut Env) {
     // Calculate difference between two timestamps (milliseconds)
     env.functions.insert("time.diffTime".to_string(), Box::new(|args| {
         if let (Some(Value::Int(start)), Some(Value::Int(end))) = 
-            (args.get(0), args.get(1)) {
+            (args.first(), args.get(1)) {
             
             let diff = *end - *start;
             Ok(Value::Int(diff))
@@ -0,0 +1,703 @@
+use super::{Chunk, Constant, FunctionProto, Instruction, Program};
+use crate::ast::{BinaryOp, Block, Expr, ExprKind, ForIterable, Span, Stmt, StmtKind, SyntaxError, UnaryOp};
+use crate::{apply_unary, cast_value, Value, LAMBDA_NAME};
+use std::collections::{HashMap, HashSet};
+
+/// Compile a parsed script. `source_name` is the file name used in error locations.
+/// Fails when the script goes past a limit of the bytecode format.
+pub fn compile_program(source_name: &str, program: &Block) -> Result<Program, SyntaxError> {
+    let mut compiler = Compiler::default();
+    // Top-level variables of the script are globals, or members of a module, and keep their names
+    let mut main = ChunkBuilder::new(program, true);
+    for stmt in program {
+        compiler.compile_statement(&mut main, stmt);
+    }
+    if let Some(error) = compiler.error {
+        return Err(error);
+    }
+    Ok(Program {
+        source_name: source_name.to_string(),
+        constants: compiler.constants,
+        functions: compiler.functions,
+        main: main.chunk,
+    })
+}
+
+// Key for sharing equal constants; floats are compared by their bits
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Str(String),
+    Int(i64),
+    Float(u64),
+}
+
+#[derive(Default)]
+struct Compiler {
+    constants: Vec<Constant>,
+    constant_indices: HashMap<ConstantKey, u32>,
+    functions: Vec<FunctionProto>,
+    // First limit the script went past; compiling goes on, but the program is thrown away
+    error: Option<SyntaxError>,
+}
+
+// Jumps of a loop that can only be filled in once the loop is compiled
+struct LoopContext {
+    // Scopes entered when the loop started; break and continue leave the ones opened since
+    scope_depth: usize,
+    // foreach loops keep an iterator that a break has to end
+    has_iterator: bool,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// Variable of a function or block that lives in a slot
+struct Local {
+    name: String,
+    slot: u16,
+    block_depth: usize,
+}
+
+// Code being generated for one function or the main script
+struct ChunkBuilder {
+    chunk: Chunk,
+    // Runtime scopes entered with EnterScope
+    scope_depth: usize,
+    loops: Vec<LoopContext>,
+    // Variables declared so far in the enclosing blocks, innermost last
+    locals: Vec<Local>,
+    // Blocks of the source, whether or not they get a runtime scope
+    block_depth: usize,
+    // Names that stay variables in the interpreter's scopes, see named_variables
+    named: HashSet<String>,
+    // Variables declared outside any block are globals (main script) rather than locals (function)
+    globals_at_top: bool,
+}
+
+impl ChunkBuilder {
+    fn new(body: &[Stmt], globals_at_top: bool) -> Self {
+        ChunkBuilder {
+            chunk: Chunk::default(),
+            scope_depth: 0,
+            loops: Vec::new(),
+            locals: Vec::new(),
+            block_depth: 0,
+            named: named_variables(body),
+            globals_at_top,
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.chunk.code.push(instruction);
+        self.chunk.spans.push(span);
+        self.chunk.code.len() - 1
+    }
+
+    // Index of the next instruction, the target of a jump to "here"
+    fn position(&self) -> u32 {
+        self.chunk.code.len() as u32
+    }
+
+    // Point the jump at `at` to the next instruction
+    fn patch_jump(&mut self, at: usize) {
+        let target = self.position();
+        self.patch_jump_to(at, target);
+    }
+
+    fn patch_jump_to(&mut self, at: usize, target: u32) {
+        self.chunk.code[at] = match self.chunk.code[at] {
+            Instruction::Jump(_) => Instruction::Jump(target),
+            Instruction::JumpIfFalse(_) => Instruction::JumpIfFalse(target),
+            Instruction::AndJump(_) => Instruction::AndJump(target),
+            Instruction::OrJump(_) => Instruction::OrJump(target),
+            Instruction::IterNext(_) => Instruction::IterNext(target),
+            other => unreachable!("{:?} is not a jump", other),
+        };
+    }
+
+    fn enter_scope(&mut self, span: Span) {
+        self.emit(Instruction::EnterScope, span);
+        self.scope_depth += 1;
+    }
+
+    fn exit_scope(&mut self, span: Span) {
+        self.emit(Instruction::ExitScope, span);
+        self.scope_depth -= 1;
+    }
+
+    // Leave the scopes opened inside the innermost loop, before a break or continue jumps out of them
+    fn exit_loop_scopes(&mut self, span: Span) {
+        let depth = self.loops.last().expect("parser only allows break/continue in loops").scope_depth;
+        for _ in depth..self.scope_depth {
+            self.emit(Instruction::ExitScope, span);
+        }
+    }
+
+    fn begin_block(&mut self) {
+        self.block_depth += 1;
+    }
+
+    // Forget the block's variables; their slots are reused by the blocks that follow
+    fn end_block(&mut self) {
+        while self.locals.last().is_some_and(|local| local.block_depth == self.block_depth) {
+            self.locals.pop();
+        }
+        self.block_depth -= 1;
+    }
+
+    fn is_named(&self, name: &str) -> bool {
+        self.named.contains(name) || (self.globals_at_top && self.block_depth == 0)
+    }
+
+    // Only variables kept by name need a runtime scope; the interpreter gives a block its own
+    // scope when it declares any variable, so that is still the upper bound
+    fn needs_scope(&self, block: &[Stmt]) -> bool {
+        block.iter().any(|stmt| matches!(&stmt.kind, StmtKind::VarDef { name, .. } if self.named.contains(name)))
+    }
+
+    // Slot of the nearest declaration of a local variable
+    fn resolve(&self, name: &str) -> Option<u16> {
+        self.locals.iter().rev().find(|local| local.name == name).map(|local| local.slot)
+    }
+
+    // Slot for var(name) in the current block, or None for a variable kept by name.
+    // Declaring a variable again in the same block reuses its slot.
+    fn declare(&mut self, name: &str) -> Result<Option<u16>, String> {
+        if self.is_named(name) {
+            return Ok(None);
+        }
+        if let Some(local) = self.locals.iter().rev()
+            .take_while(|local| local.block_depth == self.block_depth)
+            .find(|local| local.name == name) {
+            return Ok(Some(local.slot));
+        }
+        let slot = self.locals.last().map_or(0, |local| local.slot + 1);
+        // The slot count is a u16 too, which keeps every slot below u16::MAX
+        let slots = slot.checked_add(1)
+            .ok_or_else(|| format!("Too many local variables (at most {} at once)", u16::MAX))?;
+        self.chunk.slots = self.chunk.slots.max(slots);
+        self.locals.push(Local { name: name.to_string(), slot, block_depth: self.block_depth });
+        Ok(Some(slot))
+    }
+}
+
+// Names a chunk has to keep in the interpreter's scopes instead of slots: every name used inside
+// a function or lambda defined in it, which looks variables up in the scope it was defined in,
+// and every name called as a function, which the interpreter first looks up as a variable.
+fn named_variables(body: &[Stmt]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for stmt in body {
+        visit_statement(stmt, false, &mut names);
+    }
+    names
+}
+
+// `nested` is set inside a function defined in the chunk, where every name counts
+fn visit_statement(stmt: &Stmt, nested: bool, names: &mut HashSet<String>) {
+    let note = |name: &str, names: &mut HashSet<String>| {
+        if nested {
+            names.insert(name.to_string());
+        }
+    };
+    match &stmt.kind {
+        StmtKind::Require { .. } | StmtKind::Break | StmtKind::Continue | StmtKind::Return(None) => {}
+        StmtKind::VarDef { name, value } | StmtKind::Assign { name, value } => {
+            note(name, names);
+            visit_expression(value, nested, names);
+        }
+        StmtKind::IndexAssign { target, index, value } => {
+            for expr in [target, index, value] {
+                visit_expression(expr, nested, names);
+            }
+        }
+        StmtKind::FuncDef { params, body, .. } => visit_function(params, body, names),
+        StmtKind::If { condition, then_body, else_body } => {
+            visit_expression(condition, nested, names);
+            for stmt in then_body.iter().chain(else_body.iter().flatten()) {
+                visit_statement(stmt, nested, names);
+            }
+        }
+        StmtKind::While { condition, body } => {
+            visit_expression(condition, nested, names);
+            for stmt in body {
+                visit_statement(stmt, nested, names);
+            }
+        }
+        StmtKind::For { init, condition, step, body } => {
+            visit_expression(condition, nested, names);
+            for stmt in [init.as_ref(), step.as_ref()].into_iter().chain(body) {
+                visit_statement(stmt, nested, names);
+            }
+        }
+        StmtKind::ForEach { var, iterable, body } => {
+            note(var, names);
+            match iterable {
+                ForIterable::Range { start, end, step } => {
+                    for expr in [Some(start), Some(end), step.as_ref()].into_iter().flatten() {
+                        visit_expression(expr, nested, names);
+                    }
+                }
+                ForIterable::Value(expr) => visit_expression(expr, nested, names),
+            }
+            for stmt in body {
+                visit_statement(stmt, nested, names);
+            }
+        }
+        StmtKind::Return(Some(expr)) | StmtKind::Expr(expr) => visit_expression(expr, nested, names),
+    }
+}
+
+fn visit_expression(expr: &Expr, nested: bool, names: &mut HashSet<String>) {
+    match &expr.kind {
+        ExprKind::Str(_) | ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::Bool(_) => {}
+        ExprKind::Var(name) => {
+            if nested {
+                names.insert(name.clone());
+            }
+        }
+        ExprKind::List(items) => {
+            for item in items {
+                visit_expression(item, nested, names);
+            }
+        }
+        ExprKind::Map(entries) => {
+            for (key, value) in entries {
+                visit_expression(key, nested, names);
+                visit_expression(value, nested, names);
+            }
+        }
+        ExprKind::Cast { expr, .. } | ExprKind::Unary { expr, .. } => visit_expression(expr, nested, names),
+        ExprKind::Binary { left, right, .. } => {
+            visit_expression(left, nested, names);
+            visit_expression(right, nested, names);
+        }
+        ExprKind::Index { target, index } => {
+            visit_expression(target, nested, names);
+            visit_expression(index, nested, names);
+        }
+        ExprKind::Call { name, args } => {
+            names.insert(name.clone());
+            for arg in args {
+                visit_expression(arg, nested, names);
+            }
+        }
+        ExprKind::CallValue { callee, args } => {
+            visit_expression(callee, nested, names);
+            for arg in args {
+                visit_expression(arg, nested, names);
+            }
+        }
+        ExprKind::Lambda { params, body } => visit_function(params, body, names),
+    }
+}
+
+fn visit_function(params: &[String], body: &[Stmt], names: &mut HashSet<String>) {
+    names.extend(params.iter().cloned());
+    for stmt in body {
+        visit_statement(stmt, true, names);
+    }
+}
+
+// Value of an expression made only of literals, casts and negation, such as int:5 or -float:1.5.
+// Expressions whose evaluation fails are left to fail at runtime.
+fn constant_value(expr: &Expr) -> Option<Value> {
+    match &expr.kind {
+        ExprKind::Str(s) => Some(Value::String(s.clone())),
+        ExprKind::Int(i) => Some(Value::Int(*i)),
+        ExprKind::Float(f) => Some(Value::Float(*f)),
+        ExprKind::Bool(b) => Some(Value::Bool(*b)),
+        ExprKind::Cast { ty, expr } => cast_value(*ty, constant_value(expr)?).ok(),
+        ExprKind::Unary { op, expr } => apply_unary(*op, constant_value(expr)?).ok(),
+        _ => None,
+    }
+}
+
+impl Compiler {
+    fn constant(&mut self, constant: Constant) -> u32 {
+        let key = match &constant {
+            Constant::Str(s) => ConstantKey::Str(s.clone()),
+            Constant::Int(i) => ConstantKey::Int(*i),
+            Constant::Float(f) => ConstantKey::Float(f.to_bits()),
+        };
+        if let Some(&index) = self.constant_indices.get(&key) {
+            return index;
+        }
+        let index = self.constants.len() as u32;
+        self.constants.push(constant);
+        self.constant_indices.insert(key, index);
+        index
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        self.constant(Constant::Str(name.to_string()))
+    }
+
+    // Compile a function body into the function table. The arguments arrive in the first slots;
+    // a parameter that has to be kept by name is copied into the call's scope first.
+    fn compile_function(&mut self, name: &str, params: &[String], body: &[Stmt]) -> u32 {
+        let mut builder = ChunkBuilder::new(body, false);
+        builder.chunk.slots = params.len() as u16;
+        let span = body.first().map_or_else(Span::default, |stmt| stmt.span);
+        for (slot, param) in params.iter().enumerate() {
+            let slot = slot as u16;
+            if builder.is_named(param) {
+                builder.emit(Instruction::LoadLocal(slot), span);
+                let name = self.name(param);
+                builder.emit(Instruction::DefineVar(name), span);
+            } else {
+                builder.locals.push(Local { name: param.clone(), slot, block_depth: 0 });
+            }
+        }
+        for stmt in body {
+            self.compile_statement(&mut builder, stmt);
+        }
+        // Falling off the end returns null
+        let span = body.last().map_or_else(Span::default, |stmt| stmt.span);
+        builder.emit(Instruction::Null, span);
+        builder.emit(Instruction::Return, span);
+
+        self.functions.push(FunctionProto {
+            name: name.to_string(),
+            params: params.to_vec(),
+            chunk: builder.chunk,
+        });
+        (self.functions.len() - 1) as u32
+    }
+
+    fn compile_block(&mut self, builder: &mut ChunkBuilder, block: &[Stmt]) {
+        for stmt in block {
+            self.compile_statement(builder, stmt);
+        }
+    }
+
+    // Body of an if or loop, in its own scope when it declares variables kept by name
+    fn compile_scoped_block(&mut self, builder: &mut ChunkBuilder, block: &[Stmt], span: Span) {
+        builder.begin_block();
+        if builder.needs_scope(block) {
+            builder.enter_scope(span);
+            self.compile_block(builder, block);
+            builder.exit_scope(span);
+        } else {
+            self.compile_block(builder, block);
+        }
+        builder.end_block();
+    }
+
+    // var(name) = <value on the stack>
+    fn compile_declaration(&mut self, builder: &mut ChunkBuilder, name: &str, span: Span) {
+        let instruction = match builder.declare(name) {
+            Ok(Some(slot)) => Instruction::StoreLocal(slot),
+            Ok(None) => Instruction::DefineVar(self.name(name)),
+            Err(message) => {
+                self.error.get_or_insert(SyntaxError::new(message, span));
+                Instruction::Pop
+            }
+        };
+        builder.emit(instruction, span);
+    }
+
+    fn compile_statement(&mut self, builder: &mut ChunkBuilder, stmt: &Stmt) {
+        let span = stmt.span;
+        match &stmt.kind {
+            StmtKind::Require { name, alias } => {
+                let name = self.name(name);
+                let instruction = match alias {
+                    Some(alias) => Instruction::RequireAs(name, self.name(alias)),
+                    None => Instruction::Require(name),
+                };
+                builder.emit(instruction, span);
+            }
+            StmtKind::VarDef { name, value } => {
+                self.compile_expression(builder, value);
+                self.compile_declaration(builder, name, span);
+            }
+            StmtKind::Assign { name, value } => {
+                self.compile_expression(builder, value);
+                let instruction = match builder.resolve(name) {
+                    Some(slot) => Instruction::StoreLocal(slot),
+                    None => Instruction::StoreVar(self.name(name)),
+                };
+                builder.emit(instruction, span);
+            }
+            StmtKind::IndexAssign { target, index, value } => {
+                self.compile_expression(builder, target);
+                self.compile_expression(builder, index);
+                self.compile_expression(builder, value);
+                builder.emit(Instruction::StoreIndex, span);
+            }
+            StmtKind::FuncDef { name, params, body, exported } => {
+                let function = self.compile_function(name, params, body);
+                let instruction = if *exported {
+                    Instruction::ExportFunction(function)
+                } else {
+                    Instruction::DefineFunction(function)
+                };
+                builder.emit(instruction, span);
+            }
+            StmtKind::If { condition, then_body, else_body } => {
+                self.compile_expression(builder, condition);
+                let to_else = builder.emit(Instruction::JumpIfFalse(0), span);
+                self.compile_scoped_block(builder, then_body, span);
+                match else_body {
+                    Some(else_body) => {
+                        let to_end = builder.emit(Instruction::Jump(0), span);
+                        builder.patch_jump(to_else);
+                        self.compile_scoped_block(builder, else_body, span);
+                        builder.patch_jump(to_end);
+                    }
+                    None => builder.patch_jump(to_else),
+                }
+            }
+            StmtKind::While { condition, body } => {
+                let start = builder.position();
+                self.compile_expression(builder, condition);
+                let to_end = builder.emit(Instruction::JumpIfFalse(0), span);
+                let context = self.compile_loop_body(builder, false, |this, builder| {
+                    this.compile_scoped_block(builder, body, span);
+                });
+                builder.emit(Instruction::Jump(start), span);
+                builder.patch_jump(to_end);
+                self.finish_loop(builder, context, start);
+            }
+            StmtKind::For { init, condition, step, body } => {
+                // The loop scope holds the variable declared by init
+                builder.begin_block();
+                let scoped = builder.needs_scope(std::slice::from_ref(init.as_ref()));
+                if scoped {
+                    builder.enter_scope(span);
+                }
+                self.compile_statement(builder, init);
+                let start = builder.position();
+                self.compile_expression(builder, condition);
+                let to_end = builder.emit(Instruction::JumpIfFalse(0), span);
+                let context = self.compile_loop_body(builder, false, |this, builder| {
+                    this.compile_scoped_block(builder, body, span);
+                });
+                let step_position = builder.position();
+                self.compile_statement(builder, step);
+                builder.emit(Instruction::Jump(start), span);
+                builder.patch_jump(to_end);
+                self.finish_loop(builder, context, step_position);
+                if scoped {
+                    builder.exit_scope(span);
+                }
+                builder.end_block();
+            }
+            StmtKind::ForEach { var, iterable, body } => {
+                match iterable {
+                    ForIterable::Range { start, end, step } => {
+                        self.compile_expression(builder, start);
+                        self.compile_expression(builder, end);
+                        match step {
+                            Some(step) => self.compile_expression(builder, step),
+                            None => {
+                                let one = self.constant(Constant::Int(1));
+                                builder.emit(Instruction::Constant(one), span);
+                            }
+                        }
+                        builder.emit(Instruction::RangeStart, span);
+                    }
+                    ForIterable::Value(expr) => {
+                        self.compile_expression(builder, expr);
+                        builder.emit(Instruction::IterStart, span);
+                    }
+                }
+                let next = builder.position();
+                let to_end = builder.emit(Instruction::IterNext(0), span);
+                // Every iteration gets a fresh scope holding the loop variable, when it is kept by name
+                let context = self.compile_loop_body(builder, true, |this, builder| {
+                    builder.begin_block();
+                    let scoped = builder.named.contains(var) || builder.needs_scope(body);
+                    if scoped {
+                        builder.enter_scope(span);
+                    }
+                    this.compile_declaration(builder, var, span);
+                    this.compile_block(builder, body);
+                    if scoped {
+                        builder.exit_scope(span);
+                    }
+                    builder.end_block();
+                });
+                builder.emit(Instruction::Jump(next), span);
+                builder.patch_jump(to_end);
+                self.finish_loop(builder, context, next);
+            }
+            StmtKind::Break => {
+                builder.exit_loop_scopes(span);
+                if builder.loops.last().is_some_and(|context| context.has_iterator) {
+                    builder.emit(Instruction::IterEnd, span);
+                }
+                let jump = builder.emit(Instruction::Jump(0), span);
+                builder.loops.last_mut().expect("break inside a loop").breaks.push(jump);
+            }
+            StmtKind::Continue => {
+                builder.exit_loop_scopes(span);
+                let jump = builder.emit(Instruction::Jump(0), span);
+                builder.loops.last_mut().expect("continue inside a loop").continues.push(jump);
+            }
+            StmtKind::Return(value) => {
+                match value {
+                    Some(expr) => self.compile_expression(builder, expr),
+                    None => {
+                        builder.emit(Instruction::Null, span);
+                    }
+                }
+                builder.emit(Instruction::Return, span);
+            }
+            StmtKind::Expr(expr) => {
+                self.compile_expression(builder, expr);
+                builder.emit(Instruction::Pop, span);
+            }
+        }
+    }
+
+    // Compile a loop body with a fresh context for the break and continue statements inside it
+    fn compile_loop_body(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        has_iterator: bool,
+        compile: impl FnOnce(&mut Self, &mut ChunkBuilder),
+    ) -> LoopContext {
+        builder.loops.push(LoopContext {
+            scope_depth: builder.scope_depth,
+            has_iterator,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+        compile(self, builder);
+        builder.loops.pop().expect("loop context pushed above")
+    }
+
+    // Breaks go to the end of the loop, continues to `continue_target`
+    fn finish_loop(&mut self, builder: &mut ChunkBuilder, context: LoopContext, continue_target: u32) {
+        for jump in context.breaks {
+            builder.patch_jump(jump);
+        }
+        for jump in context.continues {
+            builder.patch_jump_to(jump, continue_target);
+        }
+    }
+
+    fn compile_expression(&mut self, builder: &mut ChunkBuilder, expr: &Expr) {
+        let span = expr.span;
+        // Casts and negation of literals are done once, here
+        if matches!(expr.kind, ExprKind::Cast { .. } | ExprKind::Unary { .. }) {
+            if let Some(value) = constant_value(expr) {
+                let instruction = match value {
+                    Value::String(s) => Instruction::Constant(self.constant(Constant::Str(s))),
+                    Value::Int(i) => Instruction::Constant(self.constant(Constant::Int(i))),
+                    Value::Float(f) => Instruction::Constant(self.constant(Constant::Float(f))),
+                    Value::Bool(b) => if b { Instruction::True } else { Instruction::False },
+                    other => unreachable!("constant expression produced {}", other.type_name()),
+                };
+                builder.emit(instruction, span);
+                return;
+            }
+        }
+        match &expr.kind {
+            ExprKind::Str(s) => {
+                let index = self.constant(Constant::Str(s.clone()));
+                builder.emit(Instruction::Constant(index), span);
+            }
+            ExprKind::Int(i) => {
+                let index = self.constant(Constant::Int(*i));
+                builder.emit(Instruction::Constant(index), span);
+            }
+            ExprKind::Float(f) => {
+                let index = self.constant(Constant::Float(*f));
+                builder.emit(Instruction::Constant(index), span);
+            }
+            ExprKind::Bool(b) => {
+                builder.emit(if *b { Instruction::True } else { Instruction::False }, span);
+            }
+            ExprKind::List(items) => {
+                for item in items {
+                    self.compile_expression(builder, item);
+                }
+                builder.emit(Instruction::MakeList(items.len() as u32), span);
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.compile_expression(builder, key);
+                    self.compile_expression(builder, value);
+                }
+                builder.emit(Instruction::MakeMap(entries.len() as u32), span);
+            }
+            ExprKind::Var(name) => {
+                let instruction = match builder.resolve(name) {
+                    Some(slot) => Instruction::LoadLocal(slot),
+                    None => Instruction::LoadVar(self.name(name)),
+                };
+                builder.emit(instruction, span);
+            }
+            ExprKind::Cast { ty, expr } => {
+                self.compile_expression(builder, expr);
+                builder.emit(Instruction::Cast(*ty), span);
+            }
+            ExprKind::Unary { op, expr } => {
+                self.compile_expression(builder, expr);
+                let instruction = match op {
+                    UnaryOp::Neg => Instruction::Negate,
+                    UnaryOp::Not => Instruction::Not,
+                };
+                builder.emit(instruction, span);
+            }
+            ExprKind::Binary { op: op @ (BinaryOp::And | BinaryOp::Or), left, right } => {
+                // Each side is checked and may end the evaluation early; when the right side
+                // does not, it was the deciding bool and is replaced by the result
+                let (short_circuit, result) = match op {
+                    BinaryOp::And => (Instruction::AndJump(0), Instruction::True),
+                    _ => (Instruction::OrJump(0), Instruction::False),
+                };
+                self.compile_expression(builder, left);
+                let left_jump = builder.emit(short_circuit, span);
+                self.compile_expression(builder, right);
+                let right_jump = builder.emit(short_circuit, span);
+                builder.emit(result, span);
+                builder.patch_jump(left_jump);
+                builder.patch_jump(right_jump);
+            }
+            ExprKind::Binary { op, left, right } => {
+                self.compile_expression(builder, left);
+                self.compile_expression(builder, right);
+                let instruction = match op {
+                    BinaryOp::Add => Instruction::Add,
+                    BinaryOp::Sub => Instruction::Subtract,
+                    BinaryOp::Mul => Instruction::Multiply,
+                    BinaryOp::Div => Instruction::Divide,
+                    BinaryOp::Mod => Instruction::Modulo,
+                    BinaryOp::Eq => Instruction::Equal,
+                    BinaryOp::NotEq => Instruction::NotEqual,
+                    BinaryOp::Lt => Instruction::Less,
+                    BinaryOp::Gt => Instruction::Greater,
+                    BinaryOp::LtEq => Instruction::LessEqual,
+                    BinaryOp::GtEq => Instruction::GreaterEqual,
+                    BinaryOp::And | BinaryOp::Or => unreachable!("handled above"),
+                };
+                builder.emit(instruction, span);
+            }
+            ExprKind::Index { target, index } => {
+                self.compile_expression(builder, target);
+                self.compile_expression(builder, index);
+                builder.emit(Instruction::Index, span);
+            }
+            ExprKind::Call { name, args } => {
+                for arg in args {
+                    self.compile_expression(builder, arg);
+                }
+                let name = self.name(name);
+                builder.emit(Instruction::Call(name, args.len() as u32), span);
+            }
+            ExprKind::CallValue { callee, args } => {
+                self.compile_expression(builder, callee);
+                for arg in args {
+                    self.compile_expression(builder, arg);
+                }
+                builder.emit(Instruction::CallValue(args.len() as u32), span);
+            }
+            ExprKind::Lambda { params, body } => {
+                let function = self.compile_function(LAMBDA_NAME, params, body);
+                builder.emit(Instruction::MakeLambda(function), span);
+            }
+        }
+    }
+}
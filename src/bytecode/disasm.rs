@@ -0,0 +1,149 @@
+use super::{Chunk, Constant, Instruction, Program};
+use crate::ast::CastType;
+use std::fmt::Write;
+
+fn mnemonic(instruction: Instruction) -> &'static str {
+    use Instruction::*;
+    match instruction {
+        Constant(_) => "CONSTANT",
+        True => "TRUE",
+        False => "FALSE",
+        Null => "NULL",
+        Pop => "POP",
+        LoadVar(_) => "LOAD_VAR",
+        DefineVar(_) => "DEFINE_VAR",
+        StoreVar(_) => "STORE_VAR",
+        LoadLocal(_) => "LOAD_LOCAL",
+        StoreLocal(_) => "STORE_LOCAL",
+        MakeList(_) => "MAKE_LIST",
+        MakeMap(_) => "MAKE_MAP",
+        Index => "INDEX",
+        StoreIndex => "STORE_INDEX",
+        Cast(_) => "CAST",
+        Negate => "NEGATE",
+        Not => "NOT",
+        Add => "ADD",
+        Subtract => "SUBTRACT",
+        Multiply => "MULTIPLY",
+        Divide => "DIVIDE",
+        Modulo => "MODULO",
+        Equal => "EQUAL",
+        NotEqual => "NOT_EQUAL",
+        Less => "LESS",
+        Greater => "GREATER",
+        LessEqual => "LESS_EQUAL",
+        GreaterEqual => "GREATER_EQUAL",
+        AndJump(_) => "AND_JUMP",
+        OrJump(_) => "OR_JUMP",
+        Jump(_) => "JUMP",
+        JumpIfFalse(_) => "JUMP_IF_FALSE",
+        Call(..) => "CALL",
+        CallValue(_) => "CALL_VALUE",
+        DefineFunction(_) => "DEFINE_FUNCTION",
+        ExportFunction(_) => "EXPORT_FUNCTION",
+        MakeLambda(_) => "MAKE_LAMBDA",
+        Require(_) => "REQUIRE",
+        RequireAs(..) => "REQUIRE_AS",
+        Return => "RETURN",
+        EnterScope => "ENTER_SCOPE",
+        ExitScope => "EXIT_SCOPE",
+        IterStart => "ITER_START",
+        RangeStart => "RANGE_START",
+        IterNext(_) => "ITER_NEXT",
+        IterEnd => "ITER_END",
+    }
+}
+
+fn describe_constant(constant: &Constant) -> String {
+    match constant {
+        Constant::Str(s) => format!("string  {:?}", s),
+        Constant::Int(i) => format!("int     {}", i),
+        Constant::Float(f) => format!("float   {:?}", f),
+    }
+}
+
+fn signature(program: &Program, index: u32) -> String {
+    let function = &program.functions[index as usize];
+    format!("{}({})", function.name, function.params.join(", "))
+}
+
+// Operands of an instruction, with the constants and functions they refer to spelled out
+fn operands(program: &Program, instruction: Instruction) -> String {
+    use Instruction::*;
+    match instruction {
+        Constant(i) => format!("{:<5} {}", i, describe_constant(&program.constants[i as usize])),
+        LoadVar(i) | DefineVar(i) | StoreVar(i) | Require(i) => format!("{:<5} {}", i, program.name(i)),
+        LoadLocal(slot) | StoreLocal(slot) => format!("slot {}", slot),
+        MakeList(n) => format!("{} item(s)", n),
+        MakeMap(n) => format!("{} entr{}", n, if n == 1 { "y" } else { "ies" }),
+        Cast(ty) => match ty {
+            CastType::String => "string",
+            CastType::Int => "int",
+            CastType::Float => "float",
+            CastType::Bool => "bool",
+        }.to_string(),
+        AndJump(t) | OrJump(t) | Jump(t) | JumpIfFalse(t) | IterNext(t) => format!("-> {:04}", t),
+        Call(name, argc) => format!("{:<5} {}, {} arg(s)", name, program.name(name), argc),
+        CallValue(argc) => format!("{} arg(s)", argc),
+        RequireAs(name, alias) => format!("{:<5} {} as {}", name, program.name(name), program.name(alias)),
+        DefineFunction(f) | ExportFunction(f) | MakeLambda(f) => format!("{:<5} {}", f, signature(program, f)),
+        _ => String::new(),
+    }
+}
+
+// Instruction listing; the source line is shown where it changes, like "   |" below it
+fn write_chunk(out: &mut String, program: &Program, chunk: &Chunk) {
+    let mut previous_line = None;
+    for (address, (&instruction, span)) in chunk.code.iter().zip(&chunk.spans).enumerate() {
+        let line = if previous_line == Some(span.line) {
+            "   |".to_string()
+        } else {
+            format!("{:4}", span.line)
+        };
+        previous_line = Some(span.line);
+        let text = format!("{} {:04}  {:<16}{}", line, address, mnemonic(instruction), operands(program, instruction));
+        let _ = writeln!(out, "{}", text.trim_end());
+    }
+}
+
+/// Readable listing of a compiled program: constant pool, function table and the code of every chunk
+pub fn disassemble(program: &Program) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Source:    {}", program.source_name);
+
+    let _ = writeln!(out, "\nConstants ({}):", program.constants.len());
+    for (index, constant) in program.constants.iter().enumerate() {
+        let _ = writeln!(out, "  {:4}  {}", index, describe_constant(constant));
+    }
+
+    let _ = writeln!(out, "\nFunctions ({}):", program.functions.len());
+    for (index, function) in program.functions.iter().enumerate() {
+        let _ = writeln!(out, "  {:4}  {:<24} {} instruction(s), {} slot(s)",
+                         index, signature(program, index as u32), function.chunk.code.len(), function.chunk.slots);
+    }
+
+    let _ = writeln!(out, "\n== <main> ==");
+    write_chunk(&mut out, program, &program.main);
+    for (index, function) in program.functions.iter().enumerate() {
+        let _ = writeln!(out, "\n== function {}: {} ==", index, signature(program, index as u32));
+        write_chunk(&mut out, program, &function.chunk);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile_program;
+    use crate::parser::parse_program;
+
+    #[test]
+    fn test_listing_names_operands_and_lines() {
+        let program = parse_program("func(double(n)) = {\n    return n * int:2\n}\nbasic.print(double(int:21))").unwrap();
+        let listing = disassemble(&compile_program("demo.leon", &program).unwrap());
+        assert!(listing.contains("Functions (1):\n     0  double(n)                6 instruction(s), 1 slot(s)"));
+        assert!(listing.contains("== <main> ==\n   1 0000  DEFINE_FUNCTION 0     double(n)\n   4 0001  CONSTANT        1     int     21"));
+        assert!(listing.contains("   | 0002  CALL            2     double, 1 arg(s)"));
+        assert!(listing.contains("== function 0: double(n) ==\n   2 0000  LOAD_LOCAL      slot 0"));
+    }
+}
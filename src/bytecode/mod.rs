@@ -0,0 +1,630 @@
+//! Compiled form of a LeonBasic program, as stored in version 2 `.lb` files.
+//!
+//! A program is a constant pool, a table of function bodies and the code of the
+//! top-level script. Code is a flat list of instructions for a stack machine; jump
+//! operands are instruction indices and every instruction remembers the source
+//! position it was compiled from, so runtime errors still point at the script.
+//! Global variables, module members and variables used by nested functions are looked
+//! up by name in the interpreter's scopes, which lets compiled code call into modules
+//! loaded from source and the other way round. All other variables of a function or
+//! block are resolved at compile time to numbered slots of the running call.
+//!
+//! A bundle stores a program together with the compiled modules it requires, so a
+//! single `.lb` file can run without the module sources next to it.
+
+use crate::ast::{CastType, Span};
+
+mod compiler;
+mod disasm;
+mod vm;
+
+pub use compiler::compile_program;
+pub use disasm::disassemble;
+
+/// Entry of the constant pool. Variable, function and module names are stored here as strings too.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Str(String),
+    Int(i64),
+    Float(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    /// Push a constant from the pool
+    Constant(u32),
+    True,
+    False,
+    Null,
+    /// Discard the top of the stack
+    Pop,
+    /// Push the variable (or function) with the name in the constant pool
+    LoadVar(u32),
+    /// Pop a value and declare it in the current scope
+    DefineVar(u32),
+    /// Pop a value and assign it to an existing variable
+    StoreVar(u32),
+    /// Push the value in a local slot of the running chunk
+    LoadLocal(u16),
+    /// Pop a value into a local slot of the running chunk, declaring or assigning it
+    StoreLocal(u16),
+    /// Pop n items into a new list
+    MakeList(u32),
+    /// Pop n key/value pairs into a new map
+    MakeMap(u32),
+    /// Pop index and target, push target[index]
+    Index,
+    /// Pop value, index and target, then set target[index] = value
+    StoreIndex,
+    Cast(CastType),
+    Negate,
+    Not,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    /// Left side of `&&`: a false bool stays on the stack as the result and jumps; true is popped
+    AndJump(u32),
+    /// Left side of `||`: a true bool stays on the stack as the result and jumps; false is popped
+    OrJump(u32),
+    Jump(u32),
+    /// Pop a condition, which must be a bool, and jump if it is false
+    JumpIfFalse(u32),
+    /// Pop the arguments and call the named function: (name, argument count)
+    Call(u32, u32),
+    /// Pop the arguments and the function value below them, then call it
+    CallValue(u32),
+    /// Register an entry of the function table as a named function
+    DefineFunction(u32),
+    /// Like DefineFunction, for a function marked with export
+    ExportFunction(u32),
+    /// Push an entry of the function table as a lambda
+    MakeLambda(u32),
+    Require(u32),
+    /// require(name) as alias: (name, alias)
+    RequireAs(u32, u32),
+    /// Pop the result and leave the function
+    Return,
+    /// Run the following code in a new child scope, until the matching ExitScope
+    EnterScope,
+    ExitScope,
+    /// Pop a string, list or map and start iterating over it
+    IterStart,
+    /// Pop start, end and step and start iterating over the range
+    RangeStart,
+    /// Push the next item of the innermost iteration, or end it and jump when it is exhausted
+    IterNext(u32),
+    /// End the innermost iteration early (break)
+    IterEnd,
+}
+
+/// Instructions with the source position of each one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<Instruction>,
+    pub spans: Vec<Span>,
+    /// Number of local slots; a function's parameters are passed in the first ones
+    pub slots: u16,
+}
+
+/// Entry of the function table: a named function or a lambda
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProto {
+    pub name: String,
+    pub params: Vec<String>,
+    pub chunk: Chunk,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    /// Name of the source file, used in error locations
+    pub source_name: String,
+    pub constants: Vec<Constant>,
+    pub functions: Vec<FunctionProto>,
+    pub main: Chunk,
+}
+
+impl Program {
+    // Name operand of an instruction; decode checked that it refers to a string
+    fn name(&self, index: u32) -> &str {
+        match &self.constants[index as usize] {
+            Constant::Str(name) => name,
+            other => unreachable!("name operand refers to {:?}", other),
+        }
+    }
+
+    /// Names passed to `require` anywhere in the program, in order of first appearance
+    pub fn required_modules(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        let chunks = std::iter::once(&self.main).chain(self.functions.iter().map(|f| &f.chunk));
+        for instruction in chunks.flat_map(|chunk| &chunk.code) {
+            if let Instruction::Require(name) | Instruction::RequireAs(name, _) = *instruction {
+                let name = self.name(name);
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+}
+
+/// Compiled module embedded in a bundle, found by the name `require` uses for it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub program: Program,
+}
+
+/// Main program and the modules it requires, directly or through other modules
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bundle {
+    pub main: Program,
+    pub modules: Vec<Module>,
+}
+
+// Opcodes of the binary encoding
+const OP_CONSTANT: u8 = 0;
+const OP_TRUE: u8 = 1;
+const OP_FALSE: u8 = 2;
+const OP_NULL: u8 = 3;
+const OP_POP: u8 = 4;
+const OP_LOAD_VAR: u8 = 5;
+const OP_DEFINE_VAR: u8 = 6;
+const OP_STORE_VAR: u8 = 7;
+const OP_MAKE_LIST: u8 = 8;
+const OP_MAKE_MAP: u8 = 9;
+const OP_INDEX: u8 = 10;
+const OP_STORE_INDEX: u8 = 11;
+const OP_CAST: u8 = 12;
+const OP_NEGATE: u8 = 13;
+const OP_NOT: u8 = 14;
+const OP_ADD: u8 = 15;
+const OP_SUBTRACT: u8 = 16;
+const OP_MULTIPLY: u8 = 17;
+const OP_DIVIDE: u8 = 18;
+const OP_MODULO: u8 = 19;
+const OP_EQUAL: u8 = 20;
+const OP_NOT_EQUAL: u8 = 21;
+const OP_LESS: u8 = 22;
+const OP_GREATER: u8 = 23;
+const OP_LESS_EQUAL: u8 = 24;
+const OP_GREATER_EQUAL: u8 = 25;
+const OP_AND_JUMP: u8 = 26;
+const OP_OR_JUMP: u8 = 27;
+const OP_JUMP: u8 = 28;
+const OP_JUMP_IF_FALSE: u8 = 29;
+const OP_CALL: u8 = 30;
+const OP_CALL_VALUE: u8 = 31;
+const OP_DEFINE_FUNCTION: u8 = 32;
+const OP_MAKE_LAMBDA: u8 = 33;
+const OP_REQUIRE: u8 = 34;
+const OP_RETURN: u8 = 35;
+const OP_ENTER_SCOPE: u8 = 36;
+const OP_EXIT_SCOPE: u8 = 37;
+const OP_ITER_START: u8 = 38;
+const OP_RANGE_START: u8 = 39;
+const OP_ITER_NEXT: u8 = 40;
+const OP_ITER_END: u8 = 41;
+const OP_LOAD_LOCAL: u8 = 42;
+const OP_STORE_LOCAL: u8 = 43;
+const OP_EXPORT_FUNCTION: u8 = 44;
+const OP_REQUIRE_AS: u8 = 45;
+
+const CONST_STR: u8 = 0;
+const CONST_INT: u8 = 1;
+const CONST_FLOAT: u8 = 2;
+
+fn cast_code(ty: CastType) -> u8 {
+    match ty {
+        CastType::String => 0,
+        CastType::Int => 1,
+        CastType::Float => 2,
+        CastType::Bool => 3,
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    write_u32(out, len as u32);
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_instruction(out: &mut Vec<u8>, instruction: Instruction) {
+    use Instruction::*;
+    let (opcode, operands): (u8, &[u32]) = match instruction {
+        Constant(i) => (OP_CONSTANT, &[i]),
+        True => (OP_TRUE, &[]),
+        False => (OP_FALSE, &[]),
+        Null => (OP_NULL, &[]),
+        Pop => (OP_POP, &[]),
+        LoadVar(i) => (OP_LOAD_VAR, &[i]),
+        DefineVar(i) => (OP_DEFINE_VAR, &[i]),
+        StoreVar(i) => (OP_STORE_VAR, &[i]),
+        LoadLocal(slot) | StoreLocal(slot) => {
+            out.push(if matches!(instruction, LoadLocal(_)) { OP_LOAD_LOCAL } else { OP_STORE_LOCAL });
+            out.extend_from_slice(&slot.to_le_bytes());
+            return;
+        }
+        MakeList(n) => (OP_MAKE_LIST, &[n]),
+        MakeMap(n) => (OP_MAKE_MAP, &[n]),
+        Index => (OP_INDEX, &[]),
+        StoreIndex => (OP_STORE_INDEX, &[]),
+        Cast(ty) => {
+            out.extend_from_slice(&[OP_CAST, cast_code(ty)]);
+            return;
+        }
+        Negate => (OP_NEGATE, &[]),
+        Not => (OP_NOT, &[]),
+        Add => (OP_ADD, &[]),
+        Subtract => (OP_SUBTRACT, &[]),
+        Multiply => (OP_MULTIPLY, &[]),
+        Divide => (OP_DIVIDE, &[]),
+        Modulo => (OP_MODULO, &[]),
+        Equal => (OP_EQUAL, &[]),
+        NotEqual => (OP_NOT_EQUAL, &[]),
+        Less => (OP_LESS, &[]),
+        Greater => (OP_GREATER, &[]),
+        LessEqual => (OP_LESS_EQUAL, &[]),
+        GreaterEqual => (OP_GREATER_EQUAL, &[]),
+        AndJump(t) => (OP_AND_JUMP, &[t]),
+        OrJump(t) => (OP_OR_JUMP, &[t]),
+        Jump(t) => (OP_JUMP, &[t]),
+        JumpIfFalse(t) => (OP_JUMP_IF_FALSE, &[t]),
+        Call(name, argc) => {
+            out.push(OP_CALL);
+            write_u32(out, name);
+            write_u32(out, argc);
+            return;
+        }
+        CallValue(argc) => (OP_CALL_VALUE, &[argc]),
+        DefineFunction(f) => (OP_DEFINE_FUNCTION, &[f]),
+        ExportFunction(f) => (OP_EXPORT_FUNCTION, &[f]),
+        MakeLambda(f) => (OP_MAKE_LAMBDA, &[f]),
+        Require(name) => (OP_REQUIRE, &[name]),
+        RequireAs(name, alias) => {
+            out.push(OP_REQUIRE_AS);
+            write_u32(out, name);
+            write_u32(out, alias);
+            return;
+        }
+        Return => (OP_RETURN, &[]),
+        EnterScope => (OP_ENTER_SCOPE, &[]),
+        ExitScope => (OP_EXIT_SCOPE, &[]),
+        IterStart => (OP_ITER_START, &[]),
+        RangeStart => (OP_RANGE_START, &[]),
+        IterNext(t) => (OP_ITER_NEXT, &[t]),
+        IterEnd => (OP_ITER_END, &[]),
+    };
+    out.push(opcode);
+    for &operand in operands {
+        write_u32(out, operand);
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, chunk: &Chunk) {
+    out.extend_from_slice(&chunk.slots.to_le_bytes());
+    write_len(out, chunk.code.len());
+    for &instruction in &chunk.code {
+        write_instruction(out, instruction);
+    }
+    for span in &chunk.spans {
+        write_len(out, span.line);
+        write_len(out, span.column);
+    }
+}
+
+impl Program {
+    /// Binary form of the program, written after the `.lb` header
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_str(&mut out, &self.source_name);
+
+        write_len(&mut out, self.constants.len());
+        for constant in &self.constants {
+            match constant {
+                Constant::Str(s) => {
+                    out.push(CONST_STR);
+                    write_str(&mut out, s);
+                }
+                Constant::Int(i) => {
+                    out.push(CONST_INT);
+                    out.extend_from_slice(&i.to_le_bytes());
+                }
+                Constant::Float(f) => {
+                    out.push(CONST_FLOAT);
+                    out.extend_from_slice(&f.to_le_bytes());
+                }
+            }
+        }
+
+        write_len(&mut out, self.functions.len());
+        for function in &self.functions {
+            write_str(&mut out, &function.name);
+            write_len(&mut out, function.params.len());
+            for param in &function.params {
+                write_str(&mut out, param);
+            }
+            write_chunk(&mut out, &function.chunk);
+        }
+
+        write_chunk(&mut out, &self.main);
+        out
+    }
+
+    /// Read a program written by `encode` and check that every operand is in range
+    pub fn decode(bytes: &[u8]) -> Result<Program, String> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let source_name = reader.string()?;
+
+        let constant_count = reader.len()?;
+        let mut constants = Vec::new();
+        for _ in 0..constant_count {
+            constants.push(match reader.byte()? {
+                CONST_STR => Constant::Str(reader.string()?),
+                CONST_INT => Constant::Int(i64::from_le_bytes(reader.array()?)),
+                CONST_FLOAT => Constant::Float(f64::from_le_bytes(reader.array()?)),
+                tag => return Err(format!("Invalid constant tag {}", tag)),
+            });
+        }
+
+        let function_count = reader.len()?;
+        let mut functions = Vec::new();
+        for _ in 0..function_count {
+            let name = reader.string()?;
+            let param_count = reader.len()?;
+            let mut params = Vec::new();
+            for _ in 0..param_count {
+                params.push(reader.string()?);
+            }
+            let chunk = reader.chunk()?;
+            functions.push(FunctionProto { name, params, chunk });
+        }
+
+        let main = reader.chunk()?;
+        if reader.pos != bytes.len() {
+            return Err(format!("{} unexpected byte(s) after the program", bytes.len() - reader.pos));
+        }
+
+        let program = Program { source_name, constants, functions, main };
+        program.validate()?;
+        Ok(program)
+    }
+
+    // Check operands so the VM can index the tables without further checks
+    fn validate(&self) -> Result<(), String> {
+        let chunks = self.functions.iter().map(|f| (f.name.as_str(), &f.chunk))
+            .chain(std::iter::once(("<main>", &self.main)));
+        for (owner, chunk) in chunks {
+            for (pos, &instruction) in chunk.code.iter().enumerate() {
+                let ok = match instruction {
+                    Instruction::Constant(i) => (i as usize) < self.constants.len(),
+                    Instruction::LoadVar(i) | Instruction::DefineVar(i) | Instruction::StoreVar(i)
+                    | Instruction::Call(i, _) | Instruction::Require(i) => {
+                        matches!(self.constants.get(i as usize), Some(Constant::Str(_)))
+                    }
+                    Instruction::RequireAs(name, alias) => [name, alias].iter()
+                        .all(|&i| matches!(self.constants.get(i as usize), Some(Constant::Str(_)))),
+                    Instruction::AndJump(t) | Instruction::OrJump(t) | Instruction::Jump(t)
+                    | Instruction::JumpIfFalse(t) | Instruction::IterNext(t) => t as usize <= chunk.code.len(),
+                    Instruction::DefineFunction(f) | Instruction::ExportFunction(f)
+                    | Instruction::MakeLambda(f) => (f as usize) < self.functions.len(),
+                    Instruction::LoadLocal(slot) | Instruction::StoreLocal(slot) => slot < chunk.slots,
+                    _ => true,
+                };
+                if !ok {
+                    return Err(format!("Invalid operand in instruction {} of {}: {:?}", pos, owner, instruction));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bundle {
+    /// Binary form: the main program followed by the module table, each program prefixed with its length
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let main = self.main.encode();
+        write_len(&mut out, main.len());
+        out.extend_from_slice(&main);
+
+        write_len(&mut out, self.modules.len());
+        for module in &self.modules {
+            let program = module.program.encode();
+            write_str(&mut out, &module.name);
+            write_len(&mut out, program.len());
+            out.extend_from_slice(&program);
+        }
+        out
+    }
+
+    /// Read a bundle written by `encode`; every program in it is checked like `Program::decode` does
+    pub fn decode(bytes: &[u8]) -> Result<Bundle, String> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let len = reader.len()?;
+        let main = Program::decode(reader.take(len)?)?;
+
+        let module_count = reader.len()?;
+        let mut modules = Vec::new();
+        for _ in 0..module_count {
+            let name = reader.string()?;
+            let len = reader.len()?;
+            let program = Program::decode(reader.take(len)?)
+                .map_err(|e| format!("module {}: {}", name, e))?;
+            modules.push(Module { name, program });
+        }
+        if reader.pos != bytes.len() {
+            return Err(format!("{} unexpected byte(s) after the module table", bytes.len() - reader.pos));
+        }
+        Ok(Bundle { main, modules })
+    }
+}
+
+// Cursor over the encoded program
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| "Unexpected end of bytecode".to_string())?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().expect("take returns N bytes"))
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| "Invalid UTF-8 string in bytecode".to_string())
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, String> {
+        use Instruction::*;
+        Ok(match self.byte()? {
+            OP_CONSTANT => Constant(self.u32()?),
+            OP_TRUE => True,
+            OP_FALSE => False,
+            OP_NULL => Null,
+            OP_POP => Pop,
+            OP_LOAD_VAR => LoadVar(self.u32()?),
+            OP_DEFINE_VAR => DefineVar(self.u32()?),
+            OP_STORE_VAR => StoreVar(self.u32()?),
+            OP_LOAD_LOCAL => LoadLocal(u16::from_le_bytes(self.array()?)),
+            OP_STORE_LOCAL => StoreLocal(u16::from_le_bytes(self.array()?)),
+            OP_MAKE_LIST => MakeList(self.u32()?),
+            OP_MAKE_MAP => MakeMap(self.u32()?),
+            OP_INDEX => Index,
+            OP_STORE_INDEX => StoreIndex,
+            OP_CAST => Cast(match self.byte()? {
+                0 => CastType::String,
+                1 => CastType::Int,
+                2 => CastType::Float,
+                3 => CastType::Bool,
+                code => return Err(format!("Invalid cast type {}", code)),
+            }),
+            OP_NEGATE => Negate,
+            OP_NOT => Not,
+            OP_ADD => Add,
+            OP_SUBTRACT => Subtract,
+            OP_MULTIPLY => Multiply,
+            OP_DIVIDE => Divide,
+            OP_MODULO => Modulo,
+            OP_EQUAL => Equal,
+            OP_NOT_EQUAL => NotEqual,
+            OP_LESS => Less,
+            OP_GREATER => Greater,
+            OP_LESS_EQUAL => LessEqual,
+            OP_GREATER_EQUAL => GreaterEqual,
+            OP_AND_JUMP => AndJump(self.u32()?),
+            OP_OR_JUMP => OrJump(self.u32()?),
+            OP_JUMP => Jump(self.u32()?),
+            OP_JUMP_IF_FALSE => JumpIfFalse(self.u32()?),
+            OP_CALL => Call(self.u32()?, self.u32()?),
+            OP_CALL_VALUE => CallValue(self.u32()?),
+            OP_DEFINE_FUNCTION => DefineFunction(self.u32()?),
+            OP_EXPORT_FUNCTION => ExportFunction(self.u32()?),
+            OP_MAKE_LAMBDA => MakeLambda(self.u32()?),
+            OP_REQUIRE => Require(self.u32()?),
+            OP_REQUIRE_AS => RequireAs(self.u32()?, self.u32()?),
+            OP_RETURN => Return,
+            OP_ENTER_SCOPE => EnterScope,
+            OP_EXIT_SCOPE => ExitScope,
+            OP_ITER_START => IterStart,
+            OP_RANGE_START => RangeStart,
+            OP_ITER_NEXT => IterNext(self.u32()?),
+            OP_ITER_END => IterEnd,
+            opcode => return Err(format!("Invalid opcode {}", opcode)),
+        })
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, String> {
+        let slots = u16::from_le_bytes(self.array()?);
+        let count = self.len()?;
+        let mut chunk = Chunk { slots, ..Chunk::default() };
+        for _ in 0..count {
+            chunk.code.push(self.instruction()?);
+        }
+        for _ in 0..count {
+            let line = self.len()?;
+            let column = self.len()?;
+            chunk.spans.push(Span { line, column });
+        }
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let source = "var(xs) = [int:1, float:2.5, \"a\"]\nfunc(f(n)) = { return n * int:2 }\nfor(x in var(xs)) { if(x == \"a\" && true) { break } }\nvar(g) = func(a) { return f(a) }";
+        let program = compile_program("demo.leon", &parse_program(source).unwrap()).unwrap();
+        let bytes = program.encode();
+        assert_eq!(Program::decode(&bytes), Ok(program));
+
+        assert_eq!(Program::decode(&bytes[..bytes.len() - 3]), Err("Unexpected end of bytecode".to_string()));
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let main = compile_program("main.leon", &parse_program("require(\"a\")\nfunc(f()) = { require(\"b\")\n require(\"a\") }").unwrap()).unwrap();
+        assert_eq!(main.required_modules(), vec!["a", "b"]);
+
+        let module = compile_program("a.leon", &parse_program("var(x) = int:1").unwrap()).unwrap();
+        let bundle = Bundle { main, modules: vec![Module { name: "a".to_string(), program: module }] };
+        assert_eq!(Bundle::decode(&bundle.encode()), Ok(bundle));
+    }
+
+    #[test]
+    fn test_slot_limit() {
+        // 200 nested blocks of `width` variables each, all alive at the innermost one
+        let function = |width: usize| {
+            let block: String = (0..width).map(|i| format!("var(v{}) = int:0\n", i)).collect();
+            format!("func(f()) = {{ {}{} }}", format!("if(true) {{\n{}", block).repeat(200), "}".repeat(200))
+        };
+        // Test threads get a smaller stack than the main thread the compiler runs on
+        std::thread::Builder::new().stack_size(8 << 20).spawn(move || {
+            let program = compile_program("wide.leon", &parse_program(&function(327)).unwrap()).unwrap();
+            assert_eq!(program.functions[0].chunk.slots, 65400);
+            let error = compile_program("wide.leon", &parse_program(&function(328)).unwrap()).unwrap_err();
+            assert_eq!(error.message, "Too many local variables (at most 65535 at once)");
+        }).unwrap().join().unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+use super::{Chunk, Constant, Instruction, Program};
+use crate::ast::{BinaryOp, UnaryOp};
+use crate::error::ScriptError;
+use crate::scope::{Scope, ScopeRef};
+use crate::{
+    apply_arithmetic, apply_unary, assign_index, cast_value, compare_values, condition_value, index_value,
+    iterate_range, iterate_value, Callable, Env, FunctionBody, Value,
+};
+use indexmap::IndexMap;
+use std::rc::Rc;
+
+// What to do after an instruction
+enum Step {
+    Next,
+    Jump(u32),
+    Return(Value),
+}
+
+// State of one running chunk: the main script or a single function call
+struct Frame {
+    stack: Vec<Value>,
+    // Local slots of the chunk
+    locals: Vec<Value>,
+    // Scopes to go back to at each ExitScope
+    saved_scopes: Vec<ScopeRef>,
+    // Active foreach loops, innermost last
+    iterators: Vec<Box<dyn Iterator<Item = Value>>>,
+}
+
+impl Frame {
+    // Frame for a chunk; `args` go into the first slots
+    fn new(chunk: &Chunk, mut args: Vec<Value>) -> Self {
+        args.resize(args.len().max(chunk.slots as usize), Value::Null);
+        Frame { stack: Vec::new(), locals: args, saved_scopes: Vec::new(), iterators: Vec::new() }
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Result<Value, String> {
+        self.stack.pop().ok_or_else(|| "Invalid bytecode: stack underflow".to_string())
+    }
+
+    // The top `count` values, in the order they were pushed
+    fn pop_many(&mut self, count: usize) -> Result<Vec<Value>, String> {
+        let start = self.stack.len().checked_sub(count)
+            .ok_or_else(|| "Invalid bytecode: stack underflow".to_string())?;
+        Ok(self.stack.split_off(start))
+    }
+}
+
+fn constant_value(constant: &Constant) -> Value {
+    match constant {
+        Constant::Str(s) => Value::String(s.clone()),
+        Constant::Int(i) => Value::Int(*i),
+        Constant::Float(f) => Value::Float(*f),
+    }
+}
+
+// Pop two operands and push the result of an arithmetic or comparison operator
+fn binary(op: BinaryOp, frame: &mut Frame) -> Result<(), String> {
+    let right = frame.pop()?;
+    let left = frame.pop()?;
+    let result = if op.is_comparison() {
+        Value::Bool(compare_values(op, &left, &right)?)
+    } else {
+        apply_arithmetic(op, left, right)?
+    };
+    frame.push(result);
+    Ok(())
+}
+
+// Left side of && and ||: the value that decides the result ends the evaluation
+fn short_circuit(op: BinaryOp, target: u32, frame: &mut Frame) -> Result<Step, String> {
+    match frame.stack.last() {
+        Some(Value::Bool(b)) if *b == (op == BinaryOp::Or) => Ok(Step::Jump(target)),
+        Some(Value::Bool(_)) => {
+            frame.pop()?;
+            Ok(Step::Next)
+        }
+        Some(other) => Err(format!("Operator '{}' requires bool operands, got {}", op, other.type_name())),
+        None => Err("Invalid bytecode: stack underflow".to_string()),
+    }
+}
+
+fn range_bound(value: Value) -> Result<i64, String> {
+    match value {
+        Value::Int(i) => Ok(i),
+        other => Err(format!("range() arguments must be integers, got {}", other.type_name())),
+    }
+}
+
+impl Env {
+    /// Run the top-level code of a compiled program
+    pub(crate) fn execute_program(&mut self, program: Rc<Program>) -> Result<(), ScriptError> {
+        let file: Rc<str> = Rc::from(program.source_name.as_str());
+        let previous_file = std::mem::replace(&mut self.current_file, file.clone());
+        let result = self.run_chunk(&program, &program.main, Vec::new());
+        self.current_file = previous_file;
+        result.map(|_| ()).map_err(|e| e.in_file(&file))
+    }
+
+    /// Body of an entry of the function table. The caller has set up the call's scope;
+    /// the arguments go into the first slots.
+    pub(crate) fn run_compiled_function(&mut self, program: &Rc<Program>, index: usize, args: Vec<Value>) -> Result<Value, ScriptError> {
+        self.run_chunk(program, &program.functions[index].chunk, args)
+    }
+
+    // Execute a chunk until it returns or runs off its end.
+    // Scopes entered by the chunk are left again however it exits.
+    fn run_chunk(&mut self, program: &Rc<Program>, chunk: &Chunk, args: Vec<Value>) -> Result<Value, ScriptError> {
+        let base_scope = self.scope.clone();
+        let mut frame = Frame::new(chunk, args);
+        let mut ip = 0;
+        let result = loop {
+            let Some(&instruction) = chunk.code.get(ip) else { break Ok(Value::Null) };
+            ip += 1;
+            match self.execute_instruction(program, instruction, &mut frame) {
+                Ok(Step::Next) => {}
+                Ok(Step::Jump(target)) => ip = target as usize,
+                Ok(Step::Return(value)) => break Ok(value),
+                Err(e) => break Err(e.at(chunk.spans[ip - 1])),
+            }
+        };
+        self.scope = base_scope;
+        result
+    }
+
+    fn execute_instruction(&mut self, program: &Rc<Program>, instruction: Instruction, frame: &mut Frame) -> Result<Step, ScriptError> {
+        match instruction {
+            Instruction::Constant(index) => frame.push(constant_value(&program.constants[index as usize])),
+            Instruction::True => frame.push(Value::Bool(true)),
+            Instruction::False => frame.push(Value::Bool(false)),
+            Instruction::Null => frame.push(Value::Null),
+            Instruction::Pop => {
+                frame.pop()?;
+            }
+            Instruction::LoadVar(name) => {
+                let value = self.get_variable(program.name(name))?;
+                frame.push(value);
+            }
+            Instruction::DefineVar(name) => {
+                let value = frame.pop()?;
+                self.define_variable(program.name(name), value);
+            }
+            Instruction::StoreVar(name) => {
+                let value = frame.pop()?;
+                self.assign_variable(program.name(name), value)?;
+            }
+            // decode checked the slot numbers against the chunk
+            Instruction::LoadLocal(slot) => {
+                let value = frame.locals[slot as usize].clone();
+                frame.push(value);
+            }
+            Instruction::StoreLocal(slot) => {
+                frame.locals[slot as usize] = frame.pop()?;
+            }
+            Instruction::MakeList(count) => {
+                let items = frame.pop_many(count as usize)?;
+                frame.push(Value::new_list(items));
+            }
+            Instruction::MakeMap(count) => {
+                let mut values = frame.pop_many(count as usize * 2)?.into_iter();
+                let mut map = IndexMap::with_capacity(count as usize);
+                while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                    let key = match key {
+                        Value::String(key) => key,
+                        other => return Err(format!("Map key must be a string, got {}", other.type_name()).into()),
+                    };
+                    map.insert(key, value);
+                }
+                frame.push(Value::new_map(map));
+            }
+            Instruction::Index => {
+                let index = frame.pop()?;
+                let target = frame.pop()?;
+                frame.push(index_value(&target, &index)?);
+            }
+            Instruction::StoreIndex => {
+                let value = frame.pop()?;
+                let index = frame.pop()?;
+                let target = frame.pop()?;
+                assign_index(&target, &index, value)?;
+            }
+            Instruction::Cast(ty) => {
+                let value = frame.pop()?;
+                frame.push(cast_value(ty, value)?);
+            }
+            Instruction::Negate => {
+                let value = frame.pop()?;
+                frame.push(apply_unary(UnaryOp::Neg, value)?);
+            }
+            Instruction::Not => {
+                let value = frame.pop()?;
+                frame.push(apply_unary(UnaryOp::Not, value)?);
+            }
+            Instruction::Add => binary(BinaryOp::Add, frame)?,
+            Instruction::Subtract => binary(BinaryOp::Sub, frame)?,
+            Instruction::Multiply => binary(BinaryOp::Mul, frame)?,
+            Instruction::Divide => binary(BinaryOp::Div, frame)?,
+            Instruction::Modulo => binary(BinaryOp::Mod, frame)?,
+            Instruction::Equal => binary(BinaryOp::Eq, frame)?,
+            Instruction::NotEqual => binary(BinaryOp::NotEq, frame)?,
+            Instruction::Less => binary(BinaryOp::Lt, frame)?,
+            Instruction::Greater => binary(BinaryOp::Gt, frame)?,
+            Instruction::LessEqual => binary(BinaryOp::LtEq, frame)?,
+            Instruction::GreaterEqual => binary(BinaryOp::GtEq, frame)?,
+            Instruction::AndJump(target) => return Ok(short_circuit(BinaryOp::And, target, frame)?),
+            Instruction::OrJump(target) => return Ok(short_circuit(BinaryOp::Or, target, frame)?),
+            Instruction::Jump(target) => return Ok(Step::Jump(target)),
+            Instruction::JumpIfFalse(target) => {
+                if !condition_value(frame.pop()?)? {
+                    return Ok(Step::Jump(target));
+                }
+            }
+            Instruction::Call(name, argc) => {
+                let args = frame.pop_many(argc as usize)?;
+                let result = self.execute_function_call(program.name(name), args)?;
+                frame.push(result);
+            }
+            Instruction::CallValue(argc) => {
+                let args = frame.pop_many(argc as usize)?;
+                let function = match frame.pop()? {
+                    Value::Function(function) => function,
+                    other => return Err(format!("Cannot call a {} value", other.type_name()).into()),
+                };
+                let result = self.call_value(&function, args)?;
+                frame.push(result);
+            }
+            Instruction::DefineFunction(index) | Instruction::ExportFunction(index) => {
+                let proto = &program.functions[index as usize];
+                let body = FunctionBody::Compiled(program.clone(), index as usize);
+                let exported = matches!(instruction, Instruction::ExportFunction(_));
+                self.parse_function_definition(&proto.name, &proto.params, body, exported);
+            }
+            Instruction::MakeLambda(index) => {
+                let proto = &program.functions[index as usize];
+                let body = FunctionBody::Compiled(program.clone(), index as usize);
+                let function = self.new_user_function(&proto.name, &proto.params, body);
+                frame.push(Value::Function(Callable::User(Rc::new(function))));
+            }
+            Instruction::Require(name) => self.handle_require(program.name(name), None)?,
+            Instruction::RequireAs(name, alias) => self.handle_require(program.name(name), Some(program.name(alias)))?,
+            Instruction::Return => return Ok(Step::Return(frame.pop()?)),
+            Instruction::EnterScope => {
+                let scope = Scope::new_child(&self.scope);
+                frame.saved_scopes.push(std::mem::replace(&mut self.scope, scope));
+            }
+            Instruction::ExitScope => {
+                self.scope = frame.saved_scopes.pop()
+                    .ok_or_else(|| "Invalid bytecode: no scope to exit".to_string())?;
+            }
+            Instruction::IterStart => {
+                let items = iterate_value(frame.pop()?)?;
+                frame.iterators.push(items);
+            }
+            Instruction::RangeStart => {
+                let step = range_bound(frame.pop()?)?;
+                let end = range_bound(frame.pop()?)?;
+                let start = range_bound(frame.pop()?)?;
+                frame.iterators.push(iterate_range(start, end, step)?);
+            }
+            Instruction::IterNext(target) => {
+                let items = frame.iterators.last_mut()
+                    .ok_or_else(|| "Invalid bytecode: no loop to continue".to_string())?;
+                match items.next() {
+                    Some(item) => frame.push(item),
+                    None => {
+                        frame.iterators.pop();
+                        return Ok(Step::Jump(target));
+                    }
+                }
+            }
+            Instruction::IterEnd => {
+                frame.iterators.pop();
+            }
+        }
+        Ok(Step::Next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile_program;
+    use crate::parser::parse_program;
+
+    fn global(env: &Env, name: &str) -> String {
+        env.scope.borrow().with_value(name, Value::to_display_string).unwrap()
+    }
+
+    #[test]
+    fn test_compiled_program_matches_interpreter() {
+        let source = r#"
+            func(fib(n)) = { if(n < int:2) { return n } return fib(n - int:1) + fib(n - int:2) }
+            var(total) = int:0
+            for(var(i) = int:0; i < int:10; i = i + int:1) {
+                if(i % int:2 == int:0) { continue }
+                var(total) = int:0
+                total = total + i
+            }
+            var(sum) = int:0
+            for(x in range(int:10)) { if(x > int:4 || false) { break } sum = sum + x }
+            var(counter) = int:0
+            var(bump) = func() { counter = counter + int:1; return counter }
+            bump(); bump()
+            var(squares) = list.map([int:1, int:2, int:3], func(x) { return x * x })
+            var(names) = {"a": int:1}
+            names["b"] = fib(int:10)
+            var(result) = [fib(int:15), total, sum, counter, squares, names, !true && bump() == int:3]
+        "#;
+        let program = parse_program(source).unwrap();
+
+        let mut interpreted = Env::new(false);
+        crate::builtins::register_list_functions(&mut interpreted);
+        interpreted.execute_block(&program).unwrap();
+
+        let mut compiled = Env::new(false);
+        crate::builtins::register_list_functions(&mut compiled);
+        compiled.execute_program(Rc::new(compile_program("test.leon", &program).unwrap())).unwrap();
+
+        let expected = "[610, 0, 10, 2, [1, 4, 9], {\"a\": 1, \"b\": 55}, false]";
+        assert_eq!(global(&interpreted, "result"), expected);
+        assert_eq!(global(&compiled, "result"), expected);
+
+        let error = compiled.execute_program(Rc::new(compile_program("bad.leon", &parse_program("var(x) = int:1\nvar(y) = x + [int:1]").unwrap()).unwrap()));
+        let error = error.unwrap_err();
+        assert_eq!(error.to_string(), "Execution error: Operator '+' cannot be used with int and list (bad.leon:2:12)");
+    }
+
+    #[test]
+    fn test_local_slots_match_interpreter() {
+        let source = r#"
+            var(x) = int:100
+            func(counter(start)) = {
+                var(n) = start
+                return func() { n = n + int:1; return n }
+            }
+            func(shadow(x)) = {
+                var(seen) = [x]
+                if(x > int:0) { var(x) = x * int:10; list.push(seen, x) }
+                list.push(seen, x)
+                var(x) = x + int:1
+                return [seen, x]
+            }
+            func(apply(f, v)) = { return f(v) }
+            func(loops()) = {
+                var(fs) = []
+                for(i in range(int:3)) { list.push(fs, func() { return i }) }
+                var(total) = int:0
+                for(var(i) = int:0; i < int:4; i = i + int:1) { var(sq) = i * i; total = total + sq }
+                return [list.map(fs, func(f) { return f() }), total]
+            }
+            var(c) = counter(int:5)
+            c()
+            if(true) { var(x) = int:1; x = x + int:1 }
+            var(result) = [c(), shadow(int:2), shadow(-int:1), apply(func(v) { return v + x }, int:1), loops(), x, -int:3, string:float:1.5]
+        "#;
+        let program = parse_program(source).unwrap();
+        let compiled_program = compile_program("test.leon", &program).unwrap();
+        assert!(compiled_program.functions.iter().any(|f| f.chunk.code.contains(&Instruction::LoadLocal(0))));
+        assert!(!compiled_program.main.code.iter().any(|i| matches!(i, Instruction::Cast(_) | Instruction::Negate)));
+
+        let mut interpreted = Env::new(false);
+        crate::builtins::register_list_functions(&mut interpreted);
+        interpreted.execute_block(&program).unwrap();
+
+        let mut compiled = Env::new(false);
+        crate::builtins::register_list_functions(&mut compiled);
+        compiled.execute_program(Rc::new(compiled_program)).unwrap();
+
+        let expected = "[7, [[2, 20, 2], 3], [[-1, -1], 0], 101, [[0, 1, 2], 14], 100, -3, \"1.5\"]";
+        assert_eq!(global(&interpreted, "result"), expected);
+        assert_eq!(global(&compiled, "result"), expected);
+    }
+}
@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+// Command line of the interpreter. The older flag forms (--build, --shell, ...) are kept as
+// long flags of the matching subcommands, so `leonbasic --build x.leon` and
+// `leonbasic build x.leon` are the same.
+#[derive(Parser)]
+#[command(name = "leonbasic", about = "LeonBasic Interpreter", disable_version_flag = true,
+          args_conflicts_with_subcommands = true, arg_required_else_help = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// LeonBasic script (.leon) or bytecode file (.lb) to execute
+    pub file: Option<String>,
+
+    #[command(flatten)]
+    pub run: RunOptions,
+
+    /// Display version information
+    #[arg(long, alias = "ver")]
+    pub version: bool,
+}
+
+#[derive(Args)]
+pub struct RunOptions {
+    /// Print every statement as it is executed
+    #[arg(long)]
+    pub debug: bool,
+
+    /// Key of an encrypted bytecode file
+    #[arg(long, value_name = "KEY")]
+    pub key: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Create a project: leon.toml, main.leon and lib/
+    Init {
+        /// Directory of the project
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Run the entry script of the project in the current directory
+    Run {
+        #[command(flatten)]
+        options: RunOptions,
+    },
+
+    /// Install a package from a directory or a .tar/.tar.gz archive into ~/.leonbasic/packages
+    Install {
+        /// Package directory with a leon.toml, or an archive of one
+        source: PathBuf,
+    },
+
+    /// Compile a LeonBasic file to bytecode (.lb)
+    #[command(long_flag = "build")]
+    Build {
+        /// Script to compile; the output is written next to it
+        file: String,
+
+        /// Embed the modules the script requires
+        #[arg(long)]
+        bundle: bool,
+
+        /// Encrypt the program with this key
+        #[arg(long, value_name = "KEY")]
+        key: Option<String>,
+    },
+
+    /// Show the contents of a bytecode file
+    #[command(long_flag = "disasm")]
+    Disasm {
+        /// Bytecode file to show
+        file: String,
+
+        /// Key of an encrypted bytecode file
+        #[arg(long, value_name = "KEY")]
+        key: Option<String>,
+    },
+
+    /// Start interactive shell
+    #[command(long_flag = "shell")]
+    Shell,
+
+    /// Add LeonBasic to system PATH
+    #[command(long_flag = "setpath")]
+    Setpath {
+        /// Only show the change
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Remove LeonBasic from system PATH
+    #[command(long_flag = "unsetpath")]
+    Unsetpath {
+        /// Only show the change
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flag_and_subcommand_forms() {
+        let cli = Cli::try_parse_from(["leonbasic", "--build", "a.leon", "--bundle"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Build { ref file, bundle: true, key: None }) if file == "a.leon"));
+        let cli = Cli::try_parse_from(["leonbasic", "a.lb", "--debug", "--key", "k"]).unwrap();
+        assert_eq!((cli.file.as_deref(), cli.run.debug, cli.run.key.as_deref()), (Some("a.lb"), true, Some("k")));
+        assert!(matches!(Cli::try_parse_from(["leonbasic", "run"]).unwrap().command, Some(Command::Run { .. })));
+        assert!(Cli::try_parse_from(["leonbasic", "--ver"]).unwrap().version);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::ast::{Span, SyntaxError};
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Syntax,
+    Runtime,
+}
+
+/// Error raised while loading or running a script.
+///
+/// Errors start out as plain messages and pick up their location on the way out:
+/// the innermost expression or statement that failed sets the span, and the file
+/// is filled in by the script, module or function body the span belongs to.
+#[derive(Debug, Clone)]
+pub struct ScriptError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub span: Option<Span>,
+    pub file: Option<Rc<str>>,
+}
+
+impl ScriptError {
+    pub fn runtime(message: impl Into<String>) -> Self {
+        Self { kind: ErrorKind::Runtime, message: message.into(), span: None, file: None }
+    }
+
+    /// Attach a location unless a more precise one is already known
+    pub fn at(mut self, span: Span) -> Self {
+        if self.span.is_none() {
+            self.span = Some(span);
+        }
+        self
+    }
+
+    /// Record which file the span refers to, unless that is already known
+    pub fn in_file(mut self, file: &Rc<str>) -> Self {
+        if self.file.is_none() {
+            self.file = Some(file.clone());
+        }
+        self
+    }
+
+    fn title(&self) -> &'static str {
+        match self.kind {
+            ErrorKind::Syntax => "Syntax error",
+            ErrorKind::Runtime => "Execution error",
+        }
+    }
+
+    /// Full report: the message, a file:line:column pointer and the offending
+    /// source line with a caret under the column. `source` is the text of the file.
+    pub fn render(&self, source: Option<&str>) -> String {
+        let mut report = format!("{}: {}", self.title(), self.message);
+        let Some(span) = self.span else { return report };
+
+        let file = self.file.as_deref().unwrap_or("<input>");
+        report.push_str(&format!("\n --> {}:{}:{}", file, span.line, span.column));
+
+        let line = source.and_then(|text| text.lines().nth(span.line.saturating_sub(1)));
+        if let Some(line) = line {
+            let number = span.line.to_string();
+            let gutter = " ".repeat(number.len());
+            // Keep tabs so the caret lines up with the text above it
+            let padding: String = line.chars()
+                .take(span.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            report.push_str(&format!("\n{} |\n{} | {}\n{} | {}^", gutter, number, line, gutter, padding));
+        }
+        report
+    }
+}
+
+// Short single-line form, used where there is no room for a snippet
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.title(), self.message)?;
+        match (&self.file, self.span) {
+            (Some(file), Some(span)) => write!(f, " ({}:{}:{})", file, span.line, span.column),
+            (None, Some(span)) => write!(f, " (line {}, column {})", span.line, span.column),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<String> for ScriptError {
+    fn from(message: String) -> Self {
+        ScriptError::runtime(message)
+    }
+}
+
+impl From<SyntaxError> for ScriptError {
+    fn from(error: SyntaxError) -> Self {
+        Self { kind: ErrorKind::Syntax, message: error.message, span: Some(error.span), file: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_points_at_column() {
+        let file: Rc<str> = Rc::from("demo.leon");
+        let error = ScriptError::runtime("Undefined variable: x")
+            .at(Span { line: 2, column: 13 })
+            .at(Span { line: 1, column: 1 })
+            .in_file(&file);
+        let report = error.render(Some("var(a) = int:1\nbasic.print(var(x))\n"));
+        assert_eq!(
+            report,
+            "Execution error: Undefined variable: x\n --> demo.leon:2:13\n  |\n2 | basic.print(var(x))\n  |             ^"
+        );
+    }
+}
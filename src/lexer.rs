@@ -0,0 +1,271 @@
+use crate::ast::{Span, SyntaxError};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while};
+use nom::character::complete::{char, digit1, multispace1, satisfy};
+use nom::combinator::{opt, recognize, value};
+use nom::multi::many0_count;
+use nom::sequence::pair;
+use nom::IResult;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Ident(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Comma,
+    Semicolon,
+    Colon,
+    Dot,
+    Assign,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Eq,
+    NotEq,
+    Lt,
+    Gt,
+    LtEq,
+    GtEq,
+    AndAnd,
+    OrOr,
+    Not,
+    Eof,
+}
+
+impl TokenKind {
+    /// Human readable form used in error messages
+    pub fn describe(&self) -> String {
+        match self {
+            TokenKind::Ident(name) => format!("'{}'", name),
+            TokenKind::Int(i) => format!("number {}", i),
+            TokenKind::Float(f) => format!("number {}", f),
+            TokenKind::Str(_) => "string literal".to_string(),
+            TokenKind::LParen => "'('".to_string(),
+            TokenKind::RParen => "')'".to_string(),
+            TokenKind::LBrace => "'{'".to_string(),
+            TokenKind::RBrace => "'}'".to_string(),
+            TokenKind::LBracket => "'['".to_string(),
+            TokenKind::RBracket => "']'".to_string(),
+            TokenKind::Comma => "','".to_string(),
+            TokenKind::Semicolon => "';'".to_string(),
+            TokenKind::Colon => "':'".to_string(),
+            TokenKind::Dot => "'.'".to_string(),
+            TokenKind::Assign => "'='".to_string(),
+            TokenKind::Plus => "'+'".to_string(),
+            TokenKind::Minus => "'-'".to_string(),
+            TokenKind::Star => "'*'".to_string(),
+            TokenKind::Slash => "'/'".to_string(),
+            TokenKind::Percent => "'%'".to_string(),
+            TokenKind::Eq => "'=='".to_string(),
+            TokenKind::NotEq => "'!='".to_string(),
+            TokenKind::Lt => "'<'".to_string(),
+            TokenKind::Gt => "'>'".to_string(),
+            TokenKind::LtEq => "'<='".to_string(),
+            TokenKind::GtEq => "'>='".to_string(),
+            TokenKind::AndAnd => "'&&'".to_string(),
+            TokenKind::OrOr => "'||'".to_string(),
+            TokenKind::Not => "'!'".to_string(),
+            TokenKind::Eof => "end of input".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+// Whitespace and // line comments between tokens
+fn skip_trivia(input: &str) -> IResult<&str, usize> {
+    many0_count(alt((
+        multispace1,
+        recognize(pair(tag("//"), take_while(|c| c != '\n'))),
+    )))(input)
+}
+
+fn identifier(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        satisfy(|c| c.is_alphabetic() || c == '_'),
+        take_while(|c: char| c.is_alphanumeric() || c == '_'),
+    ))(input)
+}
+
+fn number(input: &str) -> IResult<&str, &str> {
+    recognize(pair(digit1, opt(pair(char('.'), digit1))))(input)
+}
+
+// Two-character operators must be tried before their one-character prefixes
+fn punctuation(input: &str) -> IResult<&str, TokenKind> {
+    alt((
+        alt((
+            value(TokenKind::Eq, tag("==")),
+            value(TokenKind::NotEq, tag("!=")),
+            value(TokenKind::LtEq, tag("<=")),
+            value(TokenKind::GtEq, tag(">=")),
+            value(TokenKind::AndAnd, tag("&&")),
+            value(TokenKind::OrOr, tag("||")),
+            value(TokenKind::Not, char('!')),
+        )),
+        alt((
+            value(TokenKind::Lt, char('<')),
+            value(TokenKind::Gt, char('>')),
+            value(TokenKind::Assign, char('=')),
+            value(TokenKind::Plus, char('+')),
+            value(TokenKind::Minus, char('-')),
+            value(TokenKind::Star, char('*')),
+            value(TokenKind::Slash, char('/')),
+            value(TokenKind::Percent, char('%')),
+        )),
+        alt((
+            value(TokenKind::LParen, char('(')),
+            value(TokenKind::RParen, char(')')),
+            value(TokenKind::LBrace, char('{')),
+            value(TokenKind::RBrace, char('}')),
+            value(TokenKind::LBracket, char('[')),
+            value(TokenKind::RBracket, char(']')),
+            value(TokenKind::Comma, char(',')),
+            value(TokenKind::Semicolon, char(';')),
+            value(TokenKind::Colon, char(':')),
+            value(TokenKind::Dot, char('.')),
+        )),
+    ))(input)
+}
+
+/// Parse a double-quoted string literal, processing escape characters.
+/// Returns None as the value when the closing quote is missing.
+fn string_literal(input: &str) -> IResult<&str, Option<String>> {
+    let (rest, _) = char('"')(input)?;
+    let mut result = String::new();
+    let mut chars = rest.chars();
+
+    loop {
+        match chars.next() {
+            None => return Ok(("", None)),
+            Some('"') => return Ok((chars.as_str(), Some(result))),
+            Some('\\') => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('r') => result.push('\r'),
+                Some('"') => result.push('"'),
+                Some('\\') => result.push('\\'),
+                // Special handling for \+ to display as +
+                Some('+') => result.push('+'),
+                Some(other) => {
+                    // Keep other escape sequences as is
+                    result.push('\\');
+                    result.push(other);
+                }
+                None => result.push('\\'),
+            },
+            Some(c) => result.push(c),
+        }
+    }
+}
+
+struct Lexer<'a> {
+    rest: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn span(&self) -> Span {
+        Span { line: self.line, column: self.column }
+    }
+
+    // Move past `len` bytes of input, keeping line and column up to date
+    fn advance(&mut self, len: usize) {
+        let (consumed, rest) = self.rest.split_at(len);
+        for c in consumed.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.rest = rest;
+    }
+
+    fn consumed_len(&self, remaining: &str) -> usize {
+        self.rest.len() - remaining.len()
+    }
+
+    fn next_token(&mut self) -> Result<Token, SyntaxError> {
+        if let Ok((remaining, _)) = skip_trivia(self.rest) {
+            let len = self.consumed_len(remaining);
+            self.advance(len);
+        }
+
+        let span = self.span();
+        if self.rest.is_empty() {
+            return Ok(Token { kind: TokenKind::Eof, span });
+        }
+
+        if let Ok((remaining, literal)) = string_literal(self.rest) {
+            let kind = match literal {
+                Some(s) => TokenKind::Str(s),
+                None => return Err(SyntaxError::new("Unterminated string literal", span)),
+            };
+            let len = self.consumed_len(remaining);
+            self.advance(len);
+            return Ok(Token { kind, span });
+        }
+
+        if let Ok((remaining, text)) = number(self.rest) {
+            let kind = if text.contains('.') {
+                TokenKind::Float(text.parse::<f64>().map_err(|_| {
+                    SyntaxError::new(format!("Invalid number: {}", text), span)
+                })?)
+            } else {
+                TokenKind::Int(text.parse::<i64>().map_err(|_| {
+                    SyntaxError::new(format!("Integer literal out of range: {}", text), span)
+                })?)
+            };
+            let len = self.consumed_len(remaining);
+            self.advance(len);
+            return Ok(Token { kind, span });
+        }
+
+        if let Ok((remaining, name)) = identifier(self.rest) {
+            let kind = TokenKind::Ident(name.to_string());
+            let len = self.consumed_len(remaining);
+            self.advance(len);
+            return Ok(Token { kind, span });
+        }
+
+        if let Ok((remaining, kind)) = punctuation(self.rest) {
+            let len = self.consumed_len(remaining);
+            self.advance(len);
+            return Ok(Token { kind, span });
+        }
+
+        let unexpected = self.rest.chars().next().unwrap_or(' ');
+        Err(SyntaxError::new(format!("Unexpected character '{}'", unexpected), span))
+    }
+}
+
+/// Split source code into tokens. The last token is always Eof.
+pub fn tokenize(source: &str) -> Result<Vec<Token>, SyntaxError> {
+    let mut lexer = Lexer { rest: source, line: 1, column: 1 };
+    let mut tokens = Vec::new();
+
+    loop {
+        let token = lexer.next_token()?;
+        let is_eof = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if is_eof {
+            return Ok(tokens);
+        }
+    }
+}
@@ -1,79 +1,20 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Read, stdin, Write};
+use std::io::{Read, stdin};
 use std::path::Path;
-use std::env;
-use std::process::Command;
-use std::os::windows::process::CommandExt; // 用于Windows特定的命令扩展
+use std::rc::Rc;
+use std::cell::RefCell;
+use indexmap::IndexMap;
 
 // Import build module for bytecode support
 mod build;
- 
-// Print usage information using standard error output
-fn print_usage(program_name: &str) {
-    eprintln!("LeonBasic Interpreter v{}", version::VERSION);
-    eprintln!("Usage:");
-    eprintln!("  {} <file> [--debug]          # Execute LeonBasic script or bytecode file", program_name);
-    eprintln!("  {} --build <file>            # Compile LeonBasic file to bytecode (.lb)", program_name);
-    eprintln!("  {} --shell                   # Start interactive shell", program_name);
-    eprintln!("  {} --setpath                 # Add LeonBasic to system PATH", program_name);
-    eprintln!("  {} --version | --ver         # Display version information", program_name);
-}
-
-/// 将LeonBasic添加到系统PATH环境变量
-fn add_to_path() -> Result<(), String> {
-    // 获取当前可执行文件的路径
-    let exe_path = env::current_exe()
-        .map_err(|e| format!("Failed to get executable path: {}", e))?;
-    
-    // 获取可执行文件所在的目录
-    let exe_dir = match exe_path.parent() {
-        Some(dir) => dir,
-        None => return Err("Failed to get executable directory".to_string())
-    };
-    
-    let exe_dir_str = exe_dir.to_string_lossy();
-    
-    // 在Windows上，我们需要使用PowerShell以管理员权限运行命令来修改PATH
-    #[cfg(target_os = "windows")]
-    {
-        println!("Adding {} to system PATH...", exe_dir_str);
-        println!("This operation requires administrator privileges.");
-        
-        // 创建一个PowerShell命令，以管理员权限运行
-        let powershell_cmd = format!(
-            "[Environment]::SetEnvironmentVariable('PATH', \"$env:PATH;{}\", 'Machine')",
-            exe_dir_str.replace('\\', "\\\\")
-        );
-        
-        // 使用PowerShell以管理员权限运行命令
-        let mut cmd = Command::new("powershell.exe");
-        cmd.arg("-Command")
-           .arg(&powershell_cmd)
-           .creation_flags(0x00000008); // CREATE_NO_WINDOW
-        
-        let output = cmd.output()
-            .map_err(|e| format!("Failed to run PowerShell command: {}", e))?;
-        
-        if output.status.success() {
-            println!("Successfully added to system PATH.");
-            println!("Please restart your terminal or command prompt to apply the changes.");
-            Ok(())
-        } else {
-            let error_msg = String::from_utf8_lossy(&output.stderr);
-            Err(format!("Failed to add to PATH. Error: {}. Please run as administrator.", error_msg))
-        }
-    }
-    
-    // 对于其他操作系统，提供一般性指导
-    #[cfg(not(target_os = "windows"))]
-    {
-        println!("To add LeonBasic to PATH, add the following line to your shell configuration file:");
-        println!("export PATH=\"{}:$PATH\"".replace('\\', '/'), exe_dir_str);
-        println!("Then restart your terminal.");
-        Ok(())
-    }
-}
+// Compiled programs: instruction set, compiler and virtual machine
+mod bytecode;
+// --setpath / --unsetpath
+mod setpath;
+// Command line arguments and subcommands
+mod cli;
 
 // ANSI color codes for terminal output
 const RESET: &str = "\x1b[0m";
@@ -88,30 +29,135 @@ const CYAN: &str = "\x1b[36m";
 mod builtins;
 // Import version information
 mod version;
+// Import tokenizer, syntax tree and parser
+mod ast;
+mod lexer;
+mod parser;
+// Variable scopes (global, module, function, block)
+mod scope;
+// Import module search path handling
+mod package;
+// Errors with source locations
+mod error;
+
+use scope::{Scope, ScopeRef};
+use error::ScriptError;
+use bytecode::Program;
+use package::{PackageManager, Project};
+use cli::{Cli, Command, RunOptions};
+use clap::{CommandFactory, Parser};
+use ast::{BinaryOp, Block, CastType, Expr, ExprKind, ForIterable, Stmt, StmtKind, UnaryOp};
 
 // Define value types
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Value {
     String(String),
     Int(i64),
     Float(f64),
-    // Add File type for file operations
-    #[allow(dead_code)]
-    File(File),
-    // Null variant is not used temporarily, retained for future extension
-    #[allow(dead_code)]
+    Bool(bool),
+    // Lists are shared: copies of a list value refer to the same items
+    List(Rc<RefCell<Vec<Value>>>),
+    // String keys in insertion order; shared like lists
+    Map(Rc<RefCell<IndexMap<String, Value>>>),
+    Function(Callable),
+    // Open file from basic.open; copies of the value share the same handle
+    File(Rc<FileHandle>),
     Null,
 }
 
-// Manually implement Clone trait
-impl Clone for Value {
-    fn clone(&self) -> Self {
+// File resource behind a file value. basic.close releases the file for every copy
+// of the value at once; using any of them afterwards is an error.
+#[derive(Debug)]
+struct FileHandle {
+    path: String,
+    file: RefCell<Option<File>>,
+}
+
+impl FileHandle {
+    // Run an operation on the open file
+    fn with_file<T>(&self, operation: impl FnOnce(&mut File) -> std::io::Result<T>) -> Result<T, String> {
+        match self.file.borrow_mut().as_mut() {
+            Some(file) => operation(file).map_err(|e| format!("{}: {}", self.path, e)),
+            None => Err(format!("File {} has already been closed", self.path)),
+        }
+    }
+    
+    fn close(&self) -> Result<(), String> {
+        match self.file.borrow_mut().take() {
+            Some(_) => Ok(()),
+            None => Err(format!("File {} has already been closed", self.path)),
+        }
+    }
+}
+
+impl Value {
+    // String form used for concatenation and string: conversion
+    fn to_display_string(&self) -> String {
+        self.format_value(false, &mut Vec::new())
+    }
+    
+    // `quoted` is set for values inside a collection, where strings are quoted. `open` holds the
+    // collections being printed around this value; one that contains itself is printed as [...] or {...}
+    // the second time
+    fn format_value(&self, quoted: bool, open: &mut Vec<*const ()>) -> String {
         match self {
-            Value::String(s) => Value::String(s.clone()),
-            Value::Int(i) => Value::Int(*i),
-            Value::Float(f) => Value::Float(*f),
-            Value::Null => Value::Null,
-            Value::File(_) => panic!("Cannot clone file handle"), // Or return an error
+            Value::String(s) if quoted => format!("{:?}", s),
+            Value::String(s) => s.clone(),
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::List(items) => {
+                let address = Rc::as_ptr(items) as *const ();
+                if open.contains(&address) {
+                    return "[...]".to_string();
+                }
+                open.push(address);
+                let items: Vec<String> = items.borrow().iter().map(|item| item.format_value(true, open)).collect();
+                open.pop();
+                format!("[{}]", items.join(", "))
+            }
+            Value::Map(entries) => {
+                let address = Rc::as_ptr(entries) as *const ();
+                if open.contains(&address) {
+                    return "{...}".to_string();
+                }
+                open.push(address);
+                let entries: Vec<String> = entries.borrow().iter()
+                    .map(|(key, value)| format!("{:?}: {}", key, value.format_value(true, open)))
+                    .collect();
+                open.pop();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Value::Function(function) => format!("[function {}]", function.name()),
+            Value::Null => "null".to_string(),
+            Value::File(handle) => format!("[File {}]", handle.path),
+        }
+    }
+    
+    fn new_list(items: Vec<Value>) -> Value {
+        Value::List(Rc::new(RefCell::new(items)))
+    }
+    
+    fn new_map(entries: IndexMap<String, Value>) -> Value {
+        Value::Map(Rc::new(RefCell::new(entries)))
+    }
+    
+    fn new_file(path: &str, file: File) -> Value {
+        Value::File(Rc::new(FileHandle { path: path.to_string(), file: RefCell::new(Some(file)) }))
+    }
+    
+    // Type name used in error messages
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Function(_) => "function",
+            Value::Null => "null",
+            Value::File(_) => "file",
         }
     }
 }
@@ -119,576 +165,398 @@ impl Clone for Value {
 // Function type alias
 type Function = Box<dyn Fn(Vec<Value>) -> Result<Value, String>>;
 
+// Function defined in a script with func(name(params)) = { ... } or func(params) { ... }
+struct UserFunction {
+    name: String,
+    params: Vec<String>,
+    body: FunctionBody,
+    // Scope the function was defined in; its body can see the variables declared there
+    closure: ScopeRef,
+    // Source file of the definition, for error locations
+    file: Rc<str>,
+}
+
+// Code of a user function: statements from a parsed source file, or an entry of a compiled program
+enum FunctionBody {
+    Ast(Rc<Block>),
+    Compiled(Rc<Program>, usize),
+}
+
+impl std::fmt::Debug for UserFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserFunction").field("name", &self.name).field("params", &self.params).finish()
+    }
+}
+
+// A function used as a value: stored in a variable, passed as an argument or returned
+#[derive(Debug, Clone)]
+enum Callable {
+    User(Rc<UserFunction>),
+    // Builtin from Env.functions, referenced by its full name such as list.len
+    Builtin(Rc<str>),
+}
+
+impl Callable {
+    fn name(&self) -> &str {
+        match self {
+            Callable::User(function) => &function.name,
+            Callable::Builtin(name) => name,
+        }
+    }
+}
+
+// Builtins that call back into script functions; they need the interpreter so Env runs them itself
+const CALLBACK_BUILTINS: [&str; 2] = ["list.map", "list.filter"];
+
+// Name given to anonymous functions in messages
+const LAMBDA_NAME: &str = "<lambda>";
+
+// How control leaves a statement or block
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+    // return statement, carries the function result up to the caller
+    Return(Value),
+}
+
+// A required file while its top-level code runs
+struct ModuleContext {
+    // Prefix its functions are published under: utils in utils.add
+    namespace: String,
+    scope: ScopeRef,
+    // Functions it defined, by their name inside the module, and whether they were marked with export
+    functions: Vec<(String, Rc<UserFunction>, bool)>,
+}
+
+// Limit on nested user function calls, prevents runaway recursion from overflowing the stack
+const MAX_CALL_DEPTH: usize = 200;
+
 // Define execution environment
 struct Env {
-    variables: HashMap<String, Value>,
-    loaded_packages: HashMap<String, bool>,
+    // Scope that var(x) = ... declares into at this point of execution; between statements of the
+    // main script or the interactive shell this is the global scope
+    scope: ScopeRef,
+    // Module search path and the packages loaded so far, shared with basic.setrequirepath
+    packages: Rc<RefCell<PackageManager>>,
     functions: HashMap<String, Function>,
+    user_functions: HashMap<String, Rc<UserFunction>>,
+    // Number of user function calls currently being executed
+    call_depth: usize,
+    // Name of the source file whose code is running, and the text of every file run so far
+    current_file: Rc<str>,
+    sources: HashMap<Rc<str>, String>,
+    // Compiled modules embedded in a bundled .lb file, by the name require uses for them
+    bundled_modules: HashMap<String, Rc<Program>>,
+    // Module whose top-level code is running, if any
+    module: Option<ModuleContext>,
+    // Functions each loaded module published, by module key, for requiring it again under another name
+    module_functions: HashMap<String, Vec<(String, Rc<UserFunction>)>>,
+    // Aliases of builtin libraries from require("basic") as b
+    library_aliases: HashMap<String, String>,
     debug_mode: bool,
 }
 
 impl Env {
     fn new(debug_mode: bool) -> Self {
-        let mut env = Self {
-            variables: HashMap::new(),
-            loaded_packages: HashMap::new(),
+        // Function registration is done in main function
+        Self {
+            scope: Scope::new_global(),
+            packages: Rc::new(RefCell::new(PackageManager::new())),
             functions: HashMap::new(),
+            user_functions: HashMap::new(),
+            call_depth: 0,
+            current_file: Rc::from("<input>"),
+            sources: HashMap::new(),
+            bundled_modules: HashMap::new(),
+            module: None,
+            module_functions: HashMap::new(),
+            library_aliases: HashMap::new(),
             debug_mode,
+        }
+    }
+    
+    // Parse and run the code of a script, module or shell line.
+    // `file` is the name used for the code in error messages.
+    fn parse_and_execute(&mut self, file: &str, code: &str) -> Result<(), ScriptError> {
+        let file: Rc<str> = Rc::from(file);
+        self.sources.insert(file.clone(), code.to_string());
+        
+        let previous_file = std::mem::replace(&mut self.current_file, file.clone());
+        let result = match parser::parse_program(code) {
+            Ok(program) => self.execute_block(&program).map(|_| ()),
+            Err(e) => Err(e.into()),
         };
-        
-        // Function registration is now done in main function
-        
-        env
+        self.current_file = previous_file;
+        result.map_err(|e| e.in_file(&file))
     }
     
-    fn parse_and_execute(&mut self, code: &str) -> Result<(), String> {
-        let lines: Vec<&str> = code.lines().collect();
-        let mut i = 0;
-        
-        while i < lines.len() {
-            let trimmed = lines[i].trim();
-            if trimmed.is_empty() || trimmed.starts_with("//") {
-                i += 1;
-                continue;
+    // Execute a single line typed into the interactive shell
+    fn execute_line(&mut self, line: &str) -> Result<(), ScriptError> {
+        self.parse_and_execute("<shell>", line)
+    }
+    
+    // Error report with the offending source line, when the source is known
+    fn format_error(&self, error: &ScriptError) -> String {
+        let source = error.file.as_ref().and_then(|file| self.sources.get(file));
+        error.render(source.map(String::as_str))
+    }
+    
+    fn execute_statement(&mut self, stmt: &Stmt) -> Result<Flow, ScriptError> {
+        if self.debug_mode {
+            println!("DEBUG: Executing statement at line {}", stmt.span.line);
+        }
+        self.execute_statement_kind(&stmt.kind).map_err(|e| e.at(stmt.span))
+    }
+    
+    fn execute_statement_kind(&mut self, kind: &StmtKind) -> Result<Flow, ScriptError> {
+        match kind {
+            StmtKind::Require { name, alias } => self.handle_require(name, alias.as_deref())?,
+            StmtKind::VarDef { name, value } => self.handle_variable_definition(name, value)?,
+            StmtKind::Assign { name, value } => self.handle_assignment(name, value)?,
+            StmtKind::IndexAssign { target, index, value } => {
+                let target = self.evaluate_expression(target)?;
+                let index = self.evaluate_expression(index)?;
+                let value = self.evaluate_expression(value)?;
+                assign_index(&target, &index, value)?;
             }
-            
-            // Special handling for if statements
-            if trimmed.starts_with("if(") {
-                // Special handling for multi-line if statements
-                let (result, new_index) = self.execute_if_statement(&lines[i..])?;
-                if let Err(e) = result {
-                    return Err(format!("Execution error: {}", e));
-                }
-                i += new_index;
-            } 
-            // Special handling for function definitions
-            else if trimmed.starts_with("func(") && trimmed.contains(" = {") {
-                // Handle function definitions directly in parse_and_execute to access i and lines variables
-                if let Err(e) = self.parse_function_definition(&lines, &mut i) {
-                    return Err(format!("Function definition error: {}", e));
-                }
+            StmtKind::FuncDef { name, params, body, exported } => {
+                self.parse_function_definition(name, params, FunctionBody::Ast(body.clone()), *exported);
             }
-            else {
-                // Process single-line statements
-                if let Err(e) = self.execute_line(trimmed) {
-                    return Err(format!("Execution error: {}", e));
-                }
-                i += 1;
+            StmtKind::If { condition, then_body, else_body } => {
+                return self.execute_if_statement(condition, then_body, else_body.as_deref());
+            }
+            StmtKind::While { condition, body } => {
+                return self.execute_while_statement(condition, body);
+            }
+            StmtKind::For { init, condition, step, body } => {
+                return self.execute_for_statement(init, condition, step, body);
+            }
+            StmtKind::ForEach { var, iterable, body } => {
+                return self.execute_foreach_statement(var, iterable, body);
+            }
+            StmtKind::Break => return Ok(Flow::Break),
+            StmtKind::Continue => return Ok(Flow::Continue),
+            StmtKind::Return(value) => {
+                let value = match value {
+                    Some(expr) => self.evaluate_expression(expr)?,
+                    None => Value::Null,
+                };
+                return Ok(Flow::Return(value));
+            }
+            StmtKind::Expr(expr) => {
+                self.evaluate_expression(expr)?;
             }
         }
-        
-        Ok(())
+        Ok(Flow::Normal)
     }
     
-    fn execute_line(&mut self, line: &str) -> Result<(), String> {
-        // Remove trailing semicolons and spaces
-        let line = line.trim_end_matches(';').trim();
-        
-        // Skip empty lines and comments
-        if line.is_empty() || line.starts_with("//") {
-            return Ok(());
-        }
-        
-        // Special handling for if statements (processed in a single execute_line call)
-        if line.starts_with("if(") {
-            // Convert single line to array for execute_if_statement call
-            let lines = [line];
-            let (result, _) = self.execute_if_statement(&lines)?;
-            return result;
-        }
-        
-        // Check if it's a require statement
-        if line.starts_with("require(") {
-            return self.handle_require(line);
-        }
-        
-        // Check if it's a variable definition
-        if line.starts_with("var(") && line.contains(" = ") {
-            return self.handle_variable_definition(line);
-        }
-        
-        // Check if it's a function call - support dot notation and simplified call methods
-        if (line.contains(".") && line.contains("(") && line.contains(")")) || 
-           (line.contains("(") && line.contains(")") && !line.starts_with("func(") && !line.starts_with("var(") && !line.starts_with("if(")) {
-            return self.handle_function_call(line);
+    // Execute statements in order, stopping early when one of them returns
+    fn execute_block(&mut self, block: &[Stmt]) -> Result<Flow, ScriptError> {
+        for stmt in block {
+            match self.execute_statement(stmt)? {
+                Flow::Normal => {}
+                flow => return Ok(flow),
+            }
         }
-        
-        // Function definitions are now handled in parse_and_execute, no need to handle here
-        
-        Ok(())
+        Ok(Flow::Normal)
     }
     
-    // Parse function definition
-    fn parse_function_definition(&mut self, lines: &[&str], i: &mut usize) -> Result<(), String> {
-        let line = lines[*i];
-        
-        // Parse function definition
-        let func_def = line.trim_start_matches("func(").trim_end_matches(" = {");
-        
-        // Parse function name and parameters
-        let func_name_end = func_def.find('(').ok_or("Function definition format error")?;
-        let func_name = &func_def[..func_name_end];
-        
-        // Parse parameters part
-        let args_part = &func_def[func_name_end + 1..];
-        // Find closing parenthesis position in parameters part
-        let args_end = args_part.find(')').ok_or("Function definition missing closing parenthesis")?;
-        let args_content = &args_part[..args_end];
-        
-        // Process parameters
-        let mut args = Vec::new();
-        if !args_content.trim().is_empty() {
-            // Simple parameter parsing
-            let parts: Vec<&str> = args_content.split(',').map(|s| s.trim()).collect();
-            for part in parts {
-                if part.starts_with("self(") && part.ends_with(")") {
-                    // Extract parameter name from self()
-                    let arg_name = part.trim_start_matches("self(").trim_end_matches(")");
-                    args.push(arg_name.to_string());
-                } else {
-                    // Ensure parameter name doesn't contain closing parenthesis
-                    let clean_arg = part.trim_end_matches(')');
-                    args.push(clean_arg.to_string());
-                }
-            }
+    // Execute the body of an if or loop in its own scope, so variables declared inside stay local to it
+    fn execute_scoped_block(&mut self, block: &[Stmt]) -> Result<Flow, ScriptError> {
+        // Blocks that declare nothing can share the enclosing scope, which keeps hot loops cheap
+        if !block.iter().any(|stmt| matches!(stmt.kind, StmtKind::VarDef { .. })) {
+            return self.execute_block(block);
         }
-        
+        let scope = Scope::new_child(&self.scope);
+        self.with_scope(scope, |env| env.execute_block(block))
+    }
+    
+    // Run `f` with `scope` as the current scope, restoring the previous one afterwards
+    fn with_scope<T>(&mut self, scope: ScopeRef, f: impl FnOnce(&mut Self) -> T) -> T {
+        let previous = std::mem::replace(&mut self.scope, scope);
+        let result = f(self);
+        self.scope = previous;
+        result
+    }
+    
+    // Register a function definition
+    fn parse_function_definition(&mut self, func_name: &str, params: &[String], body: FunctionBody, exported: bool) {
         // Add debug information
         if self.debug_mode {
-            println!("{}DEBUG: Registering function {} with parameters: {:?}{}", BLUE, func_name, args, RESET);
-        }
-        
-        // Save parameter name list
-        let args_clone = args.clone();
-        let func_name_clone = func_name.to_string();
-        let debug_mode_clone = self.debug_mode; // Copy debug_mode to closure
-        
-        // Extract function body - find from current line until matching closing brace
-        let mut func_body_lines = Vec::new();
-        let mut bracket_count = 1; // Found opening brace {
-        let mut j = *i + 1;
-        
-        while j < lines.len() && bracket_count > 0 {
-            let trimmed_line = lines[j].trim();
-            
-            // Count brackets
-            for c in trimmed_line.chars() {
-                if c == '{' {
-                    bracket_count += 1;
-                } else if c == '}' {
-                    bracket_count -= 1;
-                    if bracket_count == 0 {
-                        // Found closing bracket, not including this line
-                        break;
-                    }
-                }
-            }
-            
-            if bracket_count > 0 {
-                func_body_lines.push(trimmed_line);
-            }
-            j += 1;
+            println!("{}DEBUG: Registering function {} with parameters: {:?}{}",
+                     BLUE, func_name, params, RESET);
         }
         
-        // Update index to skip function body
-        *i = j; // j already points to line after function body end
-        
-        // Implement general function handling logic for our test cases
-        // Support function names with module prefixes (like utils.add, utils.multiply, utils.greet)
-        
-        // Implement corresponding functionality based on function name and parameters
-        if func_name.ends_with(".add") && args.len() == 2 {
-            // Handle addition function
-            self.functions.insert(func_name.to_string(), Box::new(move |call_args| {
-                if call_args.len() >= 2 {
-                    // Return sum of two parameters
-                    match (&call_args[0], &call_args[1]) {
-                        (Value::Int(a), Value::Int(b)) => {
-                            Ok(Value::Int(a + b))
-                        },
-                        (Value::Float(a), Value::Float(b)) => {
-                            Ok(Value::Float(a + b))
-                        },
-                        (Value::Int(a), Value::Float(b)) => {
-                            Ok(Value::Float(*a as f64 + *b))
-                        },
-                        (Value::Float(a), Value::Int(b)) => {
-                            Ok(Value::Float(*a + *b as f64))
-                        },
-                        _ => {
-                            Ok(Value::Null)
-                        },
-                    }
-                } else {
-                    Ok(Value::Null)
-                }
-            }));
-        } else if func_name.ends_with(".multiply") && args.len() == 2 {
-            // Handle multiplication function
-            self.functions.insert(func_name.to_string(), Box::new(move |call_args| {
-                if call_args.len() >= 2 {
-                    // Return product of two parameters
-                    match (&call_args[0], &call_args[1]) {
-                        (Value::Int(a), Value::Int(b)) => {
-                            Ok(Value::Int(a * b))
-                        },
-                        (Value::Float(a), Value::Float(b)) => {
-                            Ok(Value::Float(a * b))
-                        },
-                        (Value::Int(a), Value::Float(b)) => {
-                            Ok(Value::Float(*a as f64 * *b))
-                        },
-                        (Value::Float(a), Value::Int(b)) => {
-                            Ok(Value::Float(*a * *b as f64))
-                        },
-                        _ => {
-                            Ok(Value::Null)
-                        },
-                    }
-                } else {
-                    Ok(Value::Null)
-                }
-            }));
-        } else if func_name.ends_with(".greet") && args.len() == 1 {
-            // Handle greet function (string concatenation)
-            self.functions.insert(func_name.to_string(), Box::new(move |call_args| {
-                if let Some(Value::String(name)) = call_args.first() {
-                    // Return "Hello, {name}!"
-                    Ok(Value::String(format!("Hello, {}!", name)))
-                } else {
-                    Ok(Value::String("Hello!".to_string()))
-                }
-            }));
-        } else {
-            // Use default implementation for other functions
-            self.functions.insert(func_name.to_string(), Box::new(move |_| {
-                Ok(Value::Null)
-            }));
+        // Inside a module the function is a variable of the module scope, so the module's own
+        // code calls it by its plain name; it is published under the namespace when loading ends.
+        // An explicit prefix with the module's name, func(utils.add(...)) in utils.leon, is dropped.
+        if let Some(namespace) = self.module.as_ref().map(|module| module.namespace.clone()) {
+            let local_name = func_name.strip_prefix(&format!("{}.", namespace)).unwrap_or(func_name);
+            let function = Rc::new(self.new_user_function(&format!("{}.{}", namespace, local_name), params, body));
+            let module = self.module.as_mut().expect("checked above");
+            module.scope.borrow_mut().define(local_name, Value::Function(Callable::User(function.clone())));
+            module.functions.push((local_name.to_string(), function, exported));
+            return;
         }
         
-        Ok(())
+        let function = self.new_user_function(func_name, params, body);
+        self.user_functions.insert(func_name.to_string(), Rc::new(function));
     }
     
-    // Handle if condition statements
-    fn handle_if_condition(&mut self, _line: &str) -> Result<(), String> {
-        // This method is now just for interface compatibility, actual functionality is implemented in execute_if_statement
-        Ok(())
+    // Function that closes over the current scope
+    fn new_user_function(&self, name: &str, params: &[String], body: FunctionBody) -> UserFunction {
+        UserFunction {
+            name: name.to_string(),
+            params: params.to_vec(),
+            body,
+            closure: self.scope.clone(),
+            file: self.current_file.clone(),
+        }
     }
     
-    // Execute if statement (includes multi-line code blocks)
-    fn execute_if_statement(&mut self, lines: &[&str]) -> Result<(Result<(), String>, usize), String> {
-        let first_line = lines[0].trim();
-        if self.debug_mode {
-            println!("DEBUG: Executing if statement: {}", first_line);
-        }
-        
-        // Extract condition part
-        let cond_start = first_line.find('(').ok_or("If statement format error")? + 1;
-        let cond_end = first_line.find(')').ok_or("If statement format error")?;
-        let condition_str = &first_line[cond_start..cond_end];
-        
-        // Evaluate condition
-        let condition_result = self.evaluate_condition(condition_str)?;
-        if self.debug_mode {
-            println!("DEBUG: Condition result: {}", condition_result);
+    // Run a user function body with its parameters bound in a fresh scope inside the one it was defined in
+    fn call_user_function(&mut self, function: &UserFunction, args: Vec<Value>) -> Result<Value, ScriptError> {
+        if args.len() != function.params.len() {
+            return Err(format!("Function {} expects {} argument(s), got {}",
+                               function.name, function.params.len(), args.len()).into());
         }
-        
-        // Reimplement a cleaner, simpler version
-        let mut if_body = Vec::new();
-        let mut else_body = Vec::new();
-        let mut in_if = true;
-        let mut in_else = false;
-        let mut brace_count = 0;
-        let mut total_lines_to_skip = 0;
-        let mut found_else = false;
-        
-        // Process opening brace on first line
-        if first_line.contains('{') {
-            brace_count += 1;
+        if self.call_depth >= MAX_CALL_DEPTH {
+            return Err(format!("Maximum call depth ({}) exceeded in function {}", MAX_CALL_DEPTH, function.name).into());
         }
         
-        // Start processing from next line after if statement
-        let mut i = 1;
-        
-        while i < lines.len() {
-            let line = lines[i];
-            let trimmed = line.trim();
-            
-            // Check if it's a compressed else line
-            if (trimmed.starts_with("}") && trimmed.ends_with("{")) && 
-               (trimmed.contains(" else ") || trimmed.contains("\nelse")) {
-                // This is the end of if block and start of else block
-                in_if = false;
-                in_else = true;
-                found_else = true;
-                brace_count = 1; // Reset for else block opening brace
-                i += 1;
-                continue;
-            }
-            
-            // Check if it's a regular else line
-            if (trimmed == "else {" || trimmed == "else") && brace_count == 1 {
-                // This is the end of if block and start of else block
-                in_if = false;
-                in_else = true;
-                found_else = true;
-                // Reset brace count
-                brace_count = if trimmed.contains('{') {
-                    1
-                } else {
-                    0
-                };
-                i += 1;
-                continue;
-            }
-            
-            // Count braces
-            for c in trimmed.chars() {
-                if c == '{' {
-                    brace_count += 1;
-                } else if c == '}' {
-                    brace_count -= 1;
+        let scope = Scope::new_child(&function.closure);
+        // Compiled functions take their arguments in local slots
+        let args = match &function.body {
+            FunctionBody::Ast(_) => {
+                for (param, arg) in function.params.iter().zip(args) {
+                    scope.borrow_mut().define(param, arg);
                 }
+                Vec::new()
             }
-            
-            // Collect valid code lines
-            if in_if && !trimmed.is_empty() && trimmed != "{" && trimmed != "}" && !trimmed.starts_with("//") {
-                if_body.push(trimmed);
-            } else if in_else && !trimmed.is_empty() && trimmed != "{" && trimmed != "}" && !trimmed.starts_with("//") {
-                else_body.push(trimmed);
-            }
-            
-            // Check if reaching the end of the entire structure
-            if brace_count == 0 && (in_if || in_else) {
-                total_lines_to_skip = i + 1;
-                break;
-            }
-            
-            i += 1;
-        }
-        
-        // Ensure at least one line is skipped
-        if total_lines_to_skip == 0 {
-            total_lines_to_skip = i;
-        }
-        
+            FunctionBody::Compiled(..) => args,
+        };
+        self.call_depth += 1;
+        let previous_file = std::mem::replace(&mut self.current_file, function.file.clone());
+        let result = self.with_scope(scope, |env| match &function.body {
+            FunctionBody::Ast(body) => env.execute_block(body).map(|flow| match flow {
+                Flow::Return(value) => value,
+                _ => Value::Null,
+            }),
+            FunctionBody::Compiled(program, index) => env.run_compiled_function(program, *index, args),
+        });
+        self.current_file = previous_file;
+        self.call_depth -= 1;
+        
+        result.map_err(|e| e.in_file(&function.file))
+    }
+    
+    // Execute if statement
+    fn execute_if_statement(&mut self, condition: &Expr, then_body: &[Stmt], else_body: Option<&[Stmt]>) -> Result<Flow, ScriptError> {
+        // Evaluate condition
+        let condition_result = self.evaluate_condition(condition)?;
         if self.debug_mode {
-            println!("DEBUG: if-else structure skipped lines: {}, if block lines: {}, else block lines: {}, found else: {}", 
-                    total_lines_to_skip, if_body.len(), else_body.len(), found_else);
+            println!("DEBUG: Condition result: {}, if block statements: {}, else block statements: {}",
+                     condition_result, then_body.len(), else_body.map_or(0, |b| b.len()));
         }
         
         // Execute the corresponding code block
         if condition_result {
-            if self.debug_mode {
-                println!("DEBUG: Condition is true, executing if block code");
-            }
-            for code_line in &if_body {
-                if self.debug_mode {
-                    println!("DEBUG: Executing if code line: {}", code_line);
-                }
-                self.execute_line(code_line)?;
-            }
-        } else if found_else {
-            if self.debug_mode {
-                println!("DEBUG: Condition is false, executing else block code");
-            }
-            for code_line in &else_body {
-                if self.debug_mode {
-                    println!("DEBUG: Executing else code line: {}", code_line);
-                }
-                self.execute_line(code_line)?;
-            }
+            self.execute_scoped_block(then_body)
+        } else if let Some(else_body) = else_body {
+            self.execute_scoped_block(else_body)
+        } else {
+            Ok(Flow::Normal)
         }
-        
-        return Ok((Ok(()), total_lines_to_skip));
     }
     
-    // Evaluate condition expression
-    fn evaluate_condition(&mut self, condition: &str) -> Result<bool, String> {
-        let condition = condition.trim();
-        if self.debug_mode {
-            println!("DEBUG: Evaluating condition: {}", condition);
-        }
-        
-        // Check > comparison
-        if let Some(pos) = condition.find('>') {
-            let left = &condition[..pos].trim();
-            let right = &condition[pos+1..].trim();
-            
-            // Get left and right values
-            let left_val = self.get_value_or_constant(left)?;
-            let right_val = self.get_value_or_constant(right)?;
-            
-            if self.debug_mode {
-                println!("DEBUG: Left value type: {:?}, Right value type: {:?}", 
-                         match &left_val {
-                             Value::String(_) => "String",
-                             Value::Int(_) => "Int",
-                             Value::Float(_) => "Float",
-                             _ => "Other",
-                         },
-                         match &right_val {
-                             Value::String(_) => "String",
-                             Value::Int(_) => "Int",
-                             Value::Float(_) => "Float",
-                             _ => "Other",
-                         });
-                
-                // Print specific values
-                match (&left_val, &right_val) {
-                    (Value::Int(l), Value::Int(r)) => println!("DEBUG: Left value: {}, Right value: {}", l, r),
-                    (Value::Int(l), Value::Float(r)) => println!("DEBUG: Left value: {}, Right value: {}", l, r),
-                    (Value::Float(l), Value::Int(r)) => println!("DEBUG: Left value: {}, Right value: {}", l, r),
-                    (Value::Float(l), Value::Float(r)) => println!("DEBUG: Left value: {}, Right value: {}", l, r),
-                    _ => (),
-                }
-            }
-            
-            // Compare
-            let result = match (left_val, right_val) {
-                (Value::Int(left_num), Value::Int(right_num)) => left_num > right_num,
-                (Value::Int(left_num), Value::Float(right_num)) => (left_num as f64) > right_num,
-                (Value::Float(left_num), Value::Int(right_num)) => left_num > (right_num as f64),
-                (Value::Float(left_num), Value::Float(right_num)) => left_num > right_num,
-                _ => return Err("Comparison operations can only be used with numeric types".to_string()),
-            };
-            if self.debug_mode {
-                println!("DEBUG: Comparison result: {}", result);
-            }
-            Ok(result)
-        }
-        // Check < comparison
-        else if let Some(pos) = condition.find('<') {
-            let left = &condition[..pos].trim();
-            let right = &condition[pos+1..].trim();
-            
-            let left_val = self.get_value_or_constant(left)?;
-            let right_val = self.get_value_or_constant(right)?;
-            
-            match (left_val, right_val) {
-                (Value::Int(left_num), Value::Int(right_num)) => Ok(left_num < right_num),
-                (Value::Int(left_num), Value::Float(right_num)) => Ok((left_num as f64) < right_num),
-                (Value::Float(left_num), Value::Int(right_num)) => Ok(left_num < (right_num as f64)),
-                (Value::Float(left_num), Value::Float(right_num)) => Ok(left_num < right_num),
-                _ => Err("Comparison operations can only be used with numeric types".to_string()),
+    // Execute while loop, re-evaluating the condition before every iteration
+    fn execute_while_statement(&mut self, condition: &Expr, body: &[Stmt]) -> Result<Flow, ScriptError> {
+        let mut iterations: u64 = 0;
+        while self.evaluate_condition(condition)? {
+            iterations += 1;
+            match self.execute_scoped_block(body)? {
+                Flow::Normal | Flow::Continue => {}
+                Flow::Break => break,
+                flow => return Ok(flow),
             }
         }
-        // Check == comparison
-        else if let Some(pos) = condition.find("==") {
-            let left = &condition[..pos].trim();
-            let right = &condition[pos+2..].trim();
-            
-            let left_val = self.get_value_or_constant(left)?;
-            let right_val = self.get_value_or_constant(right)?;
-            
-            match (left_val, right_val) {
-                (Value::String(s1), Value::String(s2)) => Ok(s1 == s2),
-                (Value::Int(i1), Value::Int(i2)) => Ok(i1 == i2),
-                (Value::Float(f1), Value::Float(f2)) => Ok(f1 == f2),
-                (Value::Int(i), Value::Float(f)) => Ok((i as f64) == f),
-                (Value::Float(f), Value::Int(i)) => Ok(f == (i as f64)),
-                _ => Ok(false),
-            }
+        
+        if self.debug_mode {
+            println!("DEBUG: while loop finished after {} iteration(s)", iterations);
         }
-        // Check != comparison
-        else if let Some(pos) = condition.find("!=") {
-            let left = &condition[..pos].trim();
-            let right = &condition[pos+2..].trim();
-            
-            let left_val = self.get_value_or_constant(left)?;
-            let right_val = self.get_value_or_constant(right)?;
-            
-            match (left_val, right_val) {
-                (Value::String(s1), Value::String(s2)) => Ok(s1 != s2),
-                (Value::Int(i1), Value::Int(i2)) => Ok(i1 != i2),
-                (Value::Float(f1), Value::Float(f2)) => Ok(f1 != f2),
-                (Value::Int(i), Value::Float(f)) => Ok((i as f64) != f),
-                (Value::Float(f), Value::Int(i)) => Ok(f != (i as f64)),
-                _ => Ok(true),
+        Ok(Flow::Normal)
+    }
+    
+    // Execute counted for loop: for(init; condition; step) { ... }
+    // A variable declared by init belongs to the loop and is gone after it.
+    fn execute_for_statement(&mut self, init: &Stmt, condition: &Expr, step: &Stmt, body: &[Stmt]) -> Result<Flow, ScriptError> {
+        let loop_scope = Scope::new_child(&self.scope);
+        self.with_scope(loop_scope, |env| {
+            env.execute_statement(init)?;
+            while env.evaluate_condition(condition)? {
+                match env.execute_scoped_block(body)? {
+                    Flow::Normal | Flow::Continue => {}
+                    Flow::Break => break,
+                    flow => return Ok(flow),
+                }
+                env.execute_statement(step)?;
             }
-        }
-        else {
-            Err("Unsupported condition operator".to_string())
-        }
+            Ok(Flow::Normal)
+        })
     }
     
-    // Get value or constant, supporting variable references
-    fn get_value_or_constant(&mut self, value_str: &str) -> Result<Value, String> {
-        // If it's a variable reference (like "var(a)")
-        if value_str.starts_with("var(") && value_str.ends_with(")") {
-            let var_name = &value_str[4..value_str.len()-1];
-            if let Some(val) = self.variables.get(var_name) {
-                return Ok(match val {
-                    Value::String(s) => Value::String(s.clone()),
-                    Value::Int(i) => Value::Int(*i),
-                    Value::Float(f) => Value::Float(*f),
-                    Value::Null => Value::Null,
-                    _ => return Err("Unsupported variable type for condition comparison".to_string()),
-                });
-            } else {
-                return Err(format!("Undefined variable: {}", var_name));
+    // Execute for(var(x) in ...) loop over a range, the characters of a string, the items of a list or the keys of a map
+    fn execute_foreach_statement(&mut self, var_name: &str, iterable: &ForIterable, body: &[Stmt]) -> Result<Flow, ScriptError> {
+        let items = match iterable {
+            ForIterable::Range { start, end, step } => {
+                let start = self.evaluate_range_bound(start)?;
+                let end = self.evaluate_range_bound(end)?;
+                let step = match step {
+                    Some(step) => self.evaluate_range_bound(step)?,
+                    None => 1,
+                };
+                iterate_range(start, end, step)?
             }
-        }
-        
-        // If it's a direct variable name reference (like "a")
-        if !value_str.contains(":") && !value_str.contains(".") && !value_str.contains("(") {
-            if let Some(val) = self.variables.get(value_str) {
-                return Ok(match val {
-                    Value::String(s) => Value::String(s.clone()),
-                    Value::Int(i) => Value::Int(*i),
-                    Value::Float(f) => Value::Float(*f),
-                    Value::Null => Value::Null,
-                    _ => return Err("Unsupported variable type for condition comparison".to_string()),
-                });
+            ForIterable::Value(expr) => {
+                let value = self.evaluate_expression(expr)?;
+                iterate_value(value)?
             }
-        }
+        };
         
-        // Try to parse as integer constant
-        if let Ok(num) = value_str.parse::<i64>() {
-            return Ok(Value::Int(num));
+        // Every iteration gets a fresh scope holding the loop variable
+        for item in items {
+            let scope = Scope::new_child(&self.scope);
+            scope.borrow_mut().define(var_name, item);
+            match self.with_scope(scope, |env| env.execute_block(body))? {
+                Flow::Normal | Flow::Continue => {}
+                Flow::Break => break,
+                flow => return Ok(flow),
+            }
         }
-        
-        // Try to parse as float constant
-        if let Ok(num) = value_str.parse::<f64>() {
-            return Ok(Value::Float(num));
+        Ok(Flow::Normal)
+    }
+    
+    fn evaluate_range_bound(&mut self, expr: &Expr) -> Result<i64, ScriptError> {
+        match self.evaluate_expression(expr)? {
+            Value::Int(i) => Ok(i),
+            other => Err(format!("range() arguments must be integers, got {}", other.type_name()).into()),
         }
+    }
+    
+    // Evaluate condition of if/while/for, which must produce a bool
+    fn evaluate_condition(&mut self, condition: &Expr) -> Result<bool, ScriptError> {
+        let result = condition_value(self.evaluate_expression(condition)?)?;
         
-        // Otherwise try to parse as other value
-        if value_str.starts_with("int:") {
-            let num_str = value_str.trim_start_matches("int:");
-            if let Ok(num) = num_str.parse::<i64>() {
-                return Ok(Value::Int(num));
-            }
-        } else if value_str.starts_with("float:") {
-            let num_str = value_str.trim_start_matches("float:");
-            if let Ok(num) = num_str.parse::<f64>() {
-                return Ok(Value::Float(num));
-            }
-        } else if value_str.starts_with("string:") {
-            let content_part = value_str.trim_start_matches("string:");
-            // 正确处理字符串字面量，只移除最外层的引号
-            let content = if content_part.starts_with('"') && content_part.ends_with('"') {
-                &content_part[1..content_part.len()-1]
-            } else {
-                content_part
-            };
-            return Ok(Value::String(content.to_string()));
+        if self.debug_mode {
+            println!("DEBUG: Condition result: {}", result);
         }
-        
-        Err(format!("Cannot parse value: {}", value_str))
+        Ok(result)
     }
     
-    fn handle_require(&mut self, line: &str) -> Result<(), String> {
-        // Remove trailing semicolon
-        let line_without_semicolon = line.trim_end_matches(';');
-        
-        // Simple parsing of require statement
-        let start = line_without_semicolon.find('"').ok_or("Require statement format error")? + 1;
-        let end = line_without_semicolon.rfind('"').ok_or("Require statement format error")?;
-        let lib_name = &line_without_semicolon[start..end];
-        
-        // Mark package as loaded
-        self.loaded_packages.insert(lib_name.to_string(), true);
+    // require("name") or require("name") as alias
+    fn handle_require(&mut self, lib_name: &str, alias: Option<&str>) -> Result<(), ScriptError> {
+        let namespace = module_namespace(lib_name, alias);
         
         // Check if it's a built-in library
         match lib_name {
@@ -742,688 +610,786 @@ impl Env {
                 }));
             }
             
-            "basic" => {
-                // Basic module functions are already registered at startup
+            name if package::is_builtin(name) => {
+                // These modules are already registered at startup
+            }
+            
+            _ if self.bundled_modules.contains_key(lib_name) => {
+                // Modules embedded in a bundled .lb file come before files on disk
+                let program = self.bundled_modules[lib_name].clone();
+                let key = program.source_name.clone();
+                self.load_module(&key, lib_name, &namespace, |env| {
+                    if env.debug_mode {
+                        println!("DEBUG: Loading bundled module: {}", lib_name);
+                    }
+                    env.execute_program(program)
+                })?;
             }
             
             _ => {
-                // Try to load external file module
-                // First try direct path and test directory path
-                let file_paths = [
-                    format!("{}.leon", lib_name),
-                    format!("lib/{}.leon", lib_name),
-                    format!("{}/index.leon", lib_name),
-                    format!("test/{}.leon", lib_name),  // Add test directory path
-                ];
-                
-                let mut found = false;
-                for path in &file_paths {
-                    if std::path::Path::new(path).exists() {
-                        if self.debug_mode {
-                            println!("DEBUG: Found and loading external module: {}", path);
-                        }
-                        
-                        // Read file content
-                        match std::fs::read_to_string(path) {
-                            Ok(content) => {
-                                if self.debug_mode {
-                                    println!("DEBUG: Successfully read file content, length: {} characters", content.len());
-                                }
-                                // Execute the loaded code to register its defined functions
-                                if let Err(e) = self.parse_and_execute(&content) {
-                                    println!("DEBUG: Error executing external module: {}", e);
-                                } else {
-                                    found = true;
-                                    if self.debug_mode {
-                                        println!("DEBUG: Successfully executed external module code");
-                                    }
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                println!("DEBUG: Failed to read external module: {}, error: {}", path, e);
-                            }
-                        }
-                    } else if self.debug_mode {
-                        println!("DEBUG: Module file does not exist: {}", path);
+                // Load an external file module from the package search path
+                let path = self.packages.borrow().find_package(lib_name)?;
+                let key = package::package_key(&path);
+                self.load_module(&key, lib_name, &namespace, |env| {
+                    let content = env.packages.borrow().load_package(&path)?;
+                    let path = path.display().to_string();
+                    if env.debug_mode {
+                        println!("DEBUG: Found and loading external module: {} ({} characters)", path, content.len());
                     }
+                    // Errors inside the module are reported at their location in the module file
+                    env.parse_and_execute(&path, &content)
+                })?;
+            }
+        }
+        
+        // require("basic") as b: b.print(...) calls basic.print(...)
+        if let Some(alias) = alias.filter(|_| package::is_builtin(lib_name)) {
+            if self.module.is_some() {
+                // Inside a module the alias names are variables of the module, like its other requires
+                let prefix = format!("{}.", lib_name);
+                let names: Vec<String> = self.functions.keys().map(String::as_str)
+                    .chain(CALLBACK_BUILTINS)
+                    .filter_map(|name| name.strip_prefix(&prefix).map(str::to_string))
+                    .collect();
+                for name in names {
+                    let callable = Callable::Builtin(Rc::from(format!("{}{}", prefix, name)));
+                    self.publish_function(format!("{}.{}", alias, name), callable);
                 }
-                
-                if !found && self.debug_mode {
-                    println!("DEBUG: Module not found: {}", lib_name);
-                }
+            } else {
+                self.library_aliases.insert(alias.to_string(), lib_name.to_string());
             }
         }
         
         Ok(())
     }
     
-
+    // Make a required function callable as name. Requires in the main script publish into the
+    // global function table; requires inside a module define variables of the module's scope,
+    // so they are only visible to the module's own code.
+    fn publish_function(&mut self, name: String, function: Callable) {
+        match (&self.module, function) {
+            (Some(module), function) => module.scope.borrow_mut().define(&name, Value::Function(function)),
+            (None, Callable::User(function)) => {
+                self.user_functions.insert(name, function);
+            }
+            (None, Callable::Builtin(_)) => {}
+        }
+    }
     
-    fn handle_variable_definition(&mut self, line: &str) -> Result<(), String> {
-        let parts: Vec<&str> = line.split(" = ").collect();
-        if parts.len() != 2 {
-            return Err("Variable definition format error".to_string());
+    // Run a module's top-level code in its own root scope, which cannot see the variables of the
+    // code that required it, then publish its functions as namespace.name.
+    // When the module marks any function with export, only the exported ones are published.
+    // The code runs once per module key; requiring the module again only publishes its functions
+    // under the new namespace.
+    fn load_module(&mut self, key: &str, lib_name: &str, namespace: &str,
+                   run: impl FnOnce(&mut Self) -> Result<(), ScriptError>) -> Result<(), ScriptError> {
+        if self.packages.borrow().is_package_loaded(key) {
+            if self.debug_mode {
+                println!("DEBUG: Module already loaded: {}", lib_name);
+            }
+            let functions = self.module_functions.get(key).cloned().unwrap_or_default();
+            for (name, function) in functions {
+                self.publish_function(format!("{}.{}", namespace, name), Callable::User(function));
+            }
+            return Ok(());
         }
         
-        let var_name_part = parts[0];
-        let var_name = var_name_part.trim_start_matches("var(").trim_end_matches(")");
-        
-        let value_part = parts[1].trim_end_matches(';');
-        
-        // Check if it's a function call
-        let value = if value_part.contains(".") && value_part.contains("(") && value_part.contains(")") {
-            // Call function and get return value
-            self.execute_function_call(value_part)?
-        } else {
-            // Parse normal value
-            self.parse_value(value_part)?
-        };
-        
-        self.variables.insert(var_name.to_string(), value);
+        self.packages.borrow_mut().begin_loading(key, lib_name)?;
+        let scope = Scope::new_global();
+        let context = ModuleContext { namespace: namespace.to_string(), scope: scope.clone(), functions: Vec::new() };
+        let outer = self.module.replace(context);
+        let result = self.with_scope(scope, run);
+        let module = std::mem::replace(&mut self.module, outer).expect("module context is set while loading");
+        self.packages.borrow_mut().finish_loading(result.is_ok());
+        result?;
+        
+        let has_exports = module.functions.iter().any(|(_, _, exported)| *exported);
+        let mut published = Vec::new();
+        for (name, function, exported) in module.functions {
+            if exported || !has_exports {
+                if self.debug_mode {
+                    println!("DEBUG: Module function available as {}", function.name);
+                }
+                self.publish_function(format!("{}.{}", namespace, name), Callable::User(function.clone()));
+                published.push((name, function));
+            }
+        }
+        self.module_functions.insert(key.to_string(), published);
         Ok(())
     }
     
-    fn handle_function_call(&mut self, function_call: &str) -> Result<(), String> {
-        // Remove semicolon
-        let func_call = function_call.trim_end_matches(';');
-        
-        // Check if it's a custom function call (starts with func()
-        if func_call.starts_with("func(") {
-            // For custom function calls, extract function name and parameters
-            let call_content = func_call.trim_start_matches("func(").trim_end_matches(")");
-            
-            if let Some(func_name_end) = call_content.find('(') {
-                let func_name = &call_content[..func_name_end];
-                let args_str = &call_content[func_name_end + 1..call_content.len() - 1];
-                
-                // Parse parameters
-                let mut args = Vec::new();
-                if !args_str.trim().is_empty() {
-                    let arg_parts: Vec<&str> = args_str.split(',').map(|s| s.trim()).collect();
-                    for arg in arg_parts {
-                        let value = self.evaluate_expression(arg)?;
-                        args.push(value);
-                    }
-                }
-                
-                // Call function
-                if let Some(function) = self.functions.get(func_name) {
-                    let _ = function(args)?;
-                } else {
-                    return Err(format!("Function undefined: {}", func_name));
-                }
-            }
-            
-            return Ok(());
-        }
-        
-        // For other types of function calls, use original logic
-        let _ = self.execute_function_call(func_call)?;
-        
+    fn handle_variable_definition(&mut self, var_name: &str, value_expr: &Expr) -> Result<(), ScriptError> {
+        let value = self.evaluate_expression(value_expr)?;
+        self.define_variable(var_name, value);
         Ok(())
     }
     
-    fn execute_function_call(&mut self, function_call: &str) -> Result<Value, String> {
-        // Parse function name and parameters - Use character indices for multi-byte characters
-        let func_name_end_char = function_call.chars().position(|c| c == '(').ok_or("Function call format error")?;
-        // Convert character index to byte index
-        let func_name_end = function_call.chars().take(func_name_end_char).collect::<String>().len();
-        let func_name = &function_call[..func_name_end];
-        
-        // Find matching closing parenthesis - Using character iteration
-        let mut bracket_count = 1;
-        let mut args_end_char = func_name_end_char + 1;
-        let chars: Vec<char> = function_call.chars().collect();
+    // Declare a variable in the current scope
+    fn define_variable(&mut self, var_name: &str, value: Value) {
+        self.scope.borrow_mut().define(var_name, value);
+    }
+    
+    // Reassign an existing variable: name = value;
+    // The nearest enclosing declaration is updated, even if it is outside the current function.
+    fn handle_assignment(&mut self, var_name: &str, value_expr: &Expr) -> Result<(), ScriptError> {
+        let value = self.evaluate_expression(value_expr)?;
+        self.assign_variable(var_name, value)
+    }
+    
+    fn assign_variable(&mut self, var_name: &str, value: Value) -> Result<(), ScriptError> {
+        self.scope.borrow_mut().assign(var_name, value)
+            .map_err(|_| format!("Undefined variable: {} (use var({}) = ... to define it)", var_name, var_name).into())
+    }
+    
+    fn execute_function_call(&mut self, func_name: &str, args: Vec<Value>) -> Result<Value, ScriptError> {
+        if self.debug_mode {
+            println!("DEBUG: Calling function {} with {} argument(s)", func_name, args.len());
+        }
         
-        while args_end_char < chars.len() {
-            match chars[args_end_char] {
-                '(' => bracket_count += 1,
-                ')' => bracket_count -= 1,
-                _ => (),
-            }
-            if bracket_count == 0 {
-                break;
-            }
-            args_end_char += 1;
+        // A variable holding a function wins, so a parameter can shadow a global function
+        let variable = self.scope.borrow().with_value(func_name, |value| match value {
+            Value::Function(function) => Some(function.clone()),
+            _ => None,
+        });
+        if let Some(function) = variable.flatten() {
+            return self.call_value(&function, args);
         }
-        if bracket_count != 0 {
-            return Err("Function call format error: parentheses not matching".to_string());
+        self.call_named_function(func_name, args)
+    }
+    
+    // Call a function defined with func(...) or a builtin, by name
+    fn call_named_function(&mut self, func_name: &str, args: Vec<Value>) -> Result<Value, ScriptError> {
+        if let Some(function) = self.user_functions.get(func_name).cloned() {
+            return self.call_user_function(&function, args);
         }
-        // Convert character index to byte index
-        let args_end = function_call.chars().take(args_end_char).collect::<String>().len();
         
-        // Check if it's a simplified function call (without func() prefix)
-        let actual_func_name = if !func_name.contains('.') && !func_name.starts_with("basic.") && !func_name.starts_with("request.") {
-            // Use function name directly without prefix
-            func_name
-        } else {
-            func_name
-        };
+        let func_name = self.library_function_name(func_name);
+        match func_name.as_ref() {
+            "list.map" => return self.list_map(args),
+            "list.filter" => return self.list_filter(args),
+            _ => {}
+        }
         
-        // Check if function exists
-        if let Some(func) = self.functions.get(actual_func_name) {
-            // Parse arguments
-            let args_str = &function_call[func_name_end + 1..args_end];
-            let args = if !args_str.trim().is_empty() {
-                // Special handling for basic.input function
-                if actual_func_name == "basic.input" {
-                    // Extract content inside quotes
-                    if args_str.contains('"') {
-                        let start = args_str.find('"').ok_or("Parameter format error")? + 1;
-                        let end = args_str.rfind('"').ok_or("Parameter format error")?;
-                        let content = &args_str[start..end];
-                        vec![Value::String(content.to_string())]
-                    } else {
-                        vec![Value::String(args_str.trim().to_string())]
-                    }
-                } else {
-                    // Process parameters for other functions, supporting self() format
-                    // Better parameter parsing that handles commas inside strings
-                    let mut args_vec = Vec::new();
-                    let mut current_arg = String::new();
-                    let mut in_string = false;
-                    let mut escape_next = false;
-                    
-                    for c in args_str.chars() {
-                        if escape_next {
-                            current_arg.push(c);
-                            escape_next = false;
-                        } else if c == '\\' {
-                            escape_next = true;
-                            current_arg.push(c);
-                        } else if c == '"' {
-                            in_string = !in_string;
-                            current_arg.push(c);
-                        } else if c == ',' && !in_string {
-                            // End of current argument
-                            let clean_part = current_arg.split(';').next().unwrap_or("").trim();
-                            if !clean_part.is_empty() {
-                                // Check if it's in self() format
-                                let value = if clean_part.starts_with("self(") && clean_part.ends_with(")") {
-                                    // Extract parameter content from self()
-                                    let inner_content = clean_part.trim_start_matches("self(").trim_end_matches(")");
-                                    self.parse_value(inner_content)?
-                                } else {
-                                    self.parse_value(clean_part)?
-                                };
-                                args_vec.push(value);
-                            }
-                            current_arg.clear();
-                        } else {
-                            current_arg.push(c);
-                        }
-                    }
-                    
-                    // Add the last argument
-                    let clean_part = current_arg.split(';').next().unwrap_or("").trim();
-                    if !clean_part.is_empty() {
-                        let value = if clean_part.starts_with("self(") && clean_part.ends_with(")") {
-                            let inner_content = clean_part.trim_start_matches("self(").trim_end_matches(")");
-                            self.parse_value(inner_content)?
-                        } else {
-                            self.parse_value(clean_part)?
-                        };
-                        args_vec.push(value);
-                    }
-                    args_vec
-                }
-            } else {
-                Vec::new()
-            };
-            
-            // Call function
-            func(args)
-        } else {
-            Err(format!("Undefined function: {}", actual_func_name))
+        match self.functions.get(func_name.as_ref()) {
+            Some(func) => Ok(func(args)?),
+            None => Err(format!("Undefined function: {}", func_name).into()),
         }
     }
     
-    fn parse_value(&self, value_str: &str) -> Result<Value, String> {
-        // First check if it contains string concatenation expression
-        if value_str.contains(" + ") {
-            return self.evaluate_expression(value_str);
+    fn call_value(&mut self, function: &Callable, args: Vec<Value>) -> Result<Value, ScriptError> {
+        match function {
+            Callable::User(function) => self.call_user_function(function, args),
+            Callable::Builtin(name) => self.call_named_function(name, args),
         }
-        
-        // Process variable reference
-        if value_str.starts_with("var(") {
-            let var_name = value_str.trim_start_matches("var(").trim_end_matches(")");
-            if let Some(val) = self.variables.get(var_name) {
-                return match val {
-                    Value::String(s) => Ok(Value::String(s.clone())),
-                    Value::Int(i) => Ok(Value::Int(*i)),
-                    Value::Float(f) => Ok(Value::Float(*f)),
-                    Value::Null => Ok(Value::Null),
-                    Value::File(_) => Err("Cannot directly reference file handle".to_string()),
-                };
-            } else {
-                return Err(format!("Undefined variable: {}", var_name));
+    }
+    
+    // Function value for a name that is not a variable, if a function with that name exists
+    fn named_function(&self, name: &str) -> Option<Value> {
+        if let Some(function) = self.user_functions.get(name) {
+            return Some(Value::Function(Callable::User(function.clone())));
+        }
+        let name = self.library_function_name(name);
+        if self.functions.contains_key(name.as_ref()) || CALLBACK_BUILTINS.contains(&name.as_ref()) {
+            return Some(Value::Function(Callable::Builtin(Rc::from(name.as_ref()))));
+        }
+        None
+    }
+    
+    // Full name of a builtin called through a library alias: b.print is basic.print after require("basic") as b
+    fn library_function_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let library = name.split_once('.').and_then(|(alias, function)| {
+            self.library_aliases.get(alias).map(|library| format!("{}.{}", library, function))
+        });
+        library.map_or(Cow::Borrowed(name), Cow::Owned)
+    }
+    
+    // list.map(xs, f): new list with f applied to every item
+    fn list_map(&mut self, args: Vec<Value>) -> Result<Value, ScriptError> {
+        let (items, function) = callback_args(args, "list.map")?;
+        let mut mapped = Vec::with_capacity(items.len());
+        for item in items {
+            mapped.push(self.call_value(&function, vec![item])?);
+        }
+        Ok(Value::new_list(mapped))
+    }
+    
+    // list.filter(xs, f): new list with the items for which f returns true
+    fn list_filter(&mut self, args: Vec<Value>) -> Result<Value, ScriptError> {
+        let (items, function) = callback_args(args, "list.filter")?;
+        let mut kept = Vec::new();
+        for item in items {
+            match self.call_value(&function, vec![item.clone()])? {
+                Value::Bool(true) => kept.push(item),
+                Value::Bool(false) => {}
+                other => return Err(format!("list.filter callback must return a bool, got {}", other.type_name()).into()),
             }
         }
-        
-        // Process string
-        if value_str.starts_with("string:") {
-            let content_part = value_str.trim_start_matches("string:");
-            
-            // Check if it's in string:var(variable_name) format
-            if content_part.starts_with("var(") {
-                // Recursively call parse_value_without_expression to get variable value
-                let var_value = self.parse_value_without_expression(content_part)?;
-                // Convert the obtained value to string
-                return match var_value {
-                    Value::String(s) => Ok(Value::String(s.clone())),
-                    Value::Int(i) => Ok(Value::String(i.to_string())),
-                    Value::Float(f) => Ok(Value::String(f.to_string())),
-                    Value::Null => Ok(Value::String("null".to_string())),
-                    Value::File(_) => Ok(Value::String("[file handle]".to_string())),
-                };
+        Ok(Value::new_list(kept))
+    }
+    
+    fn evaluate_expression(&mut self, expr: &Expr) -> Result<Value, ScriptError> {
+        self.evaluate_expression_kind(&expr.kind).map_err(|e| e.at(expr.span))
+    }
+    
+    fn evaluate_expression_kind(&mut self, kind: &ExprKind) -> Result<Value, ScriptError> {
+        match kind {
+            ExprKind::Str(s) => Ok(Value::String(s.clone())),
+            ExprKind::Int(i) => Ok(Value::Int(*i)),
+            ExprKind::Float(f) => Ok(Value::Float(*f)),
+            ExprKind::Bool(b) => Ok(Value::Bool(*b)),
+            ExprKind::List(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(self.evaluate_expression(item)?);
+                }
+                Ok(Value::new_list(values))
             }
-            
-            // Normal string literal, supporting escape characters
-            let content = if content_part.starts_with('"') && content_part.ends_with('"') {
-                &content_part[1..content_part.len()-1]
-            } else {
-                content_part
-            };
-            // Handle escape characters
-            let mut result = String::new();
-            let mut chars = content.chars().peekable();
-            
-            while let Some(c) = chars.next() {
-                if c == '\\' {
-                    if let Some(next_char) = chars.next() {
-                        // Handle escape characters
-                        match next_char {
-                            'n' => result.push('\n'),  // Newline
-                            't' => result.push('\t'),  // Tab
-                            'r' => result.push('\r'),  // Carriage return
-                            '"' => result.push('"'),   // Double quote
-                            '\\' => result.push('\\'), // Backslash
-                            _ => {
-                                // Special handling for \\+ to display as +
-                                if next_char == '+' {
-                                    result.push('+');
-                                } else {
-                                    // Keep other escape sequences as is
-                                    result.push('\\');
-                                    result.push(next_char);
-                                }
-                            }
-                        }
-                    } else {
-                        // Single backslash at the end, add directly
-                        result.push('\\');
-                    }
-                } else {
-                    // Normal characters added directly
-                    result.push(c);
+            ExprKind::Map(entries) => {
+                let mut map = IndexMap::with_capacity(entries.len());
+                for (key, value) in entries {
+                    let key = match self.evaluate_expression(key)? {
+                        Value::String(key) => key,
+                        other => return Err(format!("Map key must be a string, got {}", other.type_name()).into()),
+                    };
+                    let value = self.evaluate_expression(value)?;
+                    map.insert(key, value);
                 }
+                Ok(Value::new_map(map))
             }
-            
-            return Ok(Value::String(result));
-        }
-        
-        // Process integer
-        if value_str.starts_with("int:") {
-            let num_str = value_str.trim_start_matches("int:");
-            if let Ok(num) = num_str.parse::<i64>() {
-                return Ok(Value::Int(num));
+            ExprKind::Index { target, index } => {
+                let target = self.evaluate_expression(target)?;
+                let index = self.evaluate_expression(index)?;
+                Ok(index_value(&target, &index)?)
             }
-        }
-        
-        // Process float
-        if value_str.starts_with("float:") {
-            let num_str = value_str.trim_start_matches("float:");
-            if let Ok(num) = num_str.parse::<f64>() {
-                return Ok(Value::Float(num));
+            ExprKind::Var(name) => self.get_variable(name),
+            ExprKind::Cast { ty, expr } => {
+                let value = self.evaluate_expression(expr)?;
+                Ok(cast_value(*ty, value)?)
+            }
+            ExprKind::Unary { op, expr } => {
+                let value = self.evaluate_expression(expr)?;
+                Ok(apply_unary(*op, value)?)
+            }
+            ExprKind::Binary { op: op @ (BinaryOp::And | BinaryOp::Or), left, right } => {
+                // Short-circuit: the right side is only evaluated when it decides the result
+                let left = self.evaluate_logical_operand(*op, left)?;
+                if left == (*op == BinaryOp::Or) {
+                    return Ok(Value::Bool(left));
+                }
+                self.evaluate_logical_operand(*op, right).map(Value::Bool)
+            }
+            ExprKind::Binary { op, left, right } if op.is_comparison() => {
+                let left = self.evaluate_expression(left)?;
+                let right = self.evaluate_expression(right)?;
+                Ok(Value::Bool(compare_values(*op, &left, &right)?))
+            }
+            ExprKind::Binary { op, left, right } => {
+                let left = self.evaluate_expression(left)?;
+                let right = self.evaluate_expression(right)?;
+                Ok(apply_arithmetic(*op, left, right)?)
+            }
+            ExprKind::Call { name, args } => {
+                let arg_values = self.evaluate_arguments(args)?;
+                self.execute_function_call(name, arg_values)
+            }
+            ExprKind::CallValue { callee, args } => {
+                let function = match self.evaluate_expression(callee)? {
+                    Value::Function(function) => function,
+                    other => return Err(format!("Cannot call a {} value", other.type_name()).into()),
+                };
+                let arg_values = self.evaluate_arguments(args)?;
+                self.call_value(&function, arg_values)
+            }
+            ExprKind::Lambda { params, body } => {
+                let function = self.new_user_function(LAMBDA_NAME, params, FunctionBody::Ast(body.clone()));
+                Ok(Value::Function(Callable::User(Rc::new(function))))
             }
         }
-        
-        // Default to string processing
-        Ok(Value::String(value_str.to_string()))
     }
     
-    fn evaluate_expression(&self, expr: &str) -> Result<Value, String> {
-        // Process string concatenation expression
-        let parts: Vec<&str> = expr.split(" + ").collect();
-        let mut result = String::new();
-        
-        for part in parts {
-            // Recursively parse each part's value
-            let value = self.parse_value_without_expression(part.trim())?;
-            
-            // Convert all values to strings and concatenate
-                match value {
-                    Value::String(s) => result.push_str(&s),
-                    Value::Int(i) => result.push_str(&i.to_string()),
-                    Value::Float(f) => result.push_str(&f.to_string()),
-                    Value::Null => result.push_str("null"),
-                    Value::File(_) => result.push_str("[file handle]"),
-                }
+    fn evaluate_arguments(&mut self, args: &[Expr]) -> Result<Vec<Value>, ScriptError> {
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.evaluate_expression(arg)?);
         }
-        
-        Ok(Value::String(result))
+        Ok(values)
     }
     
-    // Evaluate expression with parameter mapping
-      fn evaluate_expression_with_params(&self, expression: &str, param_map: &std::collections::HashMap<String, Value>) -> Result<Value, String> {
-          // Remove whitespace from both ends of the expression
-          let expression = expression.trim();
-          
-          // Process type conversion expression
-          if expression.contains(':') {
-              let parts: Vec<&str> = expression.split(':').collect();
-              if parts.len() == 2 {
-                  let type_part = parts[0].trim();
-                  let value_part = parts[1].trim();
-                  
-                  // First try to parse value part, support self() parameter reference
-                   let value = if value_part.starts_with("self(") && value_part.ends_with(")") {
-                       let param_name = value_part[5..value_part.len()-1].trim();
-                       if let Some(param_value) = param_map.get(param_name) {
-                           param_value.clone()
-                       } else {
-                           return Err(format!("Parameter {} undefined", param_name));
-                       }
-                   } else {
-                       // Try to parse as normal value
-                       self.parse_value_without_expression(value_part)?
-                   };
-                  
-                  // Then perform type conversion
-                  match type_part {
-                      "int" => match value {
-                          Value::Int(val) => Ok(Value::Int(val)),
-                          Value::Float(val) => Ok(Value::Int(val as i64)),
-                          Value::String(val) => {
-                              if let Ok(int_val) = val.parse::<i64>() {
-                                  Ok(Value::Int(int_val))
-                              } else {
-                                  Err(format!("Failed to convert string '{}' to integer", val))
-                              }
-                          },
-                          _ => Err(format!("Failed to convert {:?} to integer", value)),
-                      },
-                      "float" => match value {
-                          Value::Int(val) => Ok(Value::Float(val as f64)),
-                          Value::Float(val) => Ok(Value::Float(val)),
-                          Value::String(val) => {
-                              if let Ok(float_val) = val.parse::<f64>() {
-                                  Ok(Value::Float(float_val))
-                              } else {
-                                  Err(format!("Failed to convert string '{}' to float", val))
-                              }
-                          },
-                          _ => Err(format!("Failed to convert {:?} to float", value)),
-                      },
-                      "string" => match value {
-                          Value::Int(val) => Ok(Value::String(val.to_string())),
-                          Value::Float(val) => Ok(Value::String(val.to_string())),
-                          Value::String(val) => Ok(Value::String(val)),
-                          _ => Err(format!("Failed to convert {:?} to string", value)),
-                      },
-                      _ => Err(format!("Unsupported type conversion: {}", type_part)),
-                  }
-              } else {
-                  Err(format!("Invalid type conversion expression: {}", expression))
-              }
-          } else {
-              // Process literals
-              if expression.starts_with('"') && expression.ends_with('"') {
-                  // String type
-                  let content = expression[1..expression.len()-1].to_string();
-                  return Ok(Value::String(content));
-              } else if expression == "null" || expression == "Null" {
-                  // Null type
-                  return Ok(Value::Null);
-              } else if let Ok(int_val) = expression.parse::<i64>() {
-                  // Integer type
-                  return Ok(Value::Int(int_val));
-              } else if let Ok(float_val) = expression.parse::<f64>() {
-                  // Float type
-                  return Ok(Value::Float(float_val));
-              }
-              
-              // Process variable reference
-              if expression.starts_with("var(") && expression.ends_with(")") {
-                  let var_name = expression[4..expression.len()-1].trim();
-                  if let Some(value) = self.variables.get(var_name) {
-                      return Ok(value.clone());
-                  } else {
-                      return Err(format!("Variable {} undefined", var_name));
-                  }
-              }
-              
-              // Process self() parameter reference
-              if expression.starts_with("self(") && expression.ends_with(")") {
-                  let param_name = expression[5..expression.len()-1].trim();
-                  if let Some(value) = param_map.get(param_name) {
-                      return Ok(value.clone());
-                  } else {
-                      return Err(format!("Parameter {} undefined", param_name));
-                  }
-              }
-              
-              // Return error if expression doesn't match any known pattern
-              Err(format!("Unrecognized expression: {}", expression))
-          }
-      }
+    fn evaluate_logical_operand(&mut self, op: BinaryOp, expr: &Expr) -> Result<bool, ScriptError> {
+        match self.evaluate_expression(expr)? {
+            Value::Bool(b) => Ok(b),
+            other => Err(format!("Operator '{}' requires bool operands, got {}", op, other.type_name()).into()),
+        }
+    }
     
-    // Version without expression parsing to avoid recursion
-    fn parse_value_without_expression(&self, value_str: &str) -> Result<Value, String> {
-        // Process variable reference
-        if value_str.starts_with("var(") {
-            let var_name = value_str.trim_start_matches("var(").trim_end_matches(")");
-            if let Some(val) = self.variables.get(var_name) {
-                return match val {
-                    Value::String(s) => Ok(Value::String(s.clone())),
-                    Value::Int(i) => Ok(Value::Int(*i)),
-                    Value::Float(f) => Ok(Value::Float(*f)),
-                    Value::Null => Ok(Value::Null),
-                    Value::File(_) => Err("Cannot directly reference file handle".to_string()),
-                };
-            } else {
-                return Err(format!("Undefined variable: {}", var_name));
-            }
+    fn get_variable(&self, var_name: &str) -> Result<Value, ScriptError> {
+        let value = self.scope.borrow().with_value(var_name, Value::clone);
+        match value {
+            Some(value) => Ok(value),
+            None => self.named_function(var_name)
+                .ok_or_else(|| format!("Undefined variable: {}", var_name).into()),
         }
-        
-        // Process string
-        if value_str.starts_with("string:") {
-            let content_part = value_str.trim_start_matches("string:");
-            
-            // Check if it's in string:var(variable_name) format
-            if content_part.starts_with("var(") {
-                // Get variable name
-                let var_name = content_part.trim_start_matches("var(").trim_end_matches(")");
-                if let Some(val) = self.variables.get(var_name) {
-                    // Convert variable value to string
-                    return match val {
-                        Value::String(s) => Ok(Value::String(s.clone())),
-                        Value::Int(i) => Ok(Value::String(i.to_string())),
-                        Value::Float(f) => Ok(Value::String(f.to_string())),
-                        Value::Null => Ok(Value::String("null".to_string())),
-                        Value::File(_) => Ok(Value::String("[file handle]".to_string())),
-                    };
-                } else {
-                    return Err(format!("Undefined variable: {}", var_name));
-                }
-            }
-            
-            // Normal string literal, supporting escape characters
-            println!("DEBUG: Original content_part: {:?}", content_part);
-            let trimmed = if content_part.starts_with('"') && content_part.ends_with('"') {
-                println!("DEBUG: Trimming quotes, content_part length: {}", content_part.len());
-                let trimmed_str = &content_part[1..content_part.len()-1];
-                println!("DEBUG: After trimming: {:?}", trimmed_str);
-                trimmed_str
-            } else {
-                println!("DEBUG: Not trimming, using as-is");
-                content_part
-            };
-            let mut result = String::new();
-            let mut chars = trimmed.chars().peekable();
-            
-            while let Some(c) = chars.next() {
-                if c == '\\' {
-                    if let Some(next_char) = chars.next() {
-                        match next_char {
-                            'n' => result.push('\n'),
-                            't' => result.push('\t'),
-                            'r' => result.push('\r'),
-                            '"' => result.push('"'),
-                            '\\' => result.push('\\'),
-                            _ => {
-                                // Special handling for \\+ to display as +
-                                if next_char == '+' {
-                                    result.push('+');
-                                } else {
-                                    result.push('\\');
-                                    result.push(next_char);
-                                }
-                            }
-                        }
-                    } else {
-                        // Single backslash at the end, add directly
-                        result.push('\\');
-                    }
-                } else {
-                    result.push(c);
-                }
+    }
+}
+
+// Split the (list, function) parameters of list.map and list.filter
+fn callback_args(args: Vec<Value>, func_name: &str) -> Result<(Vec<Value>, Callable), String> {
+    let mut args = args.into_iter();
+    let items = match args.next() {
+        Some(Value::List(items)) => items.borrow().clone(),
+        _ => return Err(format!("{} requires a list as its first parameter", func_name)),
+    };
+    match args.next() {
+        Some(Value::Function(function)) => Ok((items, function)),
+        _ => Err(format!("{} requires a function as its second parameter", func_name)),
+    }
+}
+
+// Items of range(start, end, step); end is exclusive
+fn iterate_range(start: i64, end: i64, step: i64) -> Result<Box<dyn Iterator<Item = Value>>, String> {
+    if step == 0 {
+        return Err("range() step cannot be zero".to_string());
+    }
+    let mut current = start;
+    Ok(Box::new(std::iter::from_fn(move || {
+        let in_range = if step > 0 { current < end } else { current > end };
+        if !in_range {
+            return None;
+        }
+        let value = current;
+        // Stop instead of overflowing at the edge of the integer range
+        current = current.checked_add(step).unwrap_or(end);
+        Some(Value::Int(value))
+    })))
+}
+
+// Items a foreach loop visits: the characters of a string, the items of a list or the keys of a map
+fn iterate_value(value: Value) -> Result<Box<dyn Iterator<Item = Value>>, String> {
+    match value {
+        Value::String(s) => {
+            let chars: Vec<Value> = s.chars().map(|c| Value::String(c.to_string())).collect();
+            Ok(Box::new(chars.into_iter()))
+        }
+        // Iterate over a snapshot, so changing the list inside the loop is safe
+        Value::List(items) => Ok(Box::new(items.borrow().clone().into_iter())),
+        // Maps give their keys in insertion order
+        Value::Map(entries) => {
+            let keys: Vec<Value> = entries.borrow().keys().map(|key| Value::String(key.clone())).collect();
+            Ok(Box::new(keys.into_iter()))
+        }
+        other => Err(format!("Cannot iterate over {}", other.type_name())),
+    }
+}
+
+// Value of an if/while/for condition, which must be a bool
+fn condition_value(value: Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        other => Err(format!("Condition must be a bool, got {}", other.type_name())),
+    }
+}
+
+fn apply_unary(op: UnaryOp, value: Value) -> Result<Value, String> {
+    match (op, value) {
+        (UnaryOp::Neg, Value::Int(i)) => i.checked_neg()
+            .map(Value::Int)
+            .ok_or_else(|| "Integer overflow in negation".to_string()),
+        (UnaryOp::Neg, Value::Float(f)) => Ok(Value::Float(-f)),
+        (UnaryOp::Neg, other) => Err(format!("Cannot negate a {} value", other.type_name())),
+        (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+        (UnaryOp::Not, other) => Err(format!("Operator '!' requires a bool, got {}", other.type_name())),
+    }
+}
+
+// Turn a possibly negative index into a position in a collection of `len` items.
+// -1 is the last item; None when the index is out of range.
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let position = if index < 0 { len as i64 + index } else { index };
+    if position >= 0 && (position as usize) < len {
+        Some(position as usize)
+    } else {
+        None
+    }
+}
+
+fn list_position(index: &Value, len: usize) -> Result<usize, String> {
+    match index {
+        Value::Int(i) => resolve_index(*i, len)
+            .ok_or_else(|| format!("List index {} out of range for list of length {}", i, len)),
+        other => Err(format!("List index must be an int, got {}", other.type_name())),
+    }
+}
+
+fn map_key(key: &Value) -> Result<&str, String> {
+    match key {
+        Value::String(key) => Ok(key),
+        other => Err(format!("Map key must be a string, got {}", other.type_name())),
+    }
+}
+
+// Read target[index]
+fn index_value(target: &Value, index: &Value) -> Result<Value, String> {
+    match target {
+        Value::List(items) => {
+            let items = items.borrow();
+            let position = list_position(index, items.len())?;
+            Ok(items[position].clone())
+        }
+        Value::Map(entries) => {
+            let key = map_key(index)?;
+            entries.borrow().get(key).cloned()
+                .ok_or_else(|| format!("Key {:?} not found in map", key))
+        }
+        other => Err(format!("Cannot index into {}", other.type_name())),
+    }
+}
+
+// Write target[index] = value
+fn assign_index(target: &Value, index: &Value, value: Value) -> Result<(), String> {
+    match target {
+        Value::List(items) => {
+            let mut items = items.borrow_mut();
+            let position = list_position(index, items.len())?;
+            items[position] = value;
+            Ok(())
+        }
+        // Assigning to a missing key adds it at the end
+        Value::Map(entries) => {
+            let key = map_key(index)?;
+            entries.borrow_mut().insert(key.to_string(), value);
+            Ok(())
+        }
+        other => Err(format!("Cannot assign to an index of {}", other.type_name())),
+    }
+}
+
+// Equality used by == and !=; ints and floats compare by numeric value
+fn values_equal(left: &Value, right: &Value) -> bool {
+    collections_equal(left, right, &mut Vec::new())
+}
+
+// `comparing` holds the pairs of collections being compared around these values. Meeting a pair
+// again means the collections contain themselves in the same way, and they count as equal.
+fn collections_equal(left: &Value, right: &Value, comparing: &mut Vec<(*const (), *const ())>) -> bool {
+    match (left, right) {
+        (Value::String(l), Value::String(r)) => l == r,
+        (Value::Int(l), Value::Int(r)) => l == r,
+        (Value::Float(l), Value::Float(r)) => l == r,
+        (Value::Int(i), Value::Float(f)) | (Value::Float(f), Value::Int(i)) => (*i as f64) == *f,
+        (Value::Bool(l), Value::Bool(r)) => l == r,
+        (Value::List(l), Value::List(r)) => {
+            let pair = (Rc::as_ptr(l) as *const (), Rc::as_ptr(r) as *const ());
+            Rc::ptr_eq(l, r) || comparing.contains(&pair) || {
+                let (l, r) = (l.borrow(), r.borrow());
+                comparing.push(pair);
+                let equal = l.len() == r.len() && l.iter().zip(r.iter()).all(|(a, b)| collections_equal(a, b, comparing));
+                comparing.pop();
+                equal
             }
-            
-            return Ok(Value::String(result));
         }
-        
-        // Process integer
-        if value_str.starts_with("int:") {
-            let num_str = value_str.trim_start_matches("int:");
-            if let Ok(num) = num_str.parse::<i64>() {
-                return Ok(Value::Int(num));
+        (Value::Map(l), Value::Map(r)) => {
+            let pair = (Rc::as_ptr(l) as *const (), Rc::as_ptr(r) as *const ());
+            Rc::ptr_eq(l, r) || comparing.contains(&pair) || {
+                let (l, r) = (l.borrow(), r.borrow());
+                comparing.push(pair);
+                let equal = l.len() == r.len()
+                    && l.iter().all(|(key, value)| r.get(key).is_some_and(|other| collections_equal(value, other, comparing)));
+                comparing.pop();
+                equal
             }
         }
-        
-        // Process float
-        if value_str.starts_with("float:") {
-            let num_str = value_str.trim_start_matches("float:");
-            if let Ok(num) = num_str.parse::<f64>() {
-                return Ok(Value::Float(num));
+        (Value::Function(Callable::User(l)), Value::Function(Callable::User(r))) => Rc::ptr_eq(l, r),
+        (Value::Function(Callable::Builtin(l)), Value::Function(Callable::Builtin(r))) => l == r,
+        (Value::File(l), Value::File(r)) => Rc::ptr_eq(l, r),
+        (Value::Null, Value::Null) => true,
+        _ => false,
+    }
+}
+
+// Evaluate == != < > <= >=; ordering works on numbers and on strings
+fn compare_values(op: BinaryOp, left: &Value, right: &Value) -> Result<bool, String> {
+    let ordering = match (left, right) {
+        _ if op == BinaryOp::Eq => return Ok(values_equal(left, right)),
+        _ if op == BinaryOp::NotEq => return Ok(!values_equal(left, right)),
+        (Value::Int(l), Value::Int(r)) => l.partial_cmp(r),
+        (Value::Int(l), Value::Float(r)) => (*l as f64).partial_cmp(r),
+        (Value::Float(l), Value::Int(r)) => l.partial_cmp(&(*r as f64)),
+        (Value::Float(l), Value::Float(r)) => l.partial_cmp(r),
+        (Value::String(l), Value::String(r)) => l.partial_cmp(r),
+        _ => return Err(format!("Operator '{}' cannot compare {} with {}",
+                                op, left.type_name(), right.type_name())),
+    };
+    
+    // NaN is neither smaller nor larger than anything
+    let Some(ordering) = ordering else { return Ok(false) };
+    Ok(match op {
+        BinaryOp::Lt => ordering.is_lt(),
+        BinaryOp::Gt => ordering.is_gt(),
+        BinaryOp::LtEq => ordering.is_le(),
+        BinaryOp::GtEq => ordering.is_ge(),
+        _ => unreachable!("only ordering operators reach here"),
+    })
+}
+
+// Evaluate + - * / % on two values.
+// + concatenates when either side is a string; everything else requires numbers.
+// Int op Int stays Int (division truncates), any Float operand makes the result Float.
+fn apply_arithmetic(op: BinaryOp, left: Value, right: Value) -> Result<Value, String> {
+    if op == BinaryOp::Add && (matches!(left, Value::String(_)) || matches!(right, Value::String(_))) {
+        return Ok(Value::String(left.to_display_string() + &right.to_display_string()));
+    }
+    
+    match (left, right) {
+        (Value::Int(l), Value::Int(r)) => {
+            if matches!(op, BinaryOp::Div | BinaryOp::Mod) && r == 0 {
+                return Err("Division by zero".to_string());
             }
+            let result = match op {
+                BinaryOp::Add => l.checked_add(r),
+                BinaryOp::Sub => l.checked_sub(r),
+                BinaryOp::Mul => l.checked_mul(r),
+                BinaryOp::Div => l.checked_div(r),
+                BinaryOp::Mod => l.checked_rem(r),
+                _ => unreachable!("comparison operators are not arithmetic"),
+            };
+            result.map(Value::Int)
+                .ok_or_else(|| format!("Integer overflow in {} {} {}", l, op, r))
         }
-        
-        // Default to string processing
-        Ok(Value::String(value_str.to_string()))
+        (Value::Int(l), Value::Float(r)) => float_arithmetic(op, l as f64, r),
+        (Value::Float(l), Value::Int(r)) => float_arithmetic(op, l, r as f64),
+        (Value::Float(l), Value::Float(r)) => float_arithmetic(op, l, r),
+        (left, right) => Err(format!("Operator '{}' cannot be used with {} and {}",
+                                     op, left.type_name(), right.type_name())),
     }
 }
 
+fn float_arithmetic(op: BinaryOp, l: f64, r: f64) -> Result<Value, String> {
+    if matches!(op, BinaryOp::Div | BinaryOp::Mod) && r == 0.0 {
+        return Err("Division by zero".to_string());
+    }
+    let result = match op {
+        BinaryOp::Add => l + r,
+        BinaryOp::Sub => l - r,
+        BinaryOp::Mul => l * r,
+        BinaryOp::Div => l / r,
+        BinaryOp::Mod => l % r,
+        _ => unreachable!("comparison operators are not arithmetic"),
+    };
+    Ok(Value::Float(result))
+}
+
+// Convert a value for a typed prefix such as int:var(a) or string:var(b)
+fn cast_value(ty: CastType, value: Value) -> Result<Value, String> {
+    match ty {
+        CastType::String => Ok(Value::String(value.to_display_string())),
+        CastType::Int => match value {
+            Value::Int(val) => Ok(Value::Int(val)),
+            Value::Bool(val) => Ok(Value::Int(val as i64)),
+            Value::Float(val) => Ok(Value::Int(val as i64)),
+            Value::String(val) => val.trim().parse::<i64>()
+                .map(Value::Int)
+                .map_err(|_| format!("Failed to convert string '{}' to integer", val)),
+            other => Err(format!("Failed to convert {} to integer", other.to_display_string())),
+        },
+        CastType::Float => match value {
+            Value::Int(val) => Ok(Value::Float(val as f64)),
+            Value::Float(val) => Ok(Value::Float(val)),
+            Value::String(val) => val.trim().parse::<f64>()
+                .map(Value::Float)
+                .map_err(|_| format!("Failed to convert string '{}' to float", val)),
+            other => Err(format!("Failed to convert {} to float", other.to_display_string())),
+        },
+        CastType::Bool => match value {
+            Value::Bool(val) => Ok(Value::Bool(val)),
+            Value::Int(val) => Ok(Value::Bool(val != 0)),
+            Value::Float(val) => Ok(Value::Bool(val != 0.0)),
+            Value::String(val) => match val.trim() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                _ => Err(format!("Failed to convert string '{}' to bool", val)),
+            },
+            other => Err(format!("Failed to convert {} to bool", other.to_display_string())),
+        },
+    }
+}
+
+
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let program_name = &args[0];
+    let cli = Cli::parse();
     
     // Check version parameter
-    if args.len() > 1 && (args[1] == "--version" || args[1] == "--ver") {
+    if cli.version {
         println!("LeonBasic Interpreter v{}", version::VERSION);
         return;
     }
     
-    // Check if shell mode is enabled
-    if args.len() == 2 && args[1] == "--shell" {
-        start_shell();
-        return;
-    }
-    
-    // Check if build mode is enabled
-    if args.len() == 3 && args[1] == "--build" {
-        let source_path = &args[2];
-        // Generate output path with .lb extension
-        let output_path = if source_path.ends_with(".leon") {
-            source_path.replace(".leon", ".lb")
-        } else {
-            format!("{}.lb", source_path)
-        };
+    match cli.command {
+        Some(Command::Init { dir }) => match package::init_project(&dir) {
+            Ok(created) => {
+                for path in created {
+                    println!("Created {}", path.display());
+                }
+            }
+            Err(e) => {
+                eprintln!("{}Failed to create project: {}{}", RED, e, RESET);
+                std::process::exit(1);
+            }
+        },
         
-        println!("Compiling {} to {}", source_path, output_path);
+        Some(Command::Run { options }) => {
+            // The project of the current directory, found by its leon.toml
+            let project = match Project::find(Path::new(".")) {
+                Ok(project) => project,
+                Err(e) => {
+                    eprintln!("{}{}{}", RED, e, RESET);
+                    std::process::exit(1);
+                }
+            };
+            // Shown relative to the current directory in messages
+            let entry = project.entry();
+            let entry = std::env::current_dir().and_then(std::fs::canonicalize).ok()
+                .and_then(|cwd| entry.strip_prefix(cwd).ok().map(Path::to_path_buf))
+                .unwrap_or(entry);
+            run_file(&entry.display().to_string(), &options, Some(&project));
+        }
         
-        match build::compile_to_bytecode(source_path, &output_path) {
-            Ok(_) => println!("Compilation successful!"),
-            Err(e) => eprintln!("{}Compilation failed: {}{}", RED, e, RESET)
+        Some(Command::Install { source }) => {
+            let result = package::user_store()
+                .ok_or_else(|| "cannot find the home directory".to_string())
+                .and_then(|store| store.install(&source).map(|installed| (store.root().join(&installed.name).join(&installed.version), installed)));
+            match result {
+                Ok((dir, installed)) => println!("Installed {} {} to {}", installed.name, installed.version, dir.display()),
+                Err(e) => {
+                    eprintln!("{}Failed to install {}: {}{}", RED, source.display(), e, RESET);
+                    std::process::exit(1);
+                }
+            }
         }
-        return;
-    }
-    
-    // Check if setpath mode is enabled
-    if args.len() == 2 && args[1] == "--setpath" {
-        if let Err(e) = add_to_path() {
-            eprintln!("{}Failed to add to PATH: {}{}", RED, e, RESET);
+        
+        Some(Command::Build { file: source_path, bundle, key }) => {
+            // Generate output path with .lb extension
+            let output_path = if source_path.ends_with(".leon") {
+                source_path.replace(".leon", ".lb")
+            } else {
+                format!("{}.lb", source_path)
+            };
+            
+            println!("Compiling {} to {}", source_path, output_path);
+            
+            match build::compile_to_bytecode(&source_path, &output_path, key.as_deref(), bundle) {
+                Ok(modules) => {
+                    for module in modules {
+                        println!("Bundled module {}", module);
+                    }
+                    println!("Compilation successful!");
+                }
+                Err(e) => {
+                    eprintln!("{}Compilation failed: {}{}", RED, e, RESET);
+                    std::process::exit(1);
+                }
+            }
         }
-        return;
+        
+        Some(Command::Disasm { file, key }) => match build::disassemble_file(&file, key.as_deref()) {
+            Ok(listing) => print!("{}", listing),
+            Err(e) => {
+                eprintln!("{}Disassembly failed: {}{}", RED, e, RESET);
+                std::process::exit(1);
+            }
+        },
+        
+        Some(Command::Shell) => start_shell(),
+        
+        // --dry-run only shows the change
+        Some(Command::Setpath { dry_run }) => update_path(setpath::add_to_path(dry_run)),
+        Some(Command::Unsetpath { dry_run }) => update_path(setpath::remove_from_path(dry_run)),
+        
+        // Normal file execution mode
+        None => match cli.file {
+            Some(file_path) => run_file(&file_path, &cli.run, None),
+            None => {
+                let _ = Cli::command().print_help();
+                std::process::exit(1);
+            }
+        },
     }
-    
-    // Normal file execution mode
-    let mut debug_mode = false;
-    let mut file_path = None;
-    
-    // Parse arguments
-    for arg in &args[1..] {
-        if arg == "--debug" {
-            debug_mode = true;
-        } else if file_path.is_none() {
-            // First non --debug argument is the file path
-            file_path = Some(arg);
-        } else {
-            // Unexpected argument
-            print_usage(program_name);
-            return;
-        }
+}
+
+fn update_path(result: Result<(), String>) {
+    if let Err(e) = result {
+        eprintln!("{}Failed to update PATH: {}{}", RED, e, RESET);
+        std::process::exit(1);
     }
-    
-    // Check if file path is provided
-    let file_path = match file_path {
-        Some(path) => path,
-        None => {
-            print_usage(program_name);
-            return;
-        }
-    };
-    
+}
+
+// Execute a script or bytecode file; a project adds its module paths and must have its dependencies
+fn run_file(file_path: &str, options: &RunOptions, project: Option<&Project>) {
     if !Path::new(file_path).exists() {
         eprintln!("File not found: {}", file_path);
-        return;
+        std::process::exit(1);
     }
     
-    let mut env = Env::new(debug_mode);
+    let mut env = Env::new(options.debug);
+    // Modules are searched next to the script first
+    env.packages.borrow_mut().set_script_dir(file_path);
+    if let Some(project) = project {
+        env.packages.borrow_mut().set_project_paths(project.module_paths());
+        for (name, version) in &project.manifest.dependencies {
+            if let Err(e) = env.packages.borrow().check_dependency(name, version) {
+                eprintln!("{}Missing dependency {} {} of {}: {}{}", RED, name, version, project.manifest.package.name, e, RESET);
+                std::process::exit(1);
+            }
+        }
+    }
     
     // Register built-in functions
     builtins::register_basic_functions(&mut env);
     builtins::register_request_functions(&mut env);
     builtins::register_time_functions(&mut env);
     builtins::register_color_functions(&mut env);
+    builtins::register_list_functions(&mut env);
+    builtins::register_map_functions(&mut env);
+    builtins::register_process_functions(&mut env);
     
     // Check if the file is a bytecode file
-    if build::is_bytecode_file(file_path) {
+    let result = if build::is_bytecode_file(file_path) {
         println!("Executing bytecode file: {}", file_path);
-        match build::read_from_bytecode(file_path) {
-            Ok(content) => {
-                if let Err(e) = env.parse_and_execute(&content) {
-                    eprintln!("{}{}{}", RED, e, RESET);
+        match build::read_from_bytecode(file_path, options.key.as_deref()) {
+            // Version 1 files only wrap the source text
+            Ok(build::Bytecode::Source(content)) => env.parse_and_execute(file_path, &content),
+            Ok(build::Bytecode::Compiled(bundle)) => {
+                for module in bundle.modules {
+                    env.bundled_modules.insert(module.name, Rc::new(module.program));
                 }
-            },
-            Err(e) => eprintln!("{}Failed to execute bytecode file: {}{}", RED, e, RESET)
+                env.execute_program(Rc::new(bundle.main))
+            }
+            Err(e) => {
+                eprintln!("{}Failed to execute bytecode file: {}{}", RED, e, RESET);
+                std::process::exit(1);
+            }
         }
     } else {
         // Regular .leon file execution
-        match File::open(file_path) {
-            Ok(mut file) => {
-                let mut content = String::new();
-                if let Err(e) = file.read_to_string(&mut content) {
-                    eprintln!("{}Failed to read file: {}{}", RED, e, RESET);
-                } else if let Err(e) = env.parse_and_execute(&content) {
-                    eprintln!("{}{}{}", RED, e, RESET);
-                }
-            },
-            Err(e) => eprintln!("{}Failed to open file: {}{}", RED, e, RESET)
+        let mut content = String::new();
+        if let Err(e) = File::open(file_path).and_then(|mut file| file.read_to_string(&mut content)) {
+            eprintln!("{}Failed to read file: {}{}", RED, e, RESET);
+            std::process::exit(1);
         }
+        env.parse_and_execute(file_path, &content)
+    };
+    
+    if let Err(e) = result {
+        eprintln!("{}{}{}", RED, env.format_error(&e), RESET);
+        std::process::exit(1);
     }
+}
 
+// Name a module's functions are published under: the alias, or the last part of the module name
+fn module_namespace(lib_name: &str, alias: Option<&str>) -> String {
+    match alias {
+        Some(alias) => alias.to_string(),
+        None => lib_name.rsplit(['/', '\\']).next().unwrap_or(lib_name).to_string(),
+    }
 }
 
 // Start interactive shell
@@ -1436,10 +1402,10 @@ fn start_shell() {
     let mut env = Env::new(false); // Debug mode disabled by default in shell mode
     
     // Load basic and time libraries by default
-    if let Err(e) = env.handle_require("require(\"basic\")") {
+    if let Err(e) = env.handle_require("basic", None) {
         println!("{}Warning: Failed to load basic library: {}{}", YELLOW, e, RESET);
     }
-    if let Err(e) = env.handle_require("require(\"time\")") {
+    if let Err(e) = env.handle_require("time", None) {
         println!("{}Warning: Failed to load time library: {}{}", YELLOW, e, RESET);
     }
     
@@ -1465,9 +1431,9 @@ fn start_shell() {
             println!("  {}help/h       {}- Show this help message", CYAN, RESET);
             println!("  {}clear        {}- Clear the screen", CYAN, RESET);
             println!("{}Basic syntax examples:{}", GREEN, RESET);
-            println!("  {}{}{}  # Define variable", MAGENTA, "var(a) = string:\"Hello\";  ", RESET);
-            println!("  {}{}{}  # Print variable", MAGENTA, "basic.print(var(a));        ", RESET);
-            println!("  {}{}{}  # Load library", MAGENTA, "require(\"basic\");          ", RESET);
+            println!("  {}var(a) = string:\"Hello\";  {}  # Define variable", MAGENTA, RESET);
+            println!("  {}basic.print(var(a));        {}  # Print variable", MAGENTA, RESET);
+            println!("  {}require(\"basic\");          {}  # Load library", MAGENTA, RESET);
             continue;
         } else if line == "clear" {
             #[cfg(target_os = "windows")]
@@ -1480,7 +1446,7 @@ fn start_shell() {
         // Execute LeonBasic code
         if !line.is_empty() && !line.starts_with("//") {
             if let Err(e) = env.execute_line(line) {
-                println!("{}Error: {}{}", RED, e, RESET);
+                println!("{}{}{}", RED, env.format_error(&e), RESET);
             }
         }
     }
@@ -1489,4 +1455,313 @@ fn start_shell() {
 // Format value as string
 // Note: format_value function is now defined in the builtins module
 
-// Note: register_basic_functions function is now defined in the builtins module
\ No newline at end of file
+// Note: register_basic_functions function is now defined in the builtins module
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run source as the script `file`, with the builtin libraries registered like run_file does
+    fn run_as(file: &str, source: &str) -> (Env, Result<(), ScriptError>) {
+        let mut env = Env::new(false);
+        env.packages.borrow_mut().set_script_dir(file);
+        builtins::register_basic_functions(&mut env);
+        builtins::register_list_functions(&mut env);
+        builtins::register_map_functions(&mut env);
+        let result = env.parse_and_execute(file, source);
+        (env, result)
+    }
+
+    fn run(source: &str) -> Result<Env, ScriptError> {
+        let (env, result) = run_as("test.leon", source);
+        result.map(|_| env)
+    }
+
+    fn global(env: &Env, name: &str) -> String {
+        env.scope.borrow().with_value(name, Value::to_display_string).unwrap()
+    }
+
+    fn assert_error(source: &str, expected: &str) {
+        let error = run(source).err().unwrap().to_string();
+        assert!(error.contains(expected), "{}: {}", source, error);
+    }
+
+    #[test]
+    fn test_list_errors() {
+        assert_error("var(xs) = [int:1]\nvar(y) = var(xs)[int:1]", "List index 1 out of range for list of length 1");
+        assert_error("var(xs) = [int:1]\nvar(xs)[int:-2] = int:0", "List index -2 out of range for list of length 1");
+        assert_error("var(xs) = [int:1]\nvar(y) = var(xs)[\"a\"]", "List index must be an int, got string");
+        assert_error("list.remove([int:1], int:3)", "list.remove index 3 out of range for list of length 1");
+        assert_error("list.push(\"abc\", int:1)", "list.push requires a list parameter, got string");
+        assert_error("list.remove([int:1], \"0\")", "list.remove requires an integer index, got string");
+        assert_error("list.sort([int:1, \"a\"])", "list.sort cannot compare");
+        assert_error("list.join([int:1], int:2)", "list.join separator must be a string, got int");
+    }
+
+    #[test]
+    fn test_map_errors() {
+        assert_error("var(m) = {\"a\": int:1}\nvar(y) = var(m)[int:1]", "Map key must be a string, got int");
+        assert_error("var(m) = {\"a\": int:1}\nvar(m)[true] = int:2", "Map key must be a string, got bool");
+        assert_error("map.keys([int:1])", "map.keys requires a map parameter, got list");
+        assert_error("map.has({\"a\": int:1}, int:1)", "map.has requires a string key, got int");
+    }
+
+    #[test]
+    fn test_functions_as_values() {
+        let env = run(r#"
+            var(len) = list.len
+            var(n) = len([int:1, int:2, int:3])
+            var(lengths) = list.map([[int:1], [int:1, int:2]], list.len)
+            func(make_adder(n)) = { return func(x) { return x + n } }
+            var(add5) = make_adder(int:5)
+            var(sum) = add5(int:1)
+        "#).unwrap();
+        assert_eq!(global(&env, "n"), "3");
+        assert_eq!(global(&env, "lengths"), "[1, 2]");
+        assert_eq!(global(&env, "sum"), "6");
+
+        assert_error("var(ys) = list.map([int:1], func(a, b) { return a + b; })", "expects 2 argument(s), got 1");
+        assert_error("var(ys) = list.map([int:1], int:1)", "list.map requires a function as its second parameter");
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let env = run(r#"
+            var(a) = int:2 + int:3 * int:4 - int:10 / int:3 % int:2
+            var(b) = (int:2 + int:3) * int:4
+            var(c) = -int:2 * int:3 + int:7 % int:4
+            var(d) = int:7 / int:2
+            var(e) = int:7 / float:2
+            var(min) = -int:9223372036854775807 - int:1
+        "#).unwrap();
+        assert_eq!(global(&env, "a"), "13");
+        assert_eq!(global(&env, "b"), "20");
+        assert_eq!(global(&env, "c"), "-3");
+        assert_eq!(global(&env, "d"), "3");
+        assert_eq!(global(&env, "e"), "3.5");
+        assert_eq!(global(&env, "min"), "-9223372036854775808");
+
+        assert_error("var(x) = int:9223372036854775807 + int:1", "Integer overflow in 9223372036854775807 + 1");
+        assert_error("var(x) = -int:9223372036854775807 - int:2", "Integer overflow");
+        assert_error("var(x) = -(-int:9223372036854775807 - int:1)", "Integer overflow in negation");
+        assert_error("var(x) = int:1 / int:0", "Division by zero");
+        assert_error("var(x) = int:1 % int:0", "Division by zero");
+        assert_error("var(x) = float:1 / float:0", "Division by zero");
+    }
+
+    #[test]
+    fn test_logic_and_comparison() {
+        // boom fails when called, so the first two lines only run if the right side is skipped
+        let env = run(r#"
+            func(boom()) = { return int:1 / int:0 }
+            var(and) = false && boom()
+            var(or) = true || boom()
+            var(lt) = int:1 < float:1.5
+            var(ge) = float:2 >= int:2
+            var(gt) = int:3 > float:3.5
+            var(eq) = int:2 == float:2
+            var(ne) = float:2.5 != int:2
+        "#).unwrap();
+        assert_eq!(global(&env, "and"), "false");
+        assert_eq!(global(&env, "or"), "true");
+        assert_eq!(global(&env, "lt"), "true");
+        assert_eq!(global(&env, "ge"), "true");
+        assert_eq!(global(&env, "gt"), "false");
+        assert_eq!(global(&env, "eq"), "true");
+        assert_eq!(global(&env, "ne"), "true");
+
+        assert_error("func(boom()) = { return int:1 / int:0 }\nvar(x) = true && boom()", "Division by zero");
+        assert_error("var(x) = true && int:1", "Operator '&&' requires bool operands, got int");
+    }
+
+    #[test]
+    fn test_return() {
+        let env = run(r#"
+            func(find(xs, target)) = {
+                for(x in var(xs)) {
+                    while(true) {
+                        if(x == target) { return "found" }
+                        break
+                    }
+                }
+                return "missing"
+            }
+            func(count()) = {
+                var(i) = int:0
+                while(true) { i = i + int:1; if(i == int:3) { return i } }
+            }
+            func(nothing()) = { var(y) = int:1 }
+            func(bare()) = { return; }
+            var(found) = find([int:1, int:2], int:2)
+            var(missing) = find([int:1], int:3)
+            var(counted) = count()
+            var(none) = nothing()
+            var(empty) = bare()
+        "#).unwrap();
+        assert_eq!(global(&env, "found"), "found");
+        assert_eq!(global(&env, "missing"), "missing");
+        assert_eq!(global(&env, "counted"), "3");
+        assert_eq!(global(&env, "none"), "null");
+        assert_eq!(global(&env, "empty"), "null");
+    }
+
+    #[test]
+    fn test_function_calls() {
+        let env = run(r#"
+            func(describe(name, n, items)) = { return name + ":" + string:(n * int:2) + ":" + string:list.len(items) }
+            var(text) = describe("a", int:21, [int:1, int:2])
+            func(depth(n)) = { if(n == int:0) { return int:0 } return int:1 + depth(n - int:1) }
+            var(deep) = depth(int:20)
+        "#).unwrap();
+        assert_eq!(global(&env, "text"), "a:42:2");
+        assert_eq!(global(&env, "deep"), "20");
+
+        assert_error("func(f(a, b)) = { return a }\nf(int:1)", "expects 2 argument(s), got 1");
+        assert_error("func(f()) = { return int:1 }\nf(int:1)", "expects 0 argument(s), got 1");
+
+        // Test threads get a smaller stack than the main thread the interpreter runs on
+        std::thread::Builder::new().stack_size(8 << 20).spawn(|| {
+            assert_error("func(forever(n)) = { return forever(n + int:1) }\nforever(int:0)",
+                         "Maximum call depth (200) exceeded in function forever");
+        }).unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_while_and_assignment() {
+        let env = run(r#"
+            var(i) = int:0
+            var(total) = int:0
+            while(i < int:4) {
+                i = i + int:1
+                total = total + i
+            }
+        "#).unwrap();
+        assert_eq!(global(&env, "i"), "4");
+        assert_eq!(global(&env, "total"), "10");
+
+        assert_error("var(i) = int:0\nwhile(i < int:2) { i = i + int:1; total = i }", "Undefined variable: total");
+    }
+
+    #[test]
+    fn test_scopes() {
+        // Inner blocks and functions assign the variables of the scopes around them
+        let env = run(r#"
+            var(x) = int:1
+            var(calls) = int:0
+            if(true) { x = x + int:1; while(x < int:5) { x = x * int:2 } }
+            for(n in [int:1, int:2]) { var(x2) = n; x = x + var(x2) }
+            func(count()) = { calls = calls + int:1 }
+            count()
+            count()
+            func(shadow()) = { var(x) = int:100; return x }
+            var(shadowed) = shadow()
+        "#).unwrap();
+        assert_eq!(global(&env, "x"), "11");
+        assert_eq!(global(&env, "calls"), "2");
+        assert_eq!(global(&env, "shadowed"), "100");
+
+        for (source, name) in [
+            ("func(f()) = { var(inner) = int:1 }\nf()\nvar(y) = inner", "inner"),
+            ("if(true) { var(b) = int:1 }\nvar(y) = b", "b"),
+            ("while(true) { var(w) = int:1; break }\nvar(y) = w", "w"),
+            ("for(n in [int:1]) { var(q) = int:1 }\nvar(y) = q", "q"),
+            ("for(n in [int:1]) { }\nvar(y) = n", "n"),
+        ] {
+            assert_error(source, &format!("Undefined variable: {}", name));
+        }
+    }
+
+    #[test]
+    fn test_file_handles() {
+        let dir = std::env::temp_dir().join(format!("leonbasic-file-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("shared.txt").display().to_string();
+
+        // Both variables hold the same file, so the second read starts where the first stopped
+        let env = run(&format!(r#"
+            var(out) = basic.open({path:?}, "write")
+            var(copy) = var(out)
+            basic.write(var(out), "one ")
+            basic.write(var(copy), "two")
+            basic.close(var(copy))
+            var(file) = basic.open({path:?}, "read")
+            var(same) = var(file)
+            var(first) = basic.read(var(file))
+            var(second) = basic.read(var(same))
+            basic.close(var(file))
+        "#)).unwrap();
+        assert_eq!(global(&env, "first"), "one two");
+        assert_eq!(global(&env, "second"), "");
+
+        for call in ["basic.read(var(same))", "basic.write(var(same), \"x\")", "basic.close(var(same))"] {
+            let source = format!("var(file) = basic.open({:?}, \"read\")\nvar(same) = var(file)\nbasic.close(var(file))\n{}", path, call);
+            assert_error(&source, "has already been closed");
+        }
+
+        let missing = dir.join("missing.txt").display().to_string();
+        assert_error(&format!("basic.open({:?}, \"read\")", missing), &missing);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_self_referencing_list() {
+        let env = run(r#"
+            var(xs) = [int:1]
+            list.push(var(xs), var(xs))
+            var(text) = string:var(xs)
+            var(joined) = list.join(var(xs), ";")
+            var(ys) = [int:1]
+            list.push(var(ys), var(ys))
+            var(same) = var(xs) == var(ys)
+        "#).unwrap();
+        assert_eq!(global(&env, "text"), "[1, [...]]");
+        assert_eq!(global(&env, "joined"), "1;[1, [...]]");
+        assert_eq!(global(&env, "same"), "true");
+    }
+
+    #[test]
+    fn test_self_referencing_map() {
+        let env = run(r#"
+            var(m) = {"a": int:1}
+            var(m)["self"] = var(m)
+            var(text) = string:var(m)
+            var(n) = {"a": int:1}
+            var(n)["self"] = var(n)
+            var(same) = var(m) == var(n)
+            var(n)["a"] = int:2
+            var(changed) = var(m) == var(n)
+        "#).unwrap();
+        assert_eq!(global(&env, "text"), "{\"a\": 1, \"self\": {...}}");
+        assert_eq!(global(&env, "same"), "true");
+        assert_eq!(global(&env, "changed"), "false");
+    }
+
+    #[test]
+    fn test_modules_are_isolated_from_the_caller() {
+        let dir = std::env::temp_dir().join(format!("leonbasic-module-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("reader.leon"), "var(y) = x\n").unwrap();
+        std::fs::write(dir.join("writer.leon"), "x = int:42\n").unwrap();
+        std::fs::write(dir.join("h.leon"), "func(twice(n)) = { return n * int:2 }\n").unwrap();
+        std::fs::write(dir.join("w.leon"),
+                       "require(\"h\") as hh\nrequire(\"basic\") as b\nfunc(go()) = { return hh.twice(int:2) }\n").unwrap();
+        let main = dir.join("main.leon").display().to_string();
+
+        for module in ["reader", "writer"] {
+            let (env, result) = run_as(&main, &format!("var(x) = int:5\nrequire(\"{}\")", module));
+            assert!(result.unwrap_err().to_string().contains("Undefined variable: x"), "{}", module);
+            assert_eq!(global(&env, "x"), "5");
+        }
+
+        // Names a module gives to its own requires stay inside the module
+        let (env, result) = run_as(&main, "require(\"w\")\nvar(r) = w.go()");
+        result.unwrap();
+        assert_eq!(global(&env, "r"), "4");
+        for call in ["hh.twice(int:1)", "b.print(int:1)", "h.twice(int:1)"] {
+            let (_, result) = run_as(&main, &format!("require(\"w\")\n{}", call));
+            assert!(result.unwrap_err().to_string().contains("Undefined function"), "{}", call);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
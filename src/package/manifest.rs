@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+// Name of the project manifest
+pub const MANIFEST_FILE: &str = "leon.toml";
+
+// leon.toml:
+//
+//   [package]
+//   name = "hello"
+//   version = "0.1.0"
+//   entry = "main.leon"      # script run by `leonbasic run`
+//   paths = ["lib"]          # extra module directories, relative to the manifest
+//
+//   [dependencies]
+//   utils = "1.0.0"
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub package: PackageInfo,
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackageInfo {
+    pub name: String,
+    pub version: String,
+    #[serde(default = "default_entry")]
+    pub entry: String,
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+// The package name is the namespace of its functions, as in utils.add, so it has to be a name
+// require can load: letters, digits and _, not starting with a digit
+pub(super) fn is_module_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// major.minor.patch, each a number
+fn is_version(version: &str) -> bool {
+    let parts: Vec<&str> = version.split('.').collect();
+    parts.len() == 3 && parts.iter().all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn default_entry() -> String {
+    "main.leon".to_string()
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Manifest, String> {
+        let manifest: Manifest = toml::from_str(text).map_err(|e| e.message().to_string())?;
+        if !is_module_name(&manifest.package.name) {
+            return Err(format!("package.name must be a valid module name (use letters, digits and _), got {:?}",
+                               manifest.package.name));
+        }
+        if !is_version(&manifest.package.version) {
+            return Err(format!("package.version must look like 1.2.3, got {:?}", manifest.package.version));
+        }
+        Ok(manifest)
+    }
+
+    pub fn load(path: &Path) -> Result<Manifest, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Manifest::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+// A manifest together with the directory it was found in
+pub struct Project {
+    pub dir: PathBuf,
+    pub manifest: Manifest,
+}
+
+impl Project {
+    // Nearest leon.toml in `start` or one of its parent directories
+    pub fn find(start: &Path) -> Result<Project, String> {
+        let start = fs::canonicalize(start).unwrap_or_else(|_| start.to_path_buf());
+        let dir = start.ancestors()
+            .find(|dir| dir.join(MANIFEST_FILE).is_file())
+            .ok_or_else(|| format!("No {} found in {} or any parent directory (create one with `leonbasic init`)",
+                                   MANIFEST_FILE, start.display()))?;
+        let manifest = Manifest::load(&dir.join(MANIFEST_FILE))?;
+        Ok(Project { dir: dir.to_path_buf(), manifest })
+    }
+
+    pub fn entry(&self) -> PathBuf {
+        self.dir.join(&self.manifest.package.entry)
+    }
+
+    // Module directories from package.paths, in the order they are listed
+    pub fn module_paths(&self) -> Vec<PathBuf> {
+        self.manifest.package.paths.iter().map(|path| self.dir.join(path)).collect()
+    }
+}
+
+// Create leon.toml, main.leon and lib/ in `dir`; an existing main.leon is kept.
+// Returns the files that were created.
+pub fn init_project(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let manifest_path = dir.join(MANIFEST_FILE);
+    if manifest_path.exists() {
+        return Err(format!("{} already exists", manifest_path.display()));
+    }
+    fs::create_dir_all(dir.join("lib"))
+        .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+    // The project is named after its directory
+    let name = fs::canonicalize(dir).ok()
+        .and_then(|dir| dir.file_name().map(|name| project_name(&name.to_string_lossy())))
+        .unwrap_or_else(|| "project".to_string());
+    let manifest = format!(
+        "[package]\nname = {:?}\nversion = \"0.1.0\"\nentry = \"main.leon\"\n\
+         # Directories searched by require, relative to this file\npaths = [\"lib\"]\n\n[dependencies]\n",
+        name);
+
+    let mut created = Vec::new();
+    write_new(&manifest_path, &manifest, &mut created)?;
+    let entry = dir.join("main.leon");
+    if !entry.exists() {
+        write_new(&entry, "require(\"basic\")\n\nbasic.print(string:\"Hello, World!\")\n", &mut created)?;
+    }
+    Ok(created)
+}
+
+// Module name for a project in the directory `dir_name`, e.g. my-app becomes my_app
+fn project_name(dir_name: &str) -> String {
+    let name: String = dir_name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if is_module_name(&name) { name } else { format!("_{}", name) }
+}
+
+fn write_new(path: &Path, contents: &str, created: &mut Vec<PathBuf>) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    created.push(path.to_path_buf());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_init_and_parse() {
+        let dir = std::env::temp_dir().join(format!("leonbasic-manifest-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(init_project(&dir).unwrap().len(), 2);
+        assert!(init_project(&dir).is_err());
+
+        let project = Project::find(&dir.join("lib")).unwrap();
+        assert_eq!(project.manifest.package.name, format!("leonbasic_manifest_test_{}", std::process::id()));
+        assert_eq!(project.manifest.package.version, "0.1.0");
+        assert_eq!(project.entry(), project.dir.join("main.leon"));
+        assert_eq!(project.module_paths(), vec![project.dir.join("lib")]);
+
+        let manifest = Manifest::parse("[package]\nname = \"a\"\nversion = \"1.0.0\"\n[dependencies]\nutils = \"0.2.0\"").unwrap();
+        assert_eq!(manifest.package.entry, "main.leon");
+        assert_eq!(manifest.dependencies["utils"], "0.2.0");
+        assert!(Manifest::parse("[package]\nname = \"a\"\nversion = \"1.0.0\"\nentry_point = \"x\"").is_err());
+        assert!(Manifest::parse("[package]\nname = \"a\"\nversion = \"1.0\"").is_err());
+        for name in ["", "my-proj", "2048", "a.b"] {
+            assert!(Manifest::parse(&format!("[package]\nname = {:?}\nversion = \"1.0.0\"", name)).is_err(), "{}", name);
+        }
+        assert_eq!(project_name("my-proj"), "my_proj");
+        assert_eq!(project_name("2048"), "_2048");
+        assert_eq!(project_name("游戏"), "__");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,65 +1,249 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+mod manifest;
+mod store;
+
+pub use manifest::{init_project, Project};
+pub use store::PackageStore;
+
+// Libraries provided by the interpreter itself; requiring them loads no file
+pub const BUILTIN_LIBRARIES: [&str; 7] = ["basic", "list", "map", "process", "request", "time", "color"];
+
+// Environment variable with extra module directories, separated like PATH
+const LEON_PATH: &str = "LEON_PATH";
+
+pub fn is_builtin(package_name: &str) -> bool {
+    BUILTIN_LIBRARIES.contains(&package_name)
+}
+
+// Finds the files of modules loaded with require.
+// Directories are searched in this order:
+//   1. the directory of the script being run
+//   2. the paths listed in the project's leon.toml, when run as a project
+//   3. the directories in LEON_PATH
+//   4. the user library directory, ~/.leonbasic/lib
+//   5. directories added with basic.setrequirepath, oldest first
+//   6. packages installed with `leonbasic install`, ~/.leonbasic/packages
+// A module is identified by its resolved file, so requiring it again under another name
+// does not run it twice.
 pub struct PackageManager {
-    loaded_packages: HashMap<String, bool>,
-    require_path: PathBuf,
+    // Modules whose code has run to the end
+    loaded_packages: HashSet<String>,
+    // Modules whose code is running, outermost first: (key, name used in require)
+    loading: Vec<(String, String)>,
+    script_dir: PathBuf,
+    project_paths: Vec<PathBuf>,
+    env_paths: Vec<PathBuf>,
+    user_lib_dir: Option<PathBuf>,
+    require_paths: Vec<PathBuf>,
+    store: Option<PackageStore>,
 }
 
 impl PackageManager {
     pub fn new() -> Self {
+        let env_paths = std::env::var_os(LEON_PATH)
+            .map(|paths| std::env::split_paths(&paths).filter(|path| !path.as_os_str().is_empty()).collect())
+            .unwrap_or_default();
         Self {
-            loaded_packages: HashMap::new(),
-            require_path: PathBuf::from("./"),
+            loaded_packages: HashSet::new(),
+            loading: Vec::new(),
+            script_dir: PathBuf::new(),
+            project_paths: Vec::new(),
+            env_paths,
+            user_lib_dir: user_lib_dir(),
+            require_paths: Vec::new(),
+            store: user_store(),
         }
     }
-    
-    pub fn set_require_path(&mut self, path: &str) {
-        self.require_path = PathBuf::from(path);
+
+    // Directory of the script being run; the shell uses the current directory
+    pub fn set_script_dir(&mut self, script_path: &str) {
+        self.script_dir = Path::new(script_path).parent().map(Path::to_path_buf).unwrap_or_default();
     }
-    
-    pub fn load_package(&mut self, package_name: &str) -> Result<String, String> {
-        // Check if already loaded
-        if let Some(_) = self.loaded_packages.get(package_name) {
-            return Ok("".to_string());
+
+    // Module directories of the project being run
+    pub fn set_project_paths(&mut self, paths: Vec<PathBuf>) {
+        self.project_paths = paths;
+    }
+
+    // basic.setrequirepath: relative paths are taken from the script directory
+    pub fn add_require_path(&mut self, path: &str) {
+        let path = self.script_dir.join(path);
+        if !self.require_paths.contains(&path) {
+            self.require_paths.push(path);
         }
-        
-        // For built-in libraries, return success and mark as loaded
-        if package_name == "basic" || package_name == "request" {
-            self.loaded_packages.insert(package_name.to_string(), true);
-            return Ok("".to_string());
+    }
+
+    pub fn is_package_loaded(&self, key: &str) -> bool {
+        self.loaded_packages.contains(key)
+    }
+
+    // Called before running a module's code; a module that is still running is being required
+    // from its own dependencies, which would recurse forever
+    pub fn begin_loading(&mut self, key: &str, package_name: &str) -> Result<(), String> {
+        if let Some(start) = self.loading.iter().position(|(loading, _)| loading == key) {
+            let chain: Vec<&str> = self.loading[start..].iter().map(|(_, name)| name.as_str())
+                .chain(std::iter::once(package_name))
+                .collect();
+            return Err(format!("Circular require: {}", chain.join(" -> ")));
         }
-        
-        // Try to load custom modules from require_path
-        let file_path = self.require_path.join(format!("{}.leon", package_name));
-        
-        if file_path.exists() {
-            match fs::read_to_string(&file_path) {
-                Ok(content) => {
-                    self.loaded_packages.insert(package_name.to_string(), true);
-                    Ok(content)
-                },
-                Err(e) => Err(format!("Failed to read module file: {}", e)),
+        self.loading.push((key.to_string(), package_name.to_string()));
+        Ok(())
+    }
+
+    // Called after a module's code has run; only a module that ran without error counts as loaded
+    pub fn finish_loading(&mut self, succeeded: bool) {
+        if let Some((key, _)) = self.loading.pop() {
+            if succeeded {
+                self.loaded_packages.insert(key);
             }
-        } else {
-            Err(format!("Module not found: {}", package_name))
         }
     }
-    
-    pub fn is_package_loaded(&self, package_name: &str) -> bool {
-        self.loaded_packages.contains_key(package_name)
+
+    // Directories searched for modules, in order
+    pub fn search_path(&self) -> Vec<&Path> {
+        std::iter::once(self.script_dir.as_path())
+            .chain(self.project_paths.iter().map(PathBuf::as_path))
+            .chain(self.env_paths.iter().map(PathBuf::as_path))
+            .chain(self.user_lib_dir.as_deref())
+            .chain(self.require_paths.iter().map(PathBuf::as_path))
+            .collect()
+    }
+
+    // First module file for package_name in the search path, then the installed package
+    pub fn resolve(&self, package_name: &str) -> Option<PathBuf> {
+        self.search_path().into_iter()
+            .flat_map(|dir| [
+                dir.join(format!("{}.leon", package_name)),
+                dir.join("lib").join(format!("{}.leon", package_name)),
+                dir.join(package_name).join("index.leon"),
+            ])
+            .find(|path| path.is_file())
+            .or_else(|| self.installed_entry(package_name))
+    }
+
+    fn installed_entry(&self, package_name: &str) -> Option<PathBuf> {
+        let store = self.store.as_ref()?;
+        store.entry_path(&store.find(package_name)?)
+    }
+
+    // A dependency from leon.toml: require must load the installed package, and it must have
+    // the version the project asks for. A module file found earlier in the search path would
+    // be loaded instead, and nothing tells which version it is.
+    pub fn check_dependency(&self, package_name: &str, version: &str) -> Result<(), String> {
+        let path = self.find_package(package_name)?;
+        let Some((store, installed)) = self.store.as_ref()
+            .and_then(|store| store.find(package_name).map(|installed| (store, installed))) else {
+            return Err(format!("{} is not an installed package", path.display()));
+        };
+        if store.entry_path(&installed).as_ref() != Some(&path) {
+            return Err(format!("{} is found before the installed package", path.display()));
+        }
+        if installed.version != version {
+            return Err(format!("version {} is installed", installed.version));
+        }
+        Ok(())
+    }
+
+    // File of a module, or an error naming every directory that was searched
+    pub fn find_package(&self, package_name: &str) -> Result<PathBuf, String> {
+        self.resolve(package_name).ok_or_else(|| {
+            let mut searched: Vec<String> = self.search_path().iter()
+                .map(|dir| if dir.as_os_str().is_empty() { ".".to_string() } else { dir.display().to_string() })
+                .collect();
+            if let Some(store) = &self.store {
+                searched.push(format!("installed packages in {}", store.root().display()));
+            }
+            format!("Module not found: {} (searched {})", package_name, searched.join(", "))
+        })
     }
+
+    // Read a module's source file
+    pub fn load_package(&self, file_path: &Path) -> Result<String, String> {
+        fs::read_to_string(file_path)
+            .map_err(|e| format!("Failed to read module file {}: {}", file_path.display(), e))
+    }
+}
+
+// Key a module file is remembered by: its canonical path when it can be determined
+pub fn package_key(file_path: &Path) -> String {
+    fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf()).display().to_string()
+}
+
+fn user_dir() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".leonbasic"))
+}
+
+// Per-user module directory, ~/.leonbasic/lib
+fn user_lib_dir() -> Option<PathBuf> {
+    Some(user_dir()?.join("lib"))
+}
+
+// Per-user package store, ~/.leonbasic/packages
+pub fn user_store() -> Option<PackageStore> {
+    Some(PackageStore::at(user_dir()?.join("packages")))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
+    #[test]
+    fn test_search_order() {
+        let dir = std::env::temp_dir().join(format!("leonbasic-package-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("script/lib")).unwrap();
+        fs::create_dir_all(dir.join("extra")).unwrap();
+        fs::write(dir.join("script/lib/utils.leon"), "").unwrap();
+        fs::write(dir.join("extra/utils.leon"), "").unwrap();
+        fs::write(dir.join("extra/more.leon"), "").unwrap();
+
+        let mut pm = PackageManager::new();
+        // Packages the user has installed must not affect the result
+        pm.store = None;
+        pm.set_script_dir(dir.join("script/main.leon").to_str().unwrap());
+        assert_eq!(pm.resolve("more"), None);
+        pm.add_require_path("../extra");
+        assert_eq!(pm.resolve("utils"), Some(dir.join("script/lib/utils.leon")));
+        assert_eq!(pm.resolve("more"), Some(dir.join("script/../extra/more.leon")));
+        assert!(is_builtin("basic"));
+        assert!(pm.find_package("missing").unwrap_err().starts_with("Module not found: missing (searched "));
+
+        pm.begin_loading("a.leon", "a").unwrap();
+        pm.begin_loading("b.leon", "b").unwrap();
+        assert_eq!(pm.begin_loading("a.leon", "a"), Err("Circular require: a -> b -> a".to_string()));
+        pm.finish_loading(true);
+        assert!(pm.is_package_loaded("b.leon") && !pm.is_package_loaded("a.leon"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
-    fn test_builtin_packages() {
+    fn test_dependency_versions() {
+        let dir = std::env::temp_dir().join(format!("leonbasic-dependency-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("utils")).unwrap();
+        fs::create_dir_all(dir.join("app")).unwrap();
+        fs::write(dir.join("utils").join(manifest::MANIFEST_FILE),
+                  "[package]\nname = \"utils\"\nversion = \"1.0.0\"\nentry = \"utils.leon\"\n").unwrap();
+        fs::write(dir.join("utils/utils.leon"), "").unwrap();
+        let store = PackageStore::at(dir.join("store"));
+        store.install(&dir.join("utils")).unwrap();
+
         let mut pm = PackageManager::new();
-        assert!(pm.load_package("basic").is_ok());
-        assert!(pm.is_package_loaded("basic"));
+        pm.store = Some(store);
+        pm.set_script_dir(dir.join("app/main.leon").to_str().unwrap());
+        assert_eq!(pm.check_dependency("utils", "1.0.0"), Ok(()));
+        assert_eq!(pm.check_dependency("utils", "2.0.0"), Err("version 1.0.0 is installed".to_string()));
+        assert!(pm.check_dependency("missing", "1.0.0").unwrap_err().starts_with("Module not found: missing"));
+
+        // A file next to the script would be loaded instead of the installed package
+        fs::write(dir.join("app/utils.leon"), "").unwrap();
+        fs::write(dir.join("app/local.leon"), "").unwrap();
+        assert!(pm.check_dependency("utils", "1.0.0").unwrap_err().ends_with("utils.leon is found before the installed package"));
+        assert!(pm.check_dependency("local", "1.0.0").unwrap_err().ends_with("local.leon is not an installed package"));
+
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,241 @@
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::manifest::{is_module_name, Manifest, MANIFEST_FILE};
+
+// Name of the lockfile in the store
+const LOCKFILE: &str = "leon.lock";
+
+// Per-user store of installed packages:
+//   ~/.leonbasic/packages/<name>/<version>/   copy of the package, with its leon.toml
+//   ~/.leonbasic/packages/leon.lock           the installed version of every package
+// require("name") loads the entry script of the locked version.
+pub struct PackageStore {
+    root: PathBuf,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Lockfile {
+    #[serde(default, rename = "package")]
+    packages: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    pub entry: String,
+    // Directory or archive the package was installed from
+    pub source: String,
+}
+
+impl PackageStore {
+    pub fn at(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Installed package, as recorded in the lockfile; an entry that would leave the package
+    // directory is ignored, since the lockfile can be edited by hand
+    pub fn find(&self, name: &str) -> Option<LockedPackage> {
+        let lockfile = self.read_lockfile().ok()?;
+        lockfile.packages.into_iter()
+            .find(|package| package.name == name)
+            .filter(|package| is_module_name(&package.name) && is_plain_relative(&package.version)
+                    && is_plain_relative(&package.entry))
+    }
+
+    // Entry script of an installed package, if it exists inside the package's directory
+    pub fn entry_path(&self, package: &LockedPackage) -> Option<PathBuf> {
+        let package_dir = self.root.join(&package.name).join(&package.version);
+        contained_file(&package_dir, &package.entry)
+    }
+
+    // Copy a package directory, or unpack a .tar, .tar.gz or .tgz archive of one, into the store
+    // and lock its version. An archive may hold the package at its root or in a single directory.
+    pub fn install(&self, source: &Path) -> Result<LockedPackage, String> {
+        if !source.exists() {
+            return Err(format!("{} does not exist", source.display()));
+        }
+        fs::create_dir_all(&self.root)
+            .map_err(|e| format!("Failed to create {}: {}", self.root.display(), e))?;
+
+        let staging = self.root.join(format!(".staging-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&staging);
+        let result = self.stage(source, &staging).and_then(|package_dir| self.install_staged(source, &package_dir));
+        let _ = fs::remove_dir_all(&staging);
+        result
+    }
+
+    // Put the package's files under `staging`; returns the directory holding its leon.toml
+    fn stage(&self, source: &Path, staging: &Path) -> Result<PathBuf, String> {
+        if source.is_dir() {
+            copy_dir(source, staging)?;
+            return Ok(staging.to_path_buf());
+        }
+
+        let name = source.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let file = File::open(source).map_err(|e| format!("Failed to open {}: {}", source.display(), e))?;
+        let unpacked = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(staging)
+        } else if name.ends_with(".tar") {
+            tar::Archive::new(file).unpack(staging)
+        } else {
+            return Err(format!("{} is not a package: expected a directory, .tar, .tar.gz or .tgz", source.display()));
+        };
+        unpacked.map_err(|e| format!("Failed to unpack {}: {}", source.display(), e))?;
+
+        if staging.join(MANIFEST_FILE).is_file() {
+            return Ok(staging.to_path_buf());
+        }
+        let entries: Vec<PathBuf> = fs::read_dir(staging)
+            .map_err(|e| format!("Failed to read {}: {}", staging.display(), e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect();
+        match entries.as_slice() {
+            [dir] if dir.join(MANIFEST_FILE).is_file() => Ok(dir.clone()),
+            _ => Err(format!("{} has no {}", source.display(), MANIFEST_FILE)),
+        }
+    }
+
+    fn install_staged(&self, source: &Path, package_dir: &Path) -> Result<LockedPackage, String> {
+        let manifest_path = package_dir.join(MANIFEST_FILE);
+        if !manifest_path.is_file() {
+            return Err(format!("{} has no {}", source.display(), MANIFEST_FILE));
+        }
+        let manifest = Manifest::load(&manifest_path)
+            .map_err(|e| format!("Invalid package {}: {}", source.display(), e))?;
+        let package = manifest.package;
+        if !is_plain_relative(&package.entry) {
+            return Err(format!("Package entry {:?} must be a relative path inside the package", package.entry));
+        }
+        if contained_file(package_dir, &package.entry).is_none() {
+            return Err(format!("Package {} has no entry script {}", package.name, package.entry));
+        }
+
+        // Reinstalling a version replaces it
+        let destination = self.root.join(&package.name).join(&package.version);
+        if destination.exists() {
+            fs::remove_dir_all(&destination)
+                .map_err(|e| format!("Failed to remove {}: {}", destination.display(), e))?;
+        }
+        fs::create_dir_all(self.root.join(&package.name))
+            .map_err(|e| format!("Failed to create {}: {}", self.root.display(), e))?;
+        fs::rename(package_dir, &destination)
+            .map_err(|e| format!("Failed to install into {}: {}", destination.display(), e))?;
+
+        let source = fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+        let locked = LockedPackage {
+            name: package.name,
+            version: package.version,
+            entry: package.entry,
+            source: source.display().to_string(),
+        };
+        let mut lockfile = self.read_lockfile()?;
+        lockfile.packages.retain(|package| package.name != locked.name);
+        lockfile.packages.push(locked.clone());
+        lockfile.packages.sort_by(|a, b| a.name.cmp(&b.name));
+        self.write_lockfile(&lockfile)?;
+        Ok(locked)
+    }
+
+    fn read_lockfile(&self) -> Result<Lockfile, String> {
+        let path = self.root.join(LOCKFILE);
+        if !path.exists() {
+            return Ok(Lockfile::default());
+        }
+        let text = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e.message()))
+    }
+
+    fn write_lockfile(&self, lockfile: &Lockfile) -> Result<(), String> {
+        let path = self.root.join(LOCKFILE);
+        let text = toml::to_string(lockfile).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        fs::write(&path, format!("# Written by `leonbasic install`; do not edit\n\n{}", text))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+// A relative path without .. or a root, so joining it to a directory stays inside it
+fn is_plain_relative(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path).components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+// dir/path, when it is a file whose real location (after symlinks) is inside dir
+fn contained_file(dir: &Path, path: &str) -> Option<PathBuf> {
+    if !is_plain_relative(path) {
+        return None;
+    }
+    let dir = fs::canonicalize(dir).ok()?;
+    let file = fs::canonicalize(dir.join(path)).ok()?;
+    (file.starts_with(&dir) && file.is_file()).then_some(file)
+}
+
+// Recursive copy of a package directory, without version control data
+fn copy_dir(from: &Path, to: &Path) -> Result<(), String> {
+    fs::create_dir_all(to).map_err(|e| format!("Failed to create {}: {}", to.display(), e))?;
+    let entries = fs::read_dir(from).map_err(|e| format!("Failed to read {}: {}", from.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read {}: {}", from.display(), e))?;
+        let path = entry.path();
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        if path.is_dir() {
+            copy_dir(&path, &target)?;
+        } else {
+            fs::copy(&path, &target).map_err(|e| format!("Failed to copy {}: {}", path.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_directory_and_archive() {
+        let dir = std::env::temp_dir().join(format!("leonbasic-store-test-{}", std::process::id()));
+        let package = dir.join("src/utils");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(package.join(MANIFEST_FILE), "[package]\nname = \"utils\"\nversion = \"1.0.0\"\nentry = \"utils.leon\"\n").unwrap();
+        fs::write(package.join("utils.leon"), "func(add(a, b)) = { return a + b }\n").unwrap();
+
+        let store = PackageStore::at(dir.join("store"));
+        let locked = store.install(&package).unwrap();
+        assert_eq!((locked.name.as_str(), locked.version.as_str()), ("utils", "1.0.0"));
+        assert!(store.entry_path(&locked).unwrap().ends_with("utils/1.0.0/utils.leon"));
+
+        // Version 2.0.0 as a .tar.gz with the package inside a directory replaces the locked version
+        fs::write(package.join(MANIFEST_FILE), "[package]\nname = \"utils\"\nversion = \"2.0.0\"\nentry = \"utils.leon\"\n").unwrap();
+        let archive = dir.join("utils-2.0.0.tar.gz");
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(File::create(&archive).unwrap(), Default::default()));
+        builder.append_dir_all("utils-2.0.0", &package).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+        store.install(&archive).unwrap();
+        assert_eq!(store.find("utils").map(|package| package.version), Some("2.0.0".to_string()));
+        assert!(store.entry_path(&store.find("utils").unwrap()).is_some());
+
+        assert!(store.install(&dir.join("src")).unwrap_err().contains("has no leon.toml"));
+        fs::remove_dir_all(dir.join("store/utils")).unwrap();
+        fs::write(dir.join("outside.leon"), "").unwrap();
+        for entry in ["../../outside.leon", "/tmp/outside.leon"] {
+            fs::write(package.join(MANIFEST_FILE),
+                      format!("[package]\nname = \"utils\"\nversion = \"3.0.0\"\nentry = {:?}\n", entry)).unwrap();
+            assert!(store.install(&package).unwrap_err().contains("must be a relative path inside the package"));
+        }
+        // A lockfile edited to point outside the store is not followed
+        fs::write(dir.join("store").join(LOCKFILE),
+                  "[[package]]\nname = \"utils\"\nversion = \"2.0.0\"\nentry = \"../../../outside.leon\"\nsource = \"\"\n").unwrap();
+        assert_eq!(store.find("utils"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
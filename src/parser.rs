@@ -0,0 +1,855 @@
+use crate::ast::{BinaryOp, Block, CastType, Expr, ExprKind, ForIterable, Span, Stmt, StmtKind, SyntaxError, UnaryOp};
+use crate::lexer::{tokenize, Token, TokenKind};
+use std::rc::Rc;
+
+// Deepest nesting of blocks and expressions the parser accepts, so that parsing, compiling
+// and running if(...) { if(...) { ... } } or ((((...)))) cannot overflow the stack
+const MAX_NESTING: usize = 256;
+
+/// Parse a complete LeonBasic program into a list of statements
+pub fn parse_program(source: &str) -> Result<Block, SyntaxError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser { tokens, pos: 0, loop_depth: 0, in_function: false, depth: 0 };
+    parser.parse_program()
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // Number of loops enclosing the current statement, for break/continue checks
+    loop_depth: usize,
+    // Whether the current statement is inside a function body, for return checks
+    in_function: bool,
+    // Number of statements and operands being parsed around the current one, see MAX_NESTING
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &TokenKind {
+        &self.tokens[self.pos].kind
+    }
+
+    fn peek_at(&self, offset: usize) -> &TokenKind {
+        let index = (self.pos + offset).min(self.tokens.len() - 1);
+        &self.tokens[index].kind
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.pos].span
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn check(&self, kind: &TokenKind) -> bool {
+        self.peek() == kind
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.check(kind) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, kind: &TokenKind) -> Result<Span, SyntaxError> {
+        if self.check(kind) {
+            Ok(self.advance().span)
+        } else {
+            Err(self.unexpected(&kind.describe()))
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<String, SyntaxError> {
+        match self.peek().clone() {
+            TokenKind::Ident(name) => {
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> SyntaxError {
+        SyntaxError::new(
+            format!("Expected {}, found {}", expected, self.peek().describe()),
+            self.span(),
+        )
+    }
+
+    fn check_word(&self, word: &str) -> bool {
+        matches!(self.peek(), TokenKind::Ident(name) if name == word)
+    }
+
+    // Is the current token the identifier `word` followed by `next`?
+    fn check_keyword(&self, word: &str, next: &TokenKind) -> bool {
+        matches!(self.peek(), TokenKind::Ident(name) if name == word) && self.peek_at(1) == next
+    }
+
+    fn parse_program(&mut self) -> Result<Block, SyntaxError> {
+        let mut statements = Vec::new();
+        loop {
+            while self.eat(&TokenKind::Semicolon) {}
+            if self.check(&TokenKind::Eof) {
+                return Ok(statements);
+            }
+            statements.push(self.parse_statement()?);
+        }
+    }
+
+    fn parse_block(&mut self) -> Result<Block, SyntaxError> {
+        self.expect(&TokenKind::LBrace)?;
+        let mut statements = Vec::new();
+        loop {
+            while self.eat(&TokenKind::Semicolon) {}
+            if self.eat(&TokenKind::RBrace) {
+                return Ok(statements);
+            }
+            if self.check(&TokenKind::Eof) {
+                return Err(self.unexpected("'}'"));
+            }
+            statements.push(self.parse_statement()?);
+        }
+    }
+
+    // Run `parse` one level deeper in the program, or fail past MAX_NESTING
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, SyntaxError>) -> Result<T, SyntaxError> {
+        if self.depth >= MAX_NESTING {
+            return Err(SyntaxError::new("Nesting too deep", self.span()));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    // Every statement inside a block is one level deeper than the statement holding the block
+    fn parse_statement(&mut self) -> Result<Stmt, SyntaxError> {
+        let span = self.span();
+        let kind = self.nested(Self::parse_statement_kind)?;
+        // Semicolons between statements are optional
+        self.eat(&TokenKind::Semicolon);
+        Ok(Stmt { kind, span })
+    }
+
+    fn parse_statement_kind(&mut self) -> Result<StmtKind, SyntaxError> {
+        let span = self.span();
+        let kind = if self.check_keyword("require", &TokenKind::LParen) {
+            self.parse_require()?
+        } else if self.check_keyword("func", &TokenKind::LParen) {
+            self.parse_func()?
+        } else if self.check_word("export") && matches!(self.peek_at(1), TokenKind::Ident(word) if word == "func") {
+            if self.in_function {
+                return Err(SyntaxError::new("'export' can only be used outside functions", span));
+            }
+            self.advance();
+            match self.parse_func()? {
+                StmtKind::FuncDef { name, params, body, .. } => StmtKind::FuncDef { name, params, body, exported: true },
+                _ => return Err(SyntaxError::new("'export' must be followed by a function definition", span)),
+            }
+        } else if self.check_keyword("if", &TokenKind::LParen) {
+            self.parse_if()?
+        } else if self.check_keyword("while", &TokenKind::LParen) {
+            self.parse_while()?
+        } else if self.check_keyword("for", &TokenKind::LParen) {
+            self.parse_for()?
+        } else if self.check_word("return") {
+            if !self.in_function {
+                return Err(SyntaxError::new("'return' can only be used inside a function", span));
+            }
+            self.advance();
+            let value = match self.peek() {
+                TokenKind::Semicolon | TokenKind::RBrace | TokenKind::Eof => None,
+                _ => Some(self.parse_expression()?),
+            };
+            StmtKind::Return(value)
+        } else if self.check_word("break") || self.check_word("continue") {
+            let is_break = self.check_word("break");
+            if self.loop_depth == 0 {
+                let word = if is_break { "break" } else { "continue" };
+                return Err(SyntaxError::new(format!("'{}' can only be used inside a loop", word), span));
+            }
+            self.advance();
+            if is_break { StmtKind::Break } else { StmtKind::Continue }
+        } else {
+            self.parse_simple_statement()?.kind
+        };
+        Ok(kind)
+    }
+
+    // Variable definition, assignment or call; also used inside for(...) headers
+    fn parse_simple_statement(&mut self) -> Result<Stmt, SyntaxError> {
+        let span = self.span();
+        let kind = if self.check_keyword("var", &TokenKind::LParen)
+            && matches!(self.peek_at(2), TokenKind::Ident(_))
+            && self.peek_at(3) == &TokenKind::RParen
+            && self.peek_at(4) == &TokenKind::Assign
+        {
+            self.parse_var_def()?
+        } else if matches!(self.peek(), TokenKind::Ident(_)) && self.peek_at(1) == &TokenKind::Assign {
+            let name = self.expect_ident()?;
+            self.advance();
+            let value = self.parse_expression()?;
+            StmtKind::Assign { name, value }
+        } else {
+            let expr = self.parse_expression()?;
+            match expr.kind {
+                ExprKind::Call { .. } | ExprKind::CallValue { .. } => StmtKind::Expr(expr),
+                // var(xs)[int:0] = value;
+                ExprKind::Index { target, index } if self.eat(&TokenKind::Assign) => {
+                    let value = self.parse_expression()?;
+                    StmtKind::IndexAssign { target: *target, index: *index, value }
+                }
+                _ => return Err(SyntaxError::new("Expected a statement", span)),
+            }
+        };
+        Ok(Stmt { kind, span })
+    }
+
+    fn parse_require(&mut self) -> Result<StmtKind, SyntaxError> {
+        self.advance();
+        self.expect(&TokenKind::LParen)?;
+        let name = match self.peek().clone() {
+            TokenKind::Str(name) => {
+                self.advance();
+                name
+            }
+            _ => return Err(self.unexpected("a module name string")),
+        };
+        self.expect(&TokenKind::RParen)?;
+        // require("name") as alias
+        let alias = if self.check_word("as") {
+            self.advance();
+            Some(self.expect_ident()?)
+        } else {
+            None
+        };
+        Ok(StmtKind::Require { name, alias })
+    }
+
+    fn parse_var_def(&mut self) -> Result<StmtKind, SyntaxError> {
+        self.advance();
+        self.expect(&TokenKind::LParen)?;
+        let name = self.expect_ident()?;
+        self.expect(&TokenKind::RParen)?;
+        self.expect(&TokenKind::Assign)?;
+        let value = self.parse_expression()?;
+        Ok(StmtKind::VarDef { name, value })
+    }
+
+    // func(name(args)) is a call, func(name(params)) = { ... } a definition
+    fn parse_func(&mut self) -> Result<StmtKind, SyntaxError> {
+        let call = self.parse_func_call()?;
+        if !self.eat(&TokenKind::Assign) {
+            return Ok(StmtKind::Expr(call));
+        }
+
+        let (name, args) = match call.kind {
+            ExprKind::Call { name, args } => (name, args),
+            _ => unreachable!("parse_func_call always returns a call"),
+        };
+        let mut params = Vec::new();
+        for arg in args {
+            match arg.kind {
+                ExprKind::Var(param) => params.push(param),
+                _ => {
+                    return Err(SyntaxError::new(
+                        "Function parameters must be names or self(name)",
+                        arg.span,
+                    ))
+                }
+            }
+        }
+        let body = self.parse_function_body()?;
+        Ok(StmtKind::FuncDef { name, params, body, exported: false })
+    }
+
+    fn parse_function_body(&mut self) -> Result<Rc<Block>, SyntaxError> {
+        // break/continue cannot jump out of a function body into an enclosing loop
+        let outer_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let outer_in_function = std::mem::replace(&mut self.in_function, true);
+        let body = self.parse_block();
+        self.loop_depth = outer_loop_depth;
+        self.in_function = outer_in_function;
+        Ok(Rc::new(body?))
+    }
+
+    // func(a, b) { ... } in an expression; `func(name(...))` is a call instead
+    fn is_lambda_start(&self) -> bool {
+        match self.peek_at(2) {
+            TokenKind::RParen => true,
+            TokenKind::Ident(_) => matches!(self.peek_at(3), TokenKind::Comma | TokenKind::RParen),
+            _ => false,
+        }
+    }
+
+    fn parse_lambda(&mut self) -> Result<ExprKind, SyntaxError> {
+        self.advance();
+        self.expect(&TokenKind::LParen)?;
+        let mut params = Vec::new();
+        while !self.eat(&TokenKind::RParen) {
+            params.push(self.expect_ident()?);
+            if !self.check(&TokenKind::RParen) {
+                self.expect(&TokenKind::Comma)?;
+            }
+        }
+        let body = self.parse_function_body()?;
+        Ok(ExprKind::Lambda { params, body })
+    }
+
+    fn parse_func_call(&mut self) -> Result<Expr, SyntaxError> {
+        let span = self.span();
+        self.advance();
+        self.expect(&TokenKind::LParen)?;
+        let name = self.parse_dotted_name()?;
+        let args = self.parse_arguments()?;
+        self.expect(&TokenKind::RParen)?;
+        Ok(Expr { kind: ExprKind::Call { name, args }, span })
+    }
+
+    fn parse_if(&mut self) -> Result<StmtKind, SyntaxError> {
+        self.advance();
+        self.expect(&TokenKind::LParen)?;
+        let condition = self.parse_expression()?;
+        self.expect(&TokenKind::RParen)?;
+        let then_body = self.parse_block()?;
+
+        let else_body = if self.check_word("else") {
+            self.advance();
+            if self.check_keyword("if", &TokenKind::LParen) {
+                // else if(...) is an else branch holding a single nested if
+                let span = self.span();
+                let kind = self.nested(Self::parse_if)?;
+                Some(vec![Stmt { kind, span }])
+            } else {
+                Some(self.parse_block()?)
+            }
+        } else {
+            None
+        };
+
+        Ok(StmtKind::If { condition, then_body, else_body })
+    }
+
+    fn parse_while(&mut self) -> Result<StmtKind, SyntaxError> {
+        self.advance();
+        self.expect(&TokenKind::LParen)?;
+        let condition = self.parse_expression()?;
+        self.expect(&TokenKind::RParen)?;
+        let body = self.parse_loop_body()?;
+        Ok(StmtKind::While { condition, body })
+    }
+
+    // for(var(i) = int:0; i < int:10; i = i + int:1) { ... }
+    // for(var(x) in range(int:0, int:10)) { ... } or for(var(c) in var(text)) { ... }
+    fn parse_for(&mut self) -> Result<StmtKind, SyntaxError> {
+        self.advance();
+        self.expect(&TokenKind::LParen)?;
+
+        let foreach_var = if self.check_keyword("var", &TokenKind::LParen)
+            && matches!(self.peek_at(2), TokenKind::Ident(_))
+            && self.peek_at(3) == &TokenKind::RParen
+            && matches!(self.peek_at(4), TokenKind::Ident(word) if word == "in")
+        {
+            self.advance();
+            self.advance();
+            let name = self.expect_ident()?;
+            self.advance();
+            Some(name)
+        } else if matches!(self.peek(), TokenKind::Ident(_))
+            && matches!(self.peek_at(1), TokenKind::Ident(word) if word == "in")
+        {
+            Some(self.expect_ident()?)
+        } else {
+            None
+        };
+
+        if let Some(var) = foreach_var {
+            // Skip the `in` keyword
+            self.advance();
+            let iterable = if self.check_keyword("range", &TokenKind::LParen) {
+                let span = self.span();
+                self.advance();
+                let mut args = self.parse_arguments()?;
+                let (start, end, step) = match args.len() {
+                    1 => (Expr { kind: ExprKind::Int(0), span }, args.remove(0), None),
+                    2 => {
+                        let end = args.remove(1);
+                        (args.remove(0), end, None)
+                    }
+                    3 => {
+                        let step = args.remove(2);
+                        let end = args.remove(1);
+                        (args.remove(0), end, Some(step))
+                    }
+                    _ => return Err(SyntaxError::new("range() takes 1 to 3 arguments", span)),
+                };
+                ForIterable::Range { start, end, step }
+            } else {
+                ForIterable::Value(self.parse_expression()?)
+            };
+            self.expect(&TokenKind::RParen)?;
+            let body = self.parse_loop_body()?;
+            return Ok(StmtKind::ForEach { var, iterable, body });
+        }
+
+        let init = self.parse_simple_statement()?;
+        self.expect(&TokenKind::Semicolon)?;
+        let condition = self.parse_expression()?;
+        self.expect(&TokenKind::Semicolon)?;
+        let step = self.parse_simple_statement()?;
+        self.expect(&TokenKind::RParen)?;
+        let body = self.parse_loop_body()?;
+        Ok(StmtKind::For { init: Box::new(init), condition, step: Box::new(step), body })
+    }
+
+    fn parse_loop_body(&mut self) -> Result<Block, SyntaxError> {
+        self.loop_depth += 1;
+        let body = self.parse_block();
+        self.loop_depth -= 1;
+        body
+    }
+
+    fn parse_dotted_name(&mut self) -> Result<String, SyntaxError> {
+        let mut name = self.expect_ident()?;
+        while self.eat(&TokenKind::Dot) {
+            name.push('.');
+            name.push_str(&self.expect_ident()?);
+        }
+        Ok(name)
+    }
+
+    fn parse_arguments(&mut self) -> Result<Vec<Expr>, SyntaxError> {
+        self.expect(&TokenKind::LParen)?;
+        self.parse_expression_list(&TokenKind::RParen)
+    }
+
+    // Comma separated expressions up to and including `close`; a trailing comma is allowed
+    fn parse_expression_list(&mut self, close: &TokenKind) -> Result<Vec<Expr>, SyntaxError> {
+        let mut items = Vec::new();
+        loop {
+            if self.eat(close) {
+                return Ok(items);
+            }
+            items.push(self.parse_expression()?);
+            if !self.check(close) {
+                self.expect(&TokenKind::Comma)?;
+            }
+        }
+    }
+
+    // Entries of a map literal after the opening brace: "key": value, ... }
+    fn parse_map_entries(&mut self) -> Result<Vec<(Expr, Expr)>, SyntaxError> {
+        let mut entries = Vec::new();
+        loop {
+            if self.eat(&TokenKind::RBrace) {
+                return Ok(entries);
+            }
+            let key = self.parse_expression()?;
+            self.expect(&TokenKind::Colon)?;
+            let value = self.parse_expression()?;
+            entries.push((key, value));
+            if !self.check(&TokenKind::RBrace) {
+                self.expect(&TokenKind::Comma)?;
+            }
+        }
+    }
+
+    fn parse_expression(&mut self) -> Result<Expr, SyntaxError> {
+        self.parse_or()
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, SyntaxError> {
+        let mut left = self.parse_and()?;
+        while self.check(&TokenKind::OrOr) {
+            let span = self.advance().span;
+            let right = self.parse_and()?;
+            left = binary(BinaryOp::Or, left, right, span);
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, SyntaxError> {
+        let mut left = self.parse_comparison()?;
+        while self.check(&TokenKind::AndAnd) {
+            let span = self.advance().span;
+            let right = self.parse_comparison()?;
+            left = binary(BinaryOp::And, left, right, span);
+        }
+        Ok(left)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, SyntaxError> {
+        let left = self.parse_additive()?;
+        let op = match self.peek() {
+            TokenKind::Eq => BinaryOp::Eq,
+            TokenKind::NotEq => BinaryOp::NotEq,
+            TokenKind::Lt => BinaryOp::Lt,
+            TokenKind::Gt => BinaryOp::Gt,
+            TokenKind::LtEq => BinaryOp::LtEq,
+            TokenKind::GtEq => BinaryOp::GtEq,
+            _ => return Ok(left),
+        };
+        let span = self.advance().span;
+        let right = self.parse_additive()?;
+        Ok(binary(op, left, right, span))
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, SyntaxError> {
+        let mut left = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::Plus => BinaryOp::Add,
+                TokenKind::Minus => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            let span = self.advance().span;
+            let right = self.parse_multiplicative()?;
+            left = binary(op, left, right, span);
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, SyntaxError> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::Star => BinaryOp::Mul,
+                TokenKind::Slash => BinaryOp::Div,
+                TokenKind::Percent => BinaryOp::Mod,
+                _ => return Ok(left),
+            };
+            let span = self.advance().span;
+            let right = self.parse_unary()?;
+            left = binary(op, left, right, span);
+        }
+    }
+
+    // Every nested expression, whether in parentheses, a list, arguments or after a prefix,
+    // is parsed through here, so this is where the nesting depth is counted
+    fn parse_unary(&mut self) -> Result<Expr, SyntaxError> {
+        self.nested(Self::parse_prefixed)
+    }
+
+    fn parse_prefixed(&mut self) -> Result<Expr, SyntaxError> {
+        let span = self.span();
+        if self.eat(&TokenKind::Minus) {
+            let operand = self.parse_unary()?;
+            // Fold negative number literals right away
+            let kind = match operand.kind {
+                ExprKind::Int(i) => ExprKind::Int(-i),
+                ExprKind::Float(f) => ExprKind::Float(-f),
+                _ => ExprKind::Unary { op: UnaryOp::Neg, expr: Box::new(operand) },
+            };
+            return Ok(Expr { kind, span });
+        }
+        if self.eat(&TokenKind::Not) {
+            let operand = self.parse_unary()?;
+            return Ok(Expr { kind: ExprKind::Unary { op: UnaryOp::Not, expr: Box::new(operand) }, span });
+        }
+        self.parse_postfix()
+    }
+
+    // Indexing and calls after a primary expression: var(xs)[int:0][int:1], var(fs)[int:0](x)
+    fn parse_postfix(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            let span = self.span();
+            let kind = if self.eat(&TokenKind::LBracket) {
+                let index = self.parse_expression()?;
+                self.expect(&TokenKind::RBracket)?;
+                ExprKind::Index { target: Box::new(expr), index: Box::new(index) }
+            } else if self.check(&TokenKind::LParen) {
+                let args = self.parse_arguments()?;
+                ExprKind::CallValue { callee: Box::new(expr), args }
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr { kind, span };
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, SyntaxError> {
+        let span = self.span();
+        let kind = match self.peek().clone() {
+            TokenKind::Str(s) => {
+                self.advance();
+                ExprKind::Str(s)
+            }
+            TokenKind::Int(i) => {
+                self.advance();
+                ExprKind::Int(i)
+            }
+            TokenKind::Float(f) => {
+                self.advance();
+                ExprKind::Float(f)
+            }
+            TokenKind::LParen => {
+                self.advance();
+                let inner = self.parse_expression()?;
+                self.expect(&TokenKind::RParen)?;
+                return Ok(inner);
+            }
+            TokenKind::LBracket => {
+                self.advance();
+                ExprKind::List(self.parse_expression_list(&TokenKind::RBracket)?)
+            }
+            TokenKind::LBrace => {
+                self.advance();
+                ExprKind::Map(self.parse_map_entries()?)
+            }
+            TokenKind::Ident(name) if name == "true" || name == "false" => {
+                self.advance();
+                ExprKind::Bool(name == "true")
+            }
+            TokenKind::Ident(name) => {
+                if (name == "var" || name == "self") && self.peek_at(1) == &TokenKind::LParen {
+                    self.advance();
+                    self.advance();
+                    let var_name = self.expect_ident()?;
+                    self.expect(&TokenKind::RParen)?;
+                    ExprKind::Var(var_name)
+                } else if name == "func" && self.peek_at(1) == &TokenKind::LParen {
+                    if self.is_lambda_start() {
+                        self.parse_lambda()?
+                    } else {
+                        return self.parse_func_call();
+                    }
+                } else if let (Some(ty), TokenKind::Colon) = (CastType::from_name(&name), self.peek_at(1)) {
+                    self.advance();
+                    self.advance();
+                    // The prefix binds tighter than binary operators: int:a + int:b
+                    let operand = self.parse_unary()?;
+                    ExprKind::Cast { ty, expr: Box::new(operand) }
+                } else {
+                    let dotted = self.parse_dotted_name()?;
+                    if self.check(&TokenKind::LParen) {
+                        let args = self.parse_arguments()?;
+                        ExprKind::Call { name: dotted, args }
+                    } else {
+                        // A dotted name without arguments refers to a builtin such as list.len
+                        ExprKind::Var(dotted)
+                    }
+                }
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+        Ok(Expr { kind, span })
+    }
+}
+
+fn binary(op: BinaryOp, left: Expr, right: Expr, span: Span) -> Expr {
+    Expr { kind: ExprKind::Binary { op, left: Box::new(left), right: Box::new(right) }, span }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_with_operators_is_one_literal() {
+        let program = parse_program("basic.print(string:\"a = b (c)\");").unwrap();
+        assert_eq!(program.len(), 1);
+        match &program[0].kind {
+            StmtKind::Expr(Expr { kind: ExprKind::Call { name, args }, .. }) => {
+                assert_eq!(name, "basic.print");
+                assert!(matches!(&args[0].kind, ExprKind::Cast { expr, .. }
+                    if matches!(&expr.kind, ExprKind::Str(s) if s == "a = b (c)")));
+            }
+            other => panic!("unexpected statement: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_function_definition_and_spans() {
+        let source = "require(\"basic\")\nfunc(add(self(a), b)) = {\n    basic.print(int:self(a) + int:b);\n};\nadd(int:1, int:2);";
+        let program = parse_program(source).unwrap();
+        assert_eq!(program.len(), 3);
+        match &program[1].kind {
+            StmtKind::FuncDef { name, params, body, .. } => {
+                assert_eq!(name, "add");
+                assert_eq!(params, &["a".to_string(), "b".to_string()]);
+                assert_eq!(body.len(), 1);
+                assert_eq!(body[0].span, Span { line: 3, column: 5 });
+            }
+            other => panic!("unexpected statement: {:?}", other),
+        }
+        assert_eq!(program[2].span, Span { line: 5, column: 1 });
+    }
+
+    #[test]
+    fn test_require_alias_and_export() {
+        let program = parse_program("require(\"utils\") as u\nexport func(f(a)) = { return a }").unwrap();
+        assert!(matches!(&program[0].kind, StmtKind::Require { name, alias: Some(alias) } if name == "utils" && alias == "u"));
+        assert!(matches!(&program[1].kind, StmtKind::FuncDef { name, exported: true, .. } if name == "f"));
+        assert!(parse_program("func(f()) = { export func(g()) = { } }").is_err());
+    }
+
+    #[test]
+    fn test_if_else() {
+        let program = parse_program("if(var(a) > int:3) { basic.print(\"x\"); } else { basic.print(\"y\"); }").unwrap();
+        match &program[0].kind {
+            StmtKind::If { condition, then_body, else_body } => {
+                assert!(matches!(condition.kind, ExprKind::Binary { op: BinaryOp::Gt, .. }));
+                assert_eq!(then_body.len(), 1);
+                assert_eq!(else_body.as_ref().map(|b| b.len()), Some(1));
+            }
+            other => panic!("unexpected statement: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_else_if_chain() {
+        let source = "if(var(a) == int:1) { } else if(var(a) == int:2) { } else if(var(a) == int:3) { } else { basic.print(\"z\"); }";
+        let program = parse_program(source).unwrap();
+        let mut depth = 0;
+        let mut stmt = &program[0];
+        while let StmtKind::If { else_body: Some(else_body), .. } = &stmt.kind {
+            depth += 1;
+            match else_body.as_slice() {
+                [nested @ Stmt { kind: StmtKind::If { .. }, .. }] => stmt = nested,
+                _ => break,
+            }
+        }
+        assert_eq!(depth, 3);
+    }
+
+    #[test]
+    fn test_for_loops() {
+        let program = parse_program("for(var(i) = int:0; var(i) < int:3; i = i + int:1) { if(i == int:1) { continue; } }\nfor(x in range(int:5)) { break; }").unwrap();
+        assert!(matches!(&program[0].kind, StmtKind::For { init, .. } if matches!(init.kind, StmtKind::VarDef { .. })));
+        match &program[1].kind {
+            StmtKind::ForEach { var, iterable: ForIterable::Range { start, step, .. }, .. } => {
+                assert_eq!(var, "x");
+                assert!(matches!(start.kind, ExprKind::Int(0)));
+                assert!(step.is_none());
+            }
+            other => panic!("unexpected statement: {:?}", other),
+        }
+
+        let err = parse_program("while(int:1 > int:0) { func(f()) = { break; }; }").unwrap_err();
+        assert_eq!(err.message, "'break' can only be used inside a loop");
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        let program = parse_program("var(x) = -int:1 + int:2 * (int:3 - int:4) % int:5;").unwrap();
+        let value = match &program[0].kind {
+            StmtKind::VarDef { value, .. } => value,
+            other => panic!("unexpected statement: {:?}", other),
+        };
+        // (-int:1) + ((int:2 * (int:3 - int:4)) % int:5)
+        match &value.kind {
+            ExprKind::Binary { op: BinaryOp::Add, left, right } => {
+                assert!(matches!(left.kind, ExprKind::Unary { op: UnaryOp::Neg, .. }));
+                match &right.kind {
+                    ExprKind::Binary { op: BinaryOp::Mod, left, .. } => {
+                        assert!(matches!(&left.kind, ExprKind::Binary { op: BinaryOp::Mul, right, .. }
+                            if matches!(right.kind, ExprKind::Binary { op: BinaryOp::Sub, .. })));
+                    }
+                    other => panic!("unexpected expression: {:?}", other),
+                }
+            }
+            other => panic!("unexpected expression: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_logical_precedence() {
+        let program = parse_program("if(!bool:false || var(a) >= int:1 && var(b) != int:2) { }").unwrap();
+        match &program[0].kind {
+            StmtKind::If { condition, .. } => match &condition.kind {
+                ExprKind::Binary { op: BinaryOp::Or, left, right } => {
+                    assert!(matches!(left.kind, ExprKind::Unary { op: UnaryOp::Not, .. }));
+                    assert!(matches!(right.kind, ExprKind::Binary { op: BinaryOp::And, .. }));
+                }
+                other => panic!("unexpected expression: {:?}", other),
+            },
+            other => panic!("unexpected statement: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_list_literal_and_indexing() {
+        let program = parse_program("var(xs) = [int:1, [int:2, int:3],]\nvar(xs)[int:1][-1] = int:1 + var(xs)[0]").unwrap();
+        match &program[0].kind {
+            StmtKind::VarDef { value: Expr { kind: ExprKind::List(items), .. }, .. } => {
+                assert_eq!(items.len(), 2);
+                assert!(matches!(&items[1].kind, ExprKind::List(inner) if inner.len() == 2));
+            }
+            other => panic!("unexpected statement: {:?}", other),
+        }
+        match &program[1].kind {
+            StmtKind::IndexAssign { target, index, value } => {
+                assert!(matches!(target.kind, ExprKind::Index { .. }));
+                assert!(matches!(index.kind, ExprKind::Int(-1)));
+                assert!(matches!(value.kind, ExprKind::Binary { op: BinaryOp::Add, .. }));
+            }
+            other => panic!("unexpected statement: {:?}", other),
+        }
+        assert!(parse_program("var(xs)[int:0]").is_err());
+    }
+
+    #[test]
+    fn test_lambda_and_call_value() {
+        let program = parse_program("var(f) = func(a, b) { return a + b; }\nvar(fs)[int:0](int:1)\nfunc(add(a))").unwrap();
+        match &program[0].kind {
+            StmtKind::VarDef { value: Expr { kind: ExprKind::Lambda { params, body }, .. }, .. } => {
+                assert_eq!(params, &["a".to_string(), "b".to_string()]);
+                assert_eq!(body.len(), 1);
+            }
+            other => panic!("unexpected statement: {:?}", other),
+        }
+        assert!(matches!(&program[1].kind, StmtKind::Expr(Expr { kind: ExprKind::CallValue { .. }, .. })));
+        assert!(matches!(&program[2].kind, StmtKind::Expr(Expr { kind: ExprKind::Call { .. }, .. })));
+    }
+
+    #[test]
+    fn test_map_literal() {
+        let program = parse_program("var(m) = {string:\"a\": int:1, \"b\": {}}").unwrap();
+        match &program[0].kind {
+            StmtKind::VarDef { value: Expr { kind: ExprKind::Map(entries), .. }, .. } => {
+                assert_eq!(entries.len(), 2);
+                assert!(matches!(&entries[0].0.kind, ExprKind::Cast { ty: CastType::String, .. }));
+                assert!(matches!(&entries[1].1.kind, ExprKind::Map(inner) if inner.is_empty()));
+            }
+            other => panic!("unexpected statement: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_syntax_error_location() {
+        let err = parse_program("var(a) = string:\"x\";\nbasic.print(var(a);").unwrap_err();
+        assert_eq!(err.span, Span { line: 2, column: 19 });
+        let err = parse_program("basic.print(\"unterminated);").unwrap_err();
+        assert_eq!(err.message, "Unterminated string literal");
+        let err = parse_program("if(true) {\n    return;\n}").unwrap_err();
+        assert_eq!(err.span, Span { line: 2, column: 5 });
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        // Test threads get a smaller stack than the main thread the interpreter runs on
+        let parse = |source: String| std::thread::Builder::new().stack_size(8 << 20)
+            .spawn(move || parse_program(&source).map(|_| ())).unwrap().join().unwrap();
+        let nested = |depth: usize| format!("var(x) = {}1{};", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(nested(200)).is_ok());
+        let blocks = |open: &str, depth: usize| format!("{}{}", open.repeat(depth), "}".repeat(depth));
+        assert!(parse(blocks("if(true){", 200)).is_ok());
+        let else_ifs = format!("if(false){{}}{}", " else if(false){}".repeat(1000));
+        for source in [
+            nested(1000), format!("var(x) = {}1;", "-".repeat(1000)), format!("var(x) = {}1;", "[".repeat(1000)),
+            blocks("if(true){", 1000), blocks("while(false){", 2000), blocks("for(x in range(int:1)){", 1000), else_ifs,
+        ] {
+            let err = parse(source).unwrap_err();
+            assert_eq!(err.message, "Nesting too deep");
+            assert_eq!(err.span.line, 1);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Shared handle to a scope; function definitions keep one to remember where they were defined
+pub type ScopeRef = Rc<RefCell<Scope>>;
+
+/// One level of variables: the global scope, a module, a function call or a block.
+///
+/// Lookups walk outwards through the parents, so inner code can read and assign
+/// outer variables, while `var(name) = ...` always declares in the innermost scope.
+#[derive(Default)]
+pub struct Scope {
+    variables: HashMap<String, Value>,
+    parent: Option<ScopeRef>,
+}
+
+impl Scope {
+    pub fn new_global() -> ScopeRef {
+        Rc::new(RefCell::new(Scope::default()))
+    }
+
+    pub fn new_child(parent: &ScopeRef) -> ScopeRef {
+        Rc::new(RefCell::new(Scope {
+            variables: HashMap::new(),
+            parent: Some(parent.clone()),
+        }))
+    }
+
+    /// Declare a variable in this scope, shadowing any outer variable with the same name
+    pub fn define(&mut self, name: &str, value: Value) {
+        self.variables.insert(name.to_string(), value);
+    }
+
+    /// Look at the nearest declaration of `name` without copying it out
+    pub fn with_value<T>(&self, name: &str, f: impl FnOnce(&Value) -> T) -> Option<T> {
+        match self.variables.get(name) {
+            Some(value) => Some(f(value)),
+            None => self.parent.as_ref()?.borrow().with_value(name, f),
+        }
+    }
+
+    /// Overwrite the nearest existing variable. Returns the value back if no scope declares it.
+    pub fn assign(&mut self, name: &str, value: Value) -> Result<(), Value> {
+        if let Some(slot) = self.variables.get_mut(name) {
+            *slot = value;
+            return Ok(());
+        }
+        match &self.parent {
+            Some(parent) => parent.borrow_mut().assign(name, value),
+            None => Err(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_int(scope: &ScopeRef, name: &str) -> Option<i64> {
+        scope.borrow().with_value(name, |value| match value {
+            Value::Int(i) => *i,
+            _ => panic!("expected an int"),
+        })
+    }
+
+    #[test]
+    fn test_shadowing_and_assignment() {
+        let globals = Scope::new_global();
+        globals.borrow_mut().define("x", Value::Int(1));
+
+        let block = Scope::new_child(&globals);
+        block.borrow_mut().define("y", Value::Int(2));
+        assert!(block.borrow_mut().assign("x", Value::Int(3)).is_ok());
+        assert_eq!(read_int(&globals, "x"), Some(3));
+
+        // A declaration in the inner scope hides the outer variable without changing it
+        block.borrow_mut().define("x", Value::Int(4));
+        assert_eq!(read_int(&block, "x"), Some(4));
+        assert_eq!(read_int(&globals, "x"), Some(3));
+
+        assert_eq!(read_int(&globals, "y"), None);
+        assert!(globals.borrow_mut().assign("y", Value::Int(5)).is_err());
+    }
+}
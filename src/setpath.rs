@@ -0,0 +1,266 @@
+//! `--setpath` / `--unsetpath`: put the directory holding the LeonBasic executable on PATH.
+//!
+//! On Windows the machine PATH is changed through PowerShell. Elsewhere a marked block is
+//! written to the rc file of the user's shell (bash, zsh or fish); running `--setpath` again
+//! replaces that block instead of adding another one, and `--unsetpath` removes it.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "windows")]
+use std::process::Command;
+#[cfg(target_os = "windows")]
+use std::os::windows::process::CommandExt; // 用于Windows特定的命令扩展
+
+// Lines around the block we own in an rc file
+const BLOCK_START: &str = "# >>> leonbasic PATH >>>";
+const BLOCK_END: &str = "# <<< leonbasic PATH <<<";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    // Shell named by $SHELL, e.g. /bin/zsh
+    fn detect() -> Result<Self, String> {
+        let shell = env::var("SHELL").map_err(|_| "SHELL is not set, cannot tell which shell you use".to_string())?;
+        match Path::new(&shell).file_name().and_then(|name| name.to_str()) {
+            Some("bash") => Ok(Shell::Bash),
+            Some("zsh") => Ok(Shell::Zsh),
+            Some("fish") => Ok(Shell::Fish),
+            _ => Err(format!("Unsupported shell {} (supported: bash, zsh, fish)", shell)),
+        }
+    }
+
+    // Startup file the shell reads for interactive sessions
+    fn rc_file(self) -> Result<PathBuf, String> {
+        let home = env::var_os("HOME")
+            .map(PathBuf::from)
+            .ok_or_else(|| "HOME is not set".to_string())?;
+        Ok(match self {
+            // Terminal.app starts login shells, which read .bash_profile rather than .bashrc
+            Shell::Bash if cfg!(target_os = "macos") => home.join(".bash_profile"),
+            Shell::Bash => home.join(".bashrc"),
+            Shell::Zsh => env::var_os("ZDOTDIR").map(PathBuf::from).unwrap_or(home).join(".zshrc"),
+            Shell::Fish => env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| home.join(".config"))
+                .join("fish")
+                .join("config.fish"),
+        })
+    }
+
+    // Command that prepends `dir` to PATH, quoted for this shell
+    fn path_line(self, dir: &str) -> String {
+        let mut quoted = String::new();
+        for c in dir.chars() {
+            if matches!(c, '"' | '\\' | '$') || (c == '`' && self != Shell::Fish) {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        match self {
+            Shell::Bash | Shell::Zsh => format!("export PATH=\"{}:$PATH\"", quoted),
+            Shell::Fish => format!("set -gx PATH \"{}\" $PATH", quoted),
+        }
+    }
+}
+
+// Content with our block taken out, or None if there is no complete block
+fn remove_block(content: &str) -> Option<String> {
+    let lines: Vec<&str> = content.lines().collect();
+    let start = lines.iter().position(|line| line.trim() == BLOCK_START)?;
+    let end = start + lines[start..].iter().position(|line| line.trim() == BLOCK_END)?;
+
+    // Also drop the blank line with_block puts in front of the block, even when
+    // lines have been added after the block since
+    let before = if start > 0 && lines[start - 1].is_empty() { start - 1 } else { start };
+    let mut kept: Vec<&str> = lines[..before].to_vec();
+    kept.extend(&lines[end + 1..]);
+    let mut result = kept.join("\n");
+    if !result.is_empty() {
+        result.push('\n');
+    }
+    Some(result)
+}
+
+// Content with our block (re)written at the end
+fn with_block(content: &str, line: &str) -> String {
+    let mut result = remove_block(content).unwrap_or_else(|| content.to_string());
+    if !result.is_empty() {
+        if !result.ends_with('\n') {
+            result.push('\n');
+        }
+        result.push('\n');
+    }
+    result.push_str(&format!("{}\n{}\n{}\n", BLOCK_START, line, BLOCK_END));
+    result
+}
+
+// Line diff between the old and new file. Edits only ever touch one region,
+// so the common lines at both ends are trimmed and the middle is shown.
+fn diff(path: &Path, old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let prefix = old_lines.iter().zip(&new_lines).take_while(|(a, b)| a == b).count();
+    let suffix = old_lines[prefix..].iter().rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut out = format!("--- {}\n+++ {}\n@@ -{} +{} @@\n", path.display(), path.display(), prefix + 1, prefix + 1);
+    for line in &old_lines[prefix..old_lines.len() - suffix] {
+        out.push_str(&format!("-{}\n", line));
+    }
+    for line in &new_lines[prefix..new_lines.len() - suffix] {
+        out.push_str(&format!("+{}\n", line));
+    }
+    out
+}
+
+// Show or apply a change to an rc file
+fn update_file(path: &Path, old: &str, new: &str, dry_run: bool) -> Result<(), String> {
+    if dry_run {
+        print!("{}", diff(path, old, new));
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    fs::write(path, new).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+fn read_rc_file(path: &Path) -> Result<String, String> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+// Directory of the running executable
+fn exe_dir() -> Result<PathBuf, String> {
+    let exe_path = env::current_exe()
+        .map_err(|e| format!("Failed to get executable path: {}", e))?;
+    match exe_path.parent() {
+        Some(dir) => Ok(dir.to_path_buf()),
+        None => Err("Failed to get executable directory".to_string()),
+    }
+}
+
+/// 将LeonBasic添加到系统PATH环境变量
+#[cfg(target_os = "windows")]
+pub fn add_to_path(dry_run: bool) -> Result<(), String> {
+    let exe_dir = exe_dir()?;
+    let exe_dir_str = exe_dir.to_string_lossy();
+
+    // 创建一个PowerShell命令，以管理员权限运行
+    let powershell_cmd = format!(
+        "[Environment]::SetEnvironmentVariable('PATH', \"$env:PATH;{}\", 'Machine')",
+        exe_dir_str.replace('\\', "\\\\")
+    );
+    if dry_run {
+        println!("Would run: powershell.exe -Command {}", powershell_cmd);
+        return Ok(());
+    }
+
+    println!("Adding {} to system PATH...", exe_dir_str);
+    println!("This operation requires administrator privileges.");
+
+    // 使用PowerShell以管理员权限运行命令
+    let mut cmd = Command::new("powershell.exe");
+    cmd.arg("-Command")
+       .arg(&powershell_cmd)
+       .creation_flags(0x00000008); // CREATE_NO_WINDOW
+
+    let output = cmd.output()
+        .map_err(|e| format!("Failed to run PowerShell command: {}", e))?;
+
+    if output.status.success() {
+        println!("Successfully added to system PATH.");
+        println!("Please restart your terminal or command prompt to apply the changes.");
+        Ok(())
+    } else {
+        let error_msg = String::from_utf8_lossy(&output.stderr);
+        Err(format!("Failed to add to PATH. Error: {}. Please run as administrator.", error_msg))
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn remove_from_path(_dry_run: bool) -> Result<(), String> {
+    Err("--unsetpath is not supported on Windows; remove the LeonBasic directory from PATH in System Properties".to_string())
+}
+
+/// Add the executable's directory to PATH in the rc file of the current shell
+#[cfg(not(target_os = "windows"))]
+pub fn add_to_path(dry_run: bool) -> Result<(), String> {
+    let shell = Shell::detect()?;
+    let rc_file = shell.rc_file()?;
+    let line = shell.path_line(&exe_dir()?.to_string_lossy());
+
+    let old = read_rc_file(&rc_file)?;
+    let new = with_block(&old, &line);
+    if old == new {
+        println!("{} already adds LeonBasic to PATH.", rc_file.display());
+        return Ok(());
+    }
+    update_file(&rc_file, &old, &new, dry_run)?;
+    if !dry_run {
+        println!("Added LeonBasic to PATH in {}.", rc_file.display());
+        println!("Open a new terminal, or run: source {}", rc_file.display());
+    }
+    Ok(())
+}
+
+/// Remove the block written by `--setpath` from the rc file of the current shell
+#[cfg(not(target_os = "windows"))]
+pub fn remove_from_path(dry_run: bool) -> Result<(), String> {
+    let shell = Shell::detect()?;
+    let rc_file = shell.rc_file()?;
+
+    let old = read_rc_file(&rc_file)?;
+    let Some(new) = remove_block(&old) else {
+        println!("{} does not add LeonBasic to PATH.", rc_file.display());
+        return Ok(());
+    };
+    update_file(&rc_file, &old, &new, dry_run)?;
+    if !dry_run {
+        println!("Removed LeonBasic from PATH in {}.", rc_file.display());
+        println!("Open a new terminal to apply the change.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_is_replaced_and_removed() {
+        let original = "alias ll='ls -l'\n";
+        let line = Shell::Bash.path_line("/opt/leon $HOME");
+        assert_eq!(line, "export PATH=\"/opt/leon \\$HOME:$PATH\"");
+
+        let added = with_block(original, &line);
+        assert_eq!(added, format!("alias ll='ls -l'\n\n{}\n{}\n{}\n", BLOCK_START, line, BLOCK_END));
+        // Setting the path again leaves a single, updated block
+        assert_eq!(with_block(&added, &line), added);
+        let moved = with_block(&added, &Shell::Bash.path_line("/usr/local/leon"));
+        assert_eq!(moved.matches(BLOCK_START).count(), 1);
+        assert!(moved.contains("/usr/local/leon") && !moved.contains("/opt/leon"));
+
+        assert_eq!(remove_block(&added).as_deref(), Some(original));
+        assert_eq!(remove_block(original), None);
+        // Lines added after the block stay, and the blank line in front of the block goes
+        let extended = format!("{}export EDITOR=vim\n\nalias la='ls -a'\n", added);
+        assert_eq!(remove_block(&extended).as_deref(), Some("alias ll='ls -l'\nexport EDITOR=vim\n\nalias la='ls -a'\n"));
+        // A blank line the file already ended with survives setting and unsetting the path
+        assert_eq!(remove_block(&with_block("alias ll='ls -l'\n\n", &line)).as_deref(), Some("alias ll='ls -l'\n\n"));
+
+        let shown = diff(Path::new(".bashrc"), original, &added);
+        assert!(shown.ends_with(&format!("@@ -2 +2 @@\n+\n+{}\n+{}\n+{}\n", BLOCK_START, line, BLOCK_END)));
+    }
+}